@@ -3,15 +3,17 @@
 
 use embassy_executor::Spawner;
 use esp_hal::clock::CpuClock;
-use magic_markers::bulb::{bulb_commands_task, BulbChannel};
+use magic_markers::bulb::{bulb_commands_task, BulbChannels, BulbClients};
 use magic_markers::button::button_task;
-use magic_markers::constants::{BULB_IP_ADDRESS, HEAP_SIZE};
+use magic_markers::constants::{BULBS, HEAP_SIZE, MQTT_BROKER_MAX_CLIENTS, MQTT_EMBEDDED_BROKER};
 use magic_markers::led::{led_task, LedStateSignal};
+use magic_markers::marker_registry::{MarkerRegistry, MarkerRegistryMutex};
 use magic_markers::mk_static;
+use magic_markers::mqtt::broker::{mqtt_broker_task, MqttBroker};
 use magic_markers::networking::{connection_task, net_task};
 use magic_markers::peripherals::Peripherals;
-use magic_markers::rfid::rfid_task;
-use magic_markers::state::{periodic_sync_task, state_manager_task, StateSignal};
+use magic_markers::rfid::{rfid_task, ReaderModeSignal};
+use magic_markers::state::{periodic_sync_task, state_manager_task, StateChannel};
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
@@ -26,37 +28,65 @@ async fn main(spawner: Spawner) {
     esp_alloc::heap_allocator!(size: HEAP_SIZE);
 
     let peripherals = Peripherals::new(esp_peripherals);
-    let bulb_channel = mk_static!(BulbChannel, BulbChannel::new());
-    let state_signal = mk_static!(StateSignal, StateSignal::new());
+    let bulb_channels = mk_static!(BulbChannels, BulbChannels::new());
+    let state_channel = mk_static!(StateChannel, StateChannel::new());
     let led_state_signal = mk_static!(LedStateSignal, LedStateSignal::new());
+    let reader_mode_signal = mk_static!(ReaderModeSignal, ReaderModeSignal::new());
+    let mqtt_broker = mk_static!(MqttBroker, MqttBroker::new());
+    let marker_registry = mk_static!(
+        MarkerRegistryMutex,
+        MarkerRegistryMutex::new(MarkerRegistry::load(peripherals.flash))
+    );
 
     spawner
         .spawn(state_manager_task(
-            state_signal,
-            bulb_channel.sender(),
+            state_channel,
+            bulb_channels,
             led_state_signal,
+            reader_mode_signal,
         ))
         .unwrap();
-    spawner.spawn(periodic_sync_task(state_signal)).unwrap();
+    spawner.spawn(periodic_sync_task(state_channel)).unwrap();
     spawner
-        .spawn(rfid_task(peripherals.mfrc522, state_signal))
+        .spawn(rfid_task(
+            peripherals.reader,
+            marker_registry,
+            reader_mode_signal,
+            state_channel,
+        ))
         .unwrap();
     spawner
         .spawn(led_task(peripherals.led, led_state_signal))
         .unwrap();
     spawner
-        .spawn(button_task(peripherals.button, state_signal))
+        .spawn(button_task(peripherals.button, state_channel))
         .unwrap();
     spawner
         .spawn(connection_task(peripherals.wifi_controller))
         .unwrap();
     spawner.spawn(net_task(peripherals.network_runner)).unwrap();
-    spawner
-        .spawn(bulb_commands_task(
-            peripherals.network_stack,
-            BULB_IP_ADDRESS,
-            bulb_channel.receiver(),
-            state_signal,
-        ))
-        .unwrap();
+    let bulb_clients = BulbClients::new(peripherals.network_stack);
+    for bulb in 0..BULBS.len() {
+        spawner
+            .spawn(bulb_commands_task(
+                peripherals.network_stack,
+                bulb,
+                bulb_channels.receiver(bulb),
+                state_channel,
+                bulb_clients,
+                mqtt_broker,
+            ))
+            .unwrap();
+    }
+    if MQTT_EMBEDDED_BROKER {
+        for slot in 0..MQTT_BROKER_MAX_CLIENTS {
+            spawner
+                .spawn(mqtt_broker_task(
+                    peripherals.network_stack,
+                    mqtt_broker,
+                    slot,
+                ))
+                .unwrap();
+        }
+    }
 }
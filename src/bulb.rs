@@ -1,129 +1,551 @@
+pub mod device_group;
+pub mod govee;
+pub mod home_assistant;
+pub mod http_template;
+pub mod lifx;
+pub mod shelly;
+pub mod tasmota;
+pub mod tasmota_mqtt;
+pub mod wled;
+pub mod yeelight;
+
 extern crate alloc;
-use crate::constants::{COMMAND_DELAY_MS, HTTP_BUFFER_SIZE, HTTP_TIMEOUT_SECS};
+use crate::constants::{
+    BULBS, BULB_BACKEND, DEVICE_GROUP_PORT, GOVEE_LISTEN_PORT, HTTP_BUFFER_SIZE, HTTP_TIMEOUT_SECS,
+    MAX_BULBS, MQTT_EMBEDDED_BROKER, TCP_BUFFER_SIZE, UDP_BUFFER_SIZE,
+};
 use crate::mk_static;
-use crate::state::{StateCommand, StateSignal};
-use alloc::format;
-use core::fmt;
+use crate::mqtt::broker::MqttBroker;
+use crate::state::{StateChannel, StateCommand};
+use alloc::boxed::Box;
 use defmt::{info, warn, Format};
+use device_group::DeviceGroup;
+use embassy_futures::select::{select, Either};
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
     Stack,
 };
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::channel::{Channel, Receiver, Sender};
-use embassy_time::{Duration, Timer};
+use embassy_sync::channel::{Channel, Receiver};
+use embassy_time::Duration;
+use govee::Govee;
+use heapless::Vec;
+use home_assistant::HomeAssistant;
+use http_template::TemplatedHttp;
+use lifx::Lifx;
 use reqwless::client::HttpClient;
-use reqwless::request::Method;
+use shelly::Shelly;
+use tasmota::TasmotaHttp;
+use tasmota_mqtt::{MqttLink, TasmotaMqtt};
+use wled::Wled;
+use yeelight::Yeelight;
+
+/// a change to the light, independent of the protocol the bulb speaks
+#[derive(Format, Clone, Debug, PartialEq)]
+pub enum LightCommand {
+    /// hue 0-360, saturation and brightness 0-100
+    Color(u16, u8, u8),
+    /// white light at a brightness of 0-100
+    White(u8),
+    /// brightness 0-100, keeping the current color
+    Brightness(u8),
+    Power(bool),
+    /// how long the changes that follow fade for in milliseconds, 0 switching at once
+    Transition(u16),
+    /// white light at a color temperature in kelvin, keeping the brightness
+    ColorTemperature(u16),
+    Effect(LightEffect),
+}
+
+/// the most commands a change of light state takes: power, the color or the white
+/// brightness and temperature, and the effect
+pub const MAX_LIGHT_COMMANDS: usize = 4;
+
+/// the commands that take a bulb from one state to another, in order
+pub type LightCommands = Vec<LightCommand, MAX_LIGHT_COMMANDS>;
+
+/// an animation the bulb plays on its own
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum LightEffect {
+    /// a steady light
+    None,
+    /// slowly cycles through the colors
+    ColorCycle,
+}
+
+/// what a bulb says about its own state, fields it left out are `None`
+#[derive(Format, Clone, Debug, PartialEq, Default)]
+pub struct BulbReport {
+    pub power: Option<bool>,
+    /// brightness 0-100
+    pub brightness: Option<u8>,
+    /// hue 0-360, saturation and brightness 0-100
+    pub color: Option<(u16, u8, u8)>,
+}
+
+/// how far a reported hue, saturation or brightness may be off the commanded one
+/// before it counts as different, bulbs rounding them on the way through their rgb
+/// channels
+pub const REPORT_TOLERANCE: u16 = 2;
+
+fn close(reported: u16, commanded: u16) -> bool {
+    reported.abs_diff(commanded) <= REPORT_TOLERANCE
+}
+
+impl BulbReport {
+    /// fills in the fields `other` reports
+    pub fn merge(&mut self, other: BulbReport) {
+        self.power = other.power.or(self.power);
+        self.brightness = other.brightness.or(self.brightness);
+        self.color = other.color.or(self.color);
+    }
+
+    /// whether the report shows the bulb in the state `command` leaves it in. fields
+    /// the bulb left out count as matching
+    pub fn shows(&self, command: &LightCommand) -> bool {
+        let on = |on: bool| self.power.is_none_or(|power| power == on);
+        let brightness = |value: u8| {
+            self.brightness
+                .is_none_or(|brightness| close(brightness as u16, value as u16))
+        };
+        match *command {
+            LightCommand::Color(h, s, b) => {
+                on(true)
+                    && self.color.is_none_or(|(rh, rs, rb)| {
+                        // hue wraps around, and means nothing without saturation
+                        let hue_distance = (rh % 360).abs_diff(h % 360);
+                        (s == 0 || hue_distance.min(360 - hue_distance) <= REPORT_TOLERANCE)
+                            && close(rs as u16, s as u16)
+                            && close(rb as u16, b as u16)
+                    })
+            }
+            LightCommand::White(value) => {
+                on(true)
+                    && brightness(value)
+                    && self.color.is_none_or(|(_, saturation, _)| saturation == 0)
+            }
+            LightCommand::Brightness(0) => on(false) || brightness(0),
+            LightCommand::Brightness(value) => on(true) && brightness(value),
+            LightCommand::Power(power) => on(power),
+            // bulbs do not report these
+            LightCommand::Transition(_)
+            | LightCommand::ColorTemperature(_)
+            | LightCommand::Effect(_) => true,
+        }
+    }
+}
+
+/// what asking a bulb for its state came to
+#[derive(Format, Clone, Debug, PartialEq)]
+pub enum QueryError {
+    /// the backend has no way to ask
+    Unsupported,
+    /// the bulb did not answer
+    Failed,
+}
+
+/// why a bulb did not apply a command
+#[derive(Format, Clone, Copy, Debug, PartialEq)]
+pub enum CommandError {
+    /// the command does not fit the backend's buffer
+    TooLarge,
+    /// the bulb could not be reached, or did not answer
+    Unreachable,
+    /// the bulb answered with an error, or with a reply we cannot read
+    Rejected,
+    /// the bulb reports a state other than the commanded one
+    Mismatch,
+}
+
+/// something a bulb told us without being asked
+#[derive(Format, Clone, Debug, PartialEq)]
+pub enum BulbEvent {
+    Reported(BulbReport),
+    /// the bulb came online or went away
+    Online(bool),
+}
+
+/// a protocol for talking to bulbs
+#[allow(async_fn_in_trait)]
+pub trait LightBackend {
+    /// applies the command to the bulb
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError>;
+
+    /// applies the commands in order, stopping at the first the bulb does not apply
+    async fn apply(&mut self, commands: &[LightCommand]) -> Result<(), CommandError> {
+        for command in commands {
+            self.send(command).await?;
+        }
+        Ok(())
+    }
+
+    /// waits for the bulb to report on its own. backends that only hear from the bulb
+    /// in answer to a command never return
+    async fn poll(&mut self) -> BulbEvent {
+        core::future::pending().await
+    }
+
+    /// asks the bulb for its current state
+    async fn query(&mut self) -> Result<BulbReport, QueryError> {
+        Err(QueryError::Unsupported)
+    }
+}
+
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum BulbBackend {
+    /// tasmota firmware, commanded through its `/cm?cmnd=` http endpoint
+    Tasmota,
+    /// led strips running wled, commanded through its `/json/state` endpoint
+    Wled,
+    /// lifx bulbs, driven with their binary lan protocol over udp port 56700
+    Lifx,
+    /// yeelight bulbs, driven with json commands over tcp port 55443
+    Yeelight,
+    /// govee lights, driven through their local udp api on ports 4001-4003
+    Govee,
+    /// shelly gen2 lights, driven through their http json-rpc
+    Shelly,
+    /// whatever lights home assistant can reach, through its rest api
+    HomeAssistant,
+    /// any device with a local http api, through the requests in `HTTP_TEMPLATES`
+    TemplatedHttp,
+    /// tasmota firmware, commanded through the mqtt broker at `MQTT_BROKER_ADDRESS`
+    TasmotaMqtt,
+    /// every tasmota device in `DEVICE_GROUP_NAME`, through device groups on udp
+    /// multicast port 4447
+    DeviceGroup,
+}
+
+/// defines `Light` with a variant for every backend, forwarding each `LightBackend`
+/// method to the backend in use
+macro_rules! lights {
+    ($($variant:ident($backend:ty)),* $(,)?) => {
+        /// the backend chosen by `BULB_BACKEND`
+        pub enum Light {
+            $($variant($backend),)*
+        }
+
+        impl LightBackend for Light {
+            async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+                match self {
+                    $(Light::$variant(backend) => backend.send(command).await,)*
+                }
+            }
+
+            async fn apply(&mut self, commands: &[LightCommand]) -> Result<(), CommandError> {
+                match self {
+                    $(Light::$variant(backend) => backend.apply(commands).await,)*
+                }
+            }
+
+            async fn poll(&mut self) -> BulbEvent {
+                match self {
+                    $(Light::$variant(backend) => backend.poll().await,)*
+                }
+            }
+
+            async fn query(&mut self) -> Result<BulbReport, QueryError> {
+                match self {
+                    $(Light::$variant(backend) => backend.query().await,)*
+                }
+            }
+        }
+    };
+}
+
+lights! {
+    Tasmota(TasmotaHttp),
+    Wled(Wled),
+    Lifx(Lifx),
+    Yeelight(Yeelight),
+    Govee(Govee),
+    Shelly(Shelly),
+    HomeAssistant(HomeAssistant),
+    TemplatedHttp(TemplatedHttp),
+    TasmotaMqtt(TasmotaMqtt),
+    DeviceGroup(DeviceGroup),
+}
+
+/// a bulb the firmware drives, spoken to with `BULB_BACKEND`
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub struct BulbTarget {
+    pub name: &'static str,
+    pub address: &'static str,
+    /// the named groups the bulb belongs to
+    pub groups: &'static [&'static str],
+}
+
+/// which of the bulbs in `BULBS` a command goes to
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum BulbSelection {
+    All,
+    /// the bulbs listing the group in their `groups`
+    Group(&'static str),
+    /// the bulb with the given name
+    Named(&'static str),
+    /// the bulb at the given index of `BULBS`
+    Bulb(usize),
+}
+
+impl BulbSelection {
+    pub fn includes(&self, bulb: usize) -> bool {
+        let Some(target) = BULBS.get(bulb) else {
+            return false;
+        };
+        match *self {
+            BulbSelection::All => true,
+            BulbSelection::Group(group) => target.groups.contains(&group),
+            BulbSelection::Named(name) => target.name == name,
+            BulbSelection::Bulb(index) => index == bulb,
+        }
+    }
+}
+
+const _: () = assert!(
+    BULBS.len() <= MAX_BULBS,
+    "BULBS lists more than MAX_BULBS bulbs"
+);
+
+// the lan backends take the bulb's address as an ipv4 address rather than a host name
+const _: () = if matches!(
+    BULB_BACKEND,
+    BulbBackend::Lifx | BulbBackend::Yeelight | BulbBackend::Govee
+) {
+    let mut bulb = 0;
+    while bulb < BULBS.len() {
+        assert!(
+            is_ipv4_address(BULBS[bulb].address),
+            "BULBS addresses have to be ipv4 addresses for this backend"
+        );
+        bulb += 1;
+    }
+};
+
+// govee lights answer on a fixed port and device groups on their multicast port, so
+// only one socket can listen for them
+const _: () = assert!(
+    !matches!(BULB_BACKEND, BulbBackend::Govee | BulbBackend::DeviceGroup) || BULBS.len() == 1,
+    "the govee and device group backends drive exactly one entry of BULBS"
+);
+
+// the firmware has a single session on its embedded broker
+const _: () = assert!(
+    !(matches!(BULB_BACKEND, BulbBackend::TasmotaMqtt) && MQTT_EMBEDDED_BROKER) || BULBS.len() == 1,
+    "the embedded mqtt broker drives exactly one entry of BULBS"
+);
+
+/// whether `address` is a dotted quad ipv4 address, for checking `BULBS` at compile
+/// time
+const fn is_ipv4_address(address: &str) -> bool {
+    let bytes = address.as_bytes();
+    let (mut index, mut dots, mut digits, mut octet) = (0, 0, 0, 0u16);
+    while index < bytes.len() {
+        match bytes[index] {
+            b'.' if digits > 0 => {
+                dots += 1;
+                digits = 0;
+                octet = 0;
+            }
+            byte @ b'0'..=b'9' if digits < 3 => {
+                octet = octet * 10 + (byte - b'0') as u16;
+                digits += 1;
+                if octet > 255 {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+        index += 1;
+    }
+    dots == 3 && digits > 0
+}
+
+pub type BulbHttpClient = HttpClient<
+    'static,
+    TcpClient<'static, MAX_BULBS, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
+    DnsSocket<'static>,
+>;
+
+/// what the state manager asks of a bulb task
+#[derive(Format, Clone, Debug, PartialEq)]
+pub enum BulbRequest {
+    Apply(LightCommands),
+    /// ask the bulb for its state, to find where it drifted from the intended one
+    Query,
+}
 
-#[derive(Format, Clone, Debug)]
-pub enum TasmotaCommand {
-    HSBColor(u16, u8, u8),
-    White(u16),
-    Dimmer(u8),
+pub type BulbChannel = Channel<NoopRawMutex, BulbRequest, 8>;
+pub type BulbChannelReceiver = Receiver<'static, NoopRawMutex, BulbRequest, 8>;
+
+/// a command queue for every bulb, so a slow or unreachable bulb holds up only its own
+/// commands
+pub struct BulbChannels([BulbChannel; MAX_BULBS]);
+
+impl Default for BulbChannels {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl fmt::Display for TasmotaCommand {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match self {
-            TasmotaCommand::HSBColor(h, s, b) => {
-                write!(f, "hsbcolor%20{},{},{}", h, s, b)
+impl BulbChannels {
+    pub const fn new() -> Self {
+        Self([const { BulbChannel::new() }; MAX_BULBS])
+    }
+
+    /// queues the commands for every selected bulb. a bulb whose queue is full misses
+    /// them, and catches up on the desired state once it is reachable again
+    pub fn send(&self, bulbs: BulbSelection, commands: LightCommands) {
+        self.request(bulbs, BulbRequest::Apply(commands));
+    }
+
+    /// asks every selected bulb for its state, which comes back as a
+    /// `StateCommand::BulbQueried`
+    pub fn query(&self, bulbs: BulbSelection) {
+        self.request(bulbs, BulbRequest::Query);
+    }
+
+    fn request(&self, bulbs: BulbSelection, request: BulbRequest) {
+        for (bulb, channel) in self.0.iter().enumerate().take(BULBS.len()) {
+            if bulbs.includes(bulb) && channel.try_send(request.clone()).is_err() {
+                warn!("bulb {} is behind, dropping {}", BULBS[bulb].name, request);
             }
-            TasmotaCommand::White(value) => write!(f, "white%20{}", value),
-            TasmotaCommand::Dimmer(value) => write!(f, "dimmer%20{}", value),
         }
     }
+
+    pub fn receiver(&'static self, bulb: usize) -> BulbChannelReceiver {
+        self.0[bulb].receiver()
+    }
 }
 
-pub type BulbChannel = Channel<NoopRawMutex, TasmotaCommand, 8>;
-pub type BulbChannelSender = Sender<'static, NoopRawMutex, TasmotaCommand, 8>;
-pub type BulbChannelReceiver = Receiver<'static, NoopRawMutex, TasmotaCommand, 8>;
+/// the tcp connections and dns socket the http backends of all bulb tasks share
+pub struct BulbClients {
+    tcp: TcpClient<'static, MAX_BULBS, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
+    dns: DnsSocket<'static>,
+}
+
+impl BulbClients {
+    pub fn new(stack: Stack<'static>) -> &'static Self {
+        let state = mk_static!(
+            TcpClientState<MAX_BULBS, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
+            TcpClientState::<MAX_BULBS, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>::new()
+        );
+        let clients = mk_static!(
+            BulbClients,
+            BulbClients {
+                tcp: TcpClient::new(stack, state),
+                dns: DnsSocket::new(stack),
+            }
+        );
+        clients
+            .tcp
+            .set_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
+        clients
+    }
+}
 
-#[embassy_executor::task]
+/// drives the bulb at index `bulb` of `BULBS`, one task running for every bulb
+#[embassy_executor::task(pool_size = MAX_BULBS)]
 pub async fn bulb_commands_task(
     stack: Stack<'static>,
-    bulb_ip_addr_str: &'static str,
+    bulb: usize,
     bulb_channel_receiver: BulbChannelReceiver,
-    state_signal: &'static StateSignal,
+    state_channel: &'static StateChannel,
+    clients: &'static BulbClients,
+    mqtt_broker: &'static MqttBroker,
 ) {
-    info!("starting web task...");
+    let target = BULBS[bulb];
+    info!("starting bulb task for {}...", target.name);
     stack.wait_link_up().await;
     stack.wait_config_up().await;
-    let state = mk_static!(
-        TcpClientState<1, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
-        TcpClientState::<1, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>::new()
-    );
-    let tcp_client = mk_static!(
-        TcpClient<'static, 1, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
-        TcpClient::new(stack, state)
-    );
-    let dns_client = mk_static!(embassy_net::dns::DnsSocket<'static>, DnsSocket::new(stack));
-    tcp_client.set_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
-    let client = mk_static!(
-        HttpClient<
-            'static,
-            TcpClient<'static, 1, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
-            embassy_net::dns::DnsSocket<'static>,
-        >,
-        HttpClient::new(tcp_client, dns_client)
-    );
-    let mut buffer = [0u8; HTTP_BUFFER_SIZE];
+    let address = target.address;
+    let mut light = match BULB_BACKEND {
+        BulbBackend::Tasmota => Light::Tasmota(TasmotaHttp::new(http_client(clients), address)),
+        BulbBackend::Wled => Light::Wled(Wled::new(http_client(clients), address)),
+        BulbBackend::Lifx => Light::Lifx(Lifx::new(udp_socket(stack, 0), address)),
+        BulbBackend::Yeelight => Light::Yeelight(Yeelight::new(
+            tcp_socket(stack),
+            udp_socket(stack, 0),
+            address,
+        )),
+        BulbBackend::Govee => {
+            Light::Govee(Govee::new(udp_socket(stack, GOVEE_LISTEN_PORT), address))
+        }
+        BulbBackend::Shelly => Light::Shelly(Shelly::new(http_client(clients), address)),
+        BulbBackend::HomeAssistant => {
+            Light::HomeAssistant(HomeAssistant::new(http_client(clients)))
+        }
+        BulbBackend::TemplatedHttp => {
+            Light::TemplatedHttp(TemplatedHttp::new(http_client(clients), address))
+        }
+        BulbBackend::TasmotaMqtt if MQTT_EMBEDDED_BROKER => {
+            let link = MqttLink::Embedded(mqtt_broker);
+            Light::TasmotaMqtt(TasmotaMqtt::new(link, address, bulb))
+        }
+        BulbBackend::TasmotaMqtt => {
+            let link = MqttLink::remote(tcp_socket(stack));
+            Light::TasmotaMqtt(TasmotaMqtt::new(link, address, bulb))
+        }
+        BulbBackend::DeviceGroup => {
+            Light::DeviceGroup(DeviceGroup::new(udp_socket(stack, DEVICE_GROUP_PORT)))
+        }
+    };
+    info!("bulb {} at {}: {}", target.name, address, BULB_BACKEND);
 
     // Signal that we're ready to send commands (connected)
-    state_signal.signal(StateCommand::SetConnected(true));
+    state_channel
+        .send(StateCommand::SetConnected(bulb, true))
+        .await;
 
     loop {
-        let command = bulb_channel_receiver.receive().await;
-        let success = send_bulb_command(client, &mut buffer, bulb_ip_addr_str, command).await;
-
-        // Update connection status based on command success
-        state_signal.signal(StateCommand::SetConnected(success));
-    }
-}
-
-async fn send_bulb_command(
-    client: &mut HttpClient<
-        'static,
-        TcpClient<'static, 1, HTTP_BUFFER_SIZE, HTTP_BUFFER_SIZE>,
-        embassy_net::dns::DnsSocket<'static>,
-    >,
-    buffer: &mut [u8; HTTP_BUFFER_SIZE],
-    bulb_ip_addr: &str,
-    command: TasmotaCommand,
-) -> bool {
-    let url = format!("http://{}/cm?cmnd={}", bulb_ip_addr, command);
-    let method = Method::POST;
-    info!("sending request: {} {}", method, url.as_str());
-    let mut req = match client.request(method, url.as_str()).await {
-        Ok(req) => req,
-        Err(e) => {
-            warn!("request build error: {:?}", e);
-            Timer::after(Duration::from_secs(2)).await;
-            return false;
-        }
-    };
-    let res = match req.send(buffer).await {
-        Ok(res) => res,
-        Err(e) => {
-            warn!("request send error: {:?}", e);
-            return false;
-        }
-    };
-    info!("request sent successfully");
-    match res.body().read_to_end().await {
-        Ok(read) => {
-            info!("response body read successfully, length: {}", read.len());
-            match core::str::from_utf8(read) {
-                Ok(body) => info!("response body: {:?}", body),
-                Err(_) => warn!("response body is not valid UTF-8"),
+        let update = match select(bulb_channel_receiver.receive(), light.poll()).await {
+            Either::First(BulbRequest::Apply(commands)) => {
+                let applied = light.apply(&commands).await;
+                if let Err(e) = applied {
+                    warn!("bulb {} did not apply the commands: {}", target.name, e);
+                }
+
+                // Update connection status based on command success
+                StateCommand::SetConnected(bulb, applied.is_ok())
             }
-        }
-        Err(e) => {
-            warn!("failed to read response body: {}", e);
-            Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
-            return false;
-        }
+            Either::First(BulbRequest::Query) => match light.query().await {
+                Ok(report) => StateCommand::BulbQueried(bulb, Some(report)),
+                Err(QueryError::Unsupported) => StateCommand::BulbQueried(bulb, None),
+                Err(QueryError::Failed) => StateCommand::SetConnected(bulb, false),
+            },
+            Either::Second(BulbEvent::Reported(report)) => StateCommand::BulbReported(bulb, report),
+            Either::Second(BulbEvent::Online(online)) => StateCommand::SetConnected(bulb, online),
+        };
+        state_channel.send(update).await;
     }
-    Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
-    true
+}
+
+// every bulb task needs its own client and sockets, which a static cell per call site
+// cannot give, so they come from the heap once
+
+/// an http client on the connections shared by all bulb tasks
+fn http_client(clients: &'static BulbClients) -> &'static mut BulbHttpClient {
+    Box::leak(Box::new(HttpClient::new(&clients.tcp, &clients.dns)))
+}
+
+/// a udp socket for the udp backends bound to `port`, 0 picking any free one
+fn udp_socket(stack: Stack<'static>, port: u16) -> UdpSocket<'static> {
+    let rx_meta = Box::leak(Box::new([PacketMetadata::EMPTY; 4]));
+    let rx_buffer = Box::leak(Box::new([0u8; UDP_BUFFER_SIZE]));
+    let tx_meta = Box::leak(Box::new([PacketMetadata::EMPTY; 4]));
+    let tx_buffer = Box::leak(Box::new([0u8; UDP_BUFFER_SIZE]));
+    let mut socket = UdpSocket::new(stack, rx_meta, rx_buffer, tx_meta, tx_buffer);
+    socket.bind(port).expect("failed to bind udp socket");
+    socket
+}
+
+/// a tcp socket for the backends that keep a connection to the bulb open
+fn tcp_socket(stack: Stack<'static>) -> TcpSocket<'static> {
+    let rx_buffer = Box::leak(Box::new([0u8; TCP_BUFFER_SIZE]));
+    let tx_buffer = Box::leak(Box::new([0u8; TCP_BUFFER_SIZE]));
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
+    socket
 }
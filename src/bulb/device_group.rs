@@ -0,0 +1,330 @@
+use super::tasmota::TasmotaCommand;
+use super::{CommandError, LightBackend, LightCommand, LightEffect};
+use crate::constants::{
+    DEVICE_GROUP_ACK_TIMEOUT_MS, DEVICE_GROUP_NAME, DEVICE_GROUP_PACKET_SIZE, DEVICE_GROUP_PORT,
+    DEVICE_GROUP_SEND_ATTEMPTS,
+};
+use crate::marker_color::MarkerColor;
+use defmt::{info, warn, Format};
+use embassy_net::udp::UdpSocket;
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address};
+use embassy_time::{with_timeout, Duration, Instant};
+use heapless::Vec;
+
+/// every device group message starts with this, followed by the group name
+const HEADER: &[u8] = b"TASMOTA_DGR";
+const MULTICAST_ADDRESS: Ipv4Address = Ipv4Address::new(239, 255, 250, 250);
+
+/// message flags
+const FLAG_ACK: u16 = 8;
+
+/// item types. the type's range says how the value is encoded: 8 bit values below
+/// 64, 16 bit below 128, 32 bit below 192, strings below 224 and arrays above
+const ITEM_EOL: u8 = 0;
+const ITEM_LIGHT_FADE: u8 = 3;
+const ITEM_LIGHT_SPEED: u8 = 4;
+const ITEM_LIGHT_BRI: u8 = 5;
+const ITEM_LIGHT_SCHEME: u8 = 6;
+const ITEM_POWER: u8 = 128;
+const ITEM_LIGHT_CHANNELS: u8 = 224;
+
+pub type DeviceGroupPacket = Vec<u8, DEVICE_GROUP_PACKET_SIZE>;
+
+/// a message or its items do not fit their buffer
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub struct TooLarge;
+
+/// a value shared with the other members of a device group
+#[derive(Format, Clone, Debug, PartialEq)]
+pub enum DeviceGroupItem {
+    LightFade(bool),
+    /// fade speed in half seconds (1-40)
+    LightSpeed(u8),
+    /// brightness 0-255
+    LightBri(u8),
+    /// 0 for a steady light, otherwise one of tasmota's animations
+    LightScheme(u8),
+    /// the state of the member's first relay
+    Power(bool),
+    /// red, green, blue, cold white and warm white, 0-255
+    LightChannels([u8; 5]),
+}
+
+impl DeviceGroupItem {
+    /// the items that carry a command to the group
+    pub fn for_command(command: &LightCommand) -> Result<Vec<DeviceGroupItem, 2>, TooLarge> {
+        let items = match *command {
+            LightCommand::Color(h, s, b) => {
+                // the channels go out at full brightness, the members scale them
+                let (r, g, bl) = MarkerColor::Custom(h, s, 100).rgb();
+                Vec::from_slice(&[
+                    DeviceGroupItem::LightChannels([r, g, bl, 0, 0]),
+                    DeviceGroupItem::LightBri(percent_to_level(b)),
+                ])
+            }
+            LightCommand::White(brightness) => Vec::from_slice(&[
+                DeviceGroupItem::LightChannels([0, 0, 0, 255, 255]),
+                DeviceGroupItem::LightBri(percent_to_level(brightness)),
+            ]),
+            LightCommand::Brightness(brightness) => {
+                Vec::from_slice(&[DeviceGroupItem::LightBri(percent_to_level(brightness))])
+            }
+            LightCommand::Power(on) => Vec::from_slice(&[DeviceGroupItem::Power(on)]),
+            LightCommand::Transition(_) => match TasmotaCommand::from(command) {
+                TasmotaCommand::Fade(speed) if speed > 0 => Vec::from_slice(&[
+                    DeviceGroupItem::LightFade(true),
+                    DeviceGroupItem::LightSpeed(speed),
+                ]),
+                _ => Vec::from_slice(&[DeviceGroupItem::LightFade(false)]),
+            },
+            LightCommand::ColorTemperature(kelvin) => {
+                // mixes the cold and warm white channels between 6500k and 2700k
+                let warm = ((6500 - kelvin.clamp(2700, 6500)) as u32 * 255 / 3800) as u8;
+                Vec::from_slice(&[DeviceGroupItem::LightChannels([0, 0, 0, 255 - warm, warm])])
+            }
+            LightCommand::Effect(effect) => {
+                // scheme 2 cycles up through the colors
+                let scheme = if effect == LightEffect::ColorCycle {
+                    2
+                } else {
+                    0
+                };
+                Vec::from_slice(&[DeviceGroupItem::LightScheme(scheme)])
+            }
+        };
+        items.map_err(|()| TooLarge)
+    }
+
+    fn encode(&self, packet: &mut DeviceGroupPacket) -> Result<(), ()> {
+        match *self {
+            DeviceGroupItem::LightFade(fade) => {
+                packet.extend_from_slice(&[ITEM_LIGHT_FADE, fade as u8])
+            }
+            DeviceGroupItem::LightSpeed(speed) => {
+                packet.extend_from_slice(&[ITEM_LIGHT_SPEED, speed])
+            }
+            DeviceGroupItem::LightBri(level) => packet.extend_from_slice(&[ITEM_LIGHT_BRI, level]),
+            DeviceGroupItem::LightScheme(scheme) => {
+                packet.extend_from_slice(&[ITEM_LIGHT_SCHEME, scheme])
+            }
+            DeviceGroupItem::Power(on) => {
+                // the top byte holds how many relays the power bits are for
+                let power = on as u32 | 1 << 24;
+                packet.push(ITEM_POWER).map_err(|_| ())?;
+                packet.extend_from_slice(&power.to_le_bytes())
+            }
+            DeviceGroupItem::LightChannels(channels) => {
+                packet.extend_from_slice(&[ITEM_LIGHT_CHANNELS, channels.len() as u8])?;
+                packet.extend_from_slice(&channels)
+            }
+        }
+    }
+}
+
+/// encodes a message to `group` carrying `items`
+pub fn encode(
+    group: &str,
+    sequence: u16,
+    flags: u16,
+    items: &[DeviceGroupItem],
+) -> Result<DeviceGroupPacket, TooLarge> {
+    let mut packet = DeviceGroupPacket::new();
+    packet.extend_from_slice(HEADER).map_err(|()| TooLarge)?;
+    packet
+        .extend_from_slice(group.as_bytes())
+        .map_err(|()| TooLarge)?;
+    packet.push(0).map_err(|_| TooLarge)?;
+    packet
+        .extend_from_slice(&sequence.to_le_bytes())
+        .map_err(|()| TooLarge)?;
+    packet
+        .extend_from_slice(&flags.to_le_bytes())
+        .map_err(|()| TooLarge)?;
+    for item in items {
+        item.encode(&mut packet).map_err(|()| TooLarge)?;
+    }
+    packet.push(ITEM_EOL).map_err(|_| TooLarge)?;
+    Ok(packet)
+}
+
+/// reads the group name, sequence number and flags of a message, skipping its items
+pub fn decode_header(packet: &[u8]) -> Option<(&str, u16, u16)> {
+    let rest = packet.strip_prefix(HEADER)?;
+    let end = rest.iter().position(|&byte| byte == 0)?;
+    let group = core::str::from_utf8(&rest[..end]).ok()?;
+    match rest[end + 1..] {
+        [s0, s1, f0, f1, ..] => Some((
+            group,
+            u16::from_le_bytes([s0, s1]),
+            u16::from_le_bytes([f0, f1]),
+        )),
+        _ => None,
+    }
+}
+
+/// every tasmota device in the group `DEVICE_GROUP_NAME`, through tasmota's device
+/// groups protocol on udp multicast
+///
+/// one multicast message reaches all members at once. members acknowledge each
+/// message they receive, and it is sent again until one of them does.
+pub struct DeviceGroup {
+    socket: UdpSocket<'static>,
+    sequence: u16,
+}
+
+impl DeviceGroup {
+    /// sends through `socket`, which has to be bound to `DEVICE_GROUP_PORT` to hear
+    /// the members' acknowledgements
+    pub fn new(socket: UdpSocket<'static>) -> Self {
+        Self {
+            socket,
+            // members skip sequence numbers they have already seen, so starting from
+            // the clock keeps a restart from reusing the last run's numbers
+            sequence: Instant::now().as_micros() as u16,
+        }
+    }
+
+    fn next_sequence(&mut self) -> u16 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    /// waits for a member to acknowledge the message numbered `sequence`
+    async fn acknowledged(&mut self, sequence: u16) -> bool {
+        let socket = &mut self.socket;
+        let wait = async {
+            let mut packet = [0u8; DEVICE_GROUP_PACKET_SIZE];
+            loop {
+                let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                    continue;
+                };
+                if let Some((DEVICE_GROUP_NAME, acked, flags)) = decode_header(&packet[..len]) {
+                    if acked == sequence && flags & FLAG_ACK != 0 {
+                        info!("device group member {} acknowledged", meta.endpoint.addr);
+                        return;
+                    }
+                }
+            }
+        };
+        with_timeout(Duration::from_millis(DEVICE_GROUP_ACK_TIMEOUT_MS), wait)
+            .await
+            .is_ok()
+    }
+}
+
+impl LightBackend for DeviceGroup {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let sequence = self.next_sequence();
+        let message = DeviceGroupItem::for_command(command)
+            .and_then(|items| encode(DEVICE_GROUP_NAME, sequence, 0, &items));
+        let Ok(message) = message else {
+            warn!("device group message does not fit its buffer");
+            return Err(CommandError::TooLarge);
+        };
+        let group = IpEndpoint::new(IpAddress::Ipv4(MULTICAST_ADDRESS), DEVICE_GROUP_PORT);
+        info!("sending {} to device group {}", command, DEVICE_GROUP_NAME);
+        // members drop the repeats of a message they already applied
+        for _ in 0..DEVICE_GROUP_SEND_ATTEMPTS {
+            if let Err(e) = self.socket.send_to(&message, group).await {
+                warn!("device group send error: {:?}", e);
+                return Err(CommandError::Unreachable);
+            }
+            if self.acknowledged(sequence).await {
+                return Ok(());
+            }
+        }
+        warn!("no member of device group {} answered", DEVICE_GROUP_NAME);
+        Err(CommandError::Unreachable)
+    }
+}
+
+/// converts 0-100 to tasmota's 0-255 brightness
+fn percent_to_level(percent: u8) -> u8 {
+    (percent.min(100) as u16 * 255 / 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// the header of a message to the group `bedroom` numbered 0x0102
+    fn header(flags: u8) -> Vec<u8> {
+        let mut frame = b"TASMOTA_DGRbedroom\0".to_vec();
+        frame.extend_from_slice(&[0x02, 0x01, flags, 0x00]);
+        frame
+    }
+
+    fn frame(command: &LightCommand) -> Vec<u8> {
+        let items = DeviceGroupItem::for_command(command).unwrap();
+        encode("bedroom", 0x0102, 0, &items).unwrap().to_vec()
+    }
+
+    #[test]
+    fn encodes_tasmota_frames() {
+        // red: the channels item is an array of r, g, b, cold and warm white
+        let mut red = header(0);
+        red.extend_from_slice(&[224, 5, 255, 0, 0, 0, 0, 5, 255, 0]);
+        assert_eq!(frame(&LightCommand::Color(0, 100, 100)), red);
+
+        let mut white = header(0);
+        white.extend_from_slice(&[224, 5, 0, 0, 0, 255, 255, 5, 127, 0]);
+        assert_eq!(frame(&LightCommand::White(50)), white);
+
+        // power is a 32 bit item, its top byte the number of relays
+        let mut power = header(0);
+        power.extend_from_slice(&[128, 1, 0, 0, 1, 0]);
+        assert_eq!(frame(&LightCommand::Power(true)), power);
+
+        let mut fade = header(0);
+        fade.extend_from_slice(&[3, 1, 4, 2, 0]);
+        assert_eq!(frame(&LightCommand::Transition(1000)), fade);
+
+        let mut no_fade = header(0);
+        no_fade.extend_from_slice(&[3, 0, 0]);
+        assert_eq!(frame(&LightCommand::Transition(0)), no_fade);
+
+        let mut cycle = header(0);
+        cycle.extend_from_slice(&[6, 2, 0]);
+        assert_eq!(frame(&LightCommand::Effect(LightEffect::ColorCycle)), cycle);
+    }
+
+    #[test]
+    fn every_command_fits() {
+        let commands = [
+            LightCommand::Color(359, 100, 100),
+            LightCommand::White(100),
+            LightCommand::Brightness(100),
+            LightCommand::Power(false),
+            LightCommand::Transition(20_000),
+            LightCommand::ColorTemperature(2700),
+            LightCommand::Effect(LightEffect::None),
+        ];
+        for command in commands {
+            let items = DeviceGroupItem::for_command(&command).unwrap();
+            assert!(encode(DEVICE_GROUP_NAME, u16::MAX, 0, &items).is_ok());
+        }
+    }
+
+    #[test]
+    fn long_group_names_are_an_error() {
+        let name = "a".repeat(DEVICE_GROUP_PACKET_SIZE);
+        let items = DeviceGroupItem::for_command(&LightCommand::Power(true)).unwrap();
+        assert!(encode(&name, 1, 0, &items).is_err());
+    }
+
+    #[test]
+    fn decodes_acknowledgements() {
+        // a member's acknowledgement carries no items
+        let mut ack = header(FLAG_ACK as u8);
+        ack.push(ITEM_EOL);
+        assert_eq!(decode_header(&ack), Some(("bedroom", 0x0102, FLAG_ACK)));
+        // a status update from a member, with items after the header
+        let mut update = header(0);
+        update.extend_from_slice(&[5, 200, 0]);
+        assert_eq!(decode_header(&update), Some(("bedroom", 0x0102, 0)));
+        assert_eq!(decode_header(&ack[..ack.len() - 3]), None);
+        assert_eq!(decode_header(b"TASMOTA_DGRbedroom"), None);
+        assert_eq!(decode_header(b"NOT_TASMOTA"), None);
+    }
+}
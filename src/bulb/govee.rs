@@ -0,0 +1,388 @@
+use super::{CommandError, LightBackend, LightCommand};
+use crate::constants::{
+    GOVEE_COMMAND_PORT, GOVEE_JSON_BUFFER_SIZE, GOVEE_REPLY_TIMEOUT_MS, GOVEE_SCAN_TIMEOUT_MS,
+};
+use crate::marker_color::MarkerColor;
+use defmt::{info, warn};
+use embassy_net::udp::UdpSocket;
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address};
+use embassy_time::{with_timeout, Duration};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+const SCAN_ADDRESS: Ipv4Address = Ipv4Address::new(239, 255, 255, 250);
+const SCAN_PORT: u16 = 4001;
+/// color temperature used for white, in kelvin
+const WHITE_TEMPERATURE: u16 = 4000;
+
+#[derive(Serialize)]
+struct GoveeRequest<D> {
+    msg: GoveeMessage<D>,
+}
+
+#[derive(Serialize)]
+struct GoveeMessage<D> {
+    cmd: &'static str,
+    data: D,
+}
+
+#[derive(Serialize)]
+struct Scan {
+    account_topic: &'static str,
+}
+
+#[derive(Serialize)]
+struct Value {
+    value: u8,
+}
+
+#[derive(Serialize)]
+struct ColorWc {
+    color: Rgb,
+    /// 0 for a color, a temperature for white
+    #[serde(rename = "colorTemInKelvin")]
+    color_temperature: u16,
+}
+
+#[derive(Serialize)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+/// the fields of scan and status replies used here, everything else is skipped
+#[derive(Deserialize)]
+struct GoveeReply<'a> {
+    #[serde(borrow)]
+    msg: GoveeReplyMessage<'a>,
+}
+
+#[derive(Deserialize)]
+struct GoveeReplyMessage<'a> {
+    cmd: &'a str,
+    data: GoveeReplyData,
+}
+
+#[derive(Deserialize)]
+struct GoveeReplyData {
+    #[serde(rename = "onOff")]
+    on_off: Option<u8>,
+    brightness: Option<u8>,
+}
+
+type GoveePacket = Vec<u8, GOVEE_JSON_BUFFER_SIZE>;
+
+/// encodes a request, failing when it does not fit `GOVEE_JSON_BUFFER_SIZE`
+fn encode<D: Serialize>(
+    cmd: &'static str,
+    data: D,
+) -> Result<GoveePacket, serde_json_core::ser::Error> {
+    serde_json_core::to_vec(&GoveeRequest {
+        msg: GoveeMessage { cmd, data },
+    })
+}
+
+/// parses a reply, returning `None` for anything else on the port
+fn decode(packet: &[u8]) -> Option<GoveeReplyMessage<'_>> {
+    serde_json_core::from_slice::<GoveeReply>(packet)
+        .ok()
+        .map(|(reply, _)| reply.msg)
+}
+
+/// whether `packet`, received from `from` during a scan, is the answer of the light
+/// at `address`. every light on the lan answers the multicast scan, and only the
+/// configured one may be driven
+fn answers_scan(address: IpAddress, from: IpAddress, packet: &[u8]) -> bool {
+    from == address && decode(packet).is_some_and(|reply| reply.cmd == "scan")
+}
+
+/// govee lights, driven through their local udp api
+///
+/// govee does not acknowledge commands, so every change is followed by a `devStatus`
+/// query and counts as applied once the light reports the expected power and
+/// brightness.
+pub struct Govee {
+    socket: UdpSocket<'static>,
+    address: IpAddress,
+    /// whether the light was scanned for since it last failed to answer
+    scanned: bool,
+}
+
+impl Govee {
+    /// drives the light at `address` through `socket`, which has to be bound to the
+    /// port govee lights answer on
+    pub fn new(socket: UdpSocket<'static>, address: &'static str) -> Self {
+        let address: Ipv4Address = address
+            .parse()
+            .expect("bulb addresses are checked when building");
+        Self {
+            socket,
+            address: IpAddress::Ipv4(address),
+            scanned: false,
+        }
+    }
+
+    /// multicasts a scan, returning whether the light at the configured address
+    /// answered
+    async fn scan(&mut self) -> bool {
+        let scan = IpEndpoint::new(IpAddress::Ipv4(SCAN_ADDRESS), SCAN_PORT);
+        let request = encode(
+            "scan",
+            Scan {
+                account_topic: "reserve",
+            },
+        );
+        let Ok(request) = request else {
+            return false;
+        };
+        if let Err(e) = self.socket.send_to(&request, scan).await {
+            warn!("govee scan send error: {:?}", e);
+            return false;
+        }
+        let address = self.address;
+        let socket = &mut self.socket;
+        let wait = async {
+            let mut packet = [0u8; 512];
+            loop {
+                let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                    continue;
+                };
+                if answers_scan(address, meta.endpoint.addr, &packet[..len]) {
+                    return;
+                }
+            }
+        };
+        let found = with_timeout(Duration::from_millis(GOVEE_SCAN_TIMEOUT_MS), wait)
+            .await
+            .is_ok();
+        if found {
+            info!("found govee light at {}", address);
+        } else {
+            warn!("govee light at {} did not answer the scan", address);
+        }
+        found
+    }
+
+    /// encodes the message and sends it to the light, scanning for it first when it
+    /// has not been found yet
+    async fn send_message<D: Serialize>(
+        &mut self,
+        cmd: &'static str,
+        data: D,
+    ) -> Result<(), CommandError> {
+        let Ok(message) = encode(cmd, data) else {
+            warn!("govee {} message does not fit its buffer", cmd);
+            return Err(CommandError::TooLarge);
+        };
+        if !self.scanned {
+            // lights that ignore the scan may still take commands at their address
+            self.scan().await;
+            self.scanned = true;
+        }
+        let device = IpEndpoint::new(self.address, GOVEE_COMMAND_PORT);
+        info!(
+            "sending govee message: {}",
+            core::str::from_utf8(&message).unwrap_or_default()
+        );
+        self.socket.send_to(&message, device).await.map_err(|e| {
+            warn!("govee send error: {:?}", e);
+            CommandError::Unreachable
+        })
+    }
+
+    /// asks for the light's state, checking that it matches
+    async fn confirm(
+        &mut self,
+        on: Option<bool>,
+        brightness: Option<u8>,
+    ) -> Result<(), CommandError> {
+        self.send_message("devStatus", Empty {}).await?;
+        let reply = self
+            .receive(GOVEE_REPLY_TIMEOUT_MS, |data| {
+                on.is_none_or(|on| data.on_off == Some(on as u8))
+                    && brightness.is_none_or(|brightness| data.brightness == Some(brightness))
+            })
+            .await;
+        if reply.is_none() {
+            warn!("govee light did not report the expected state");
+            // the light may have restarted, scan for it again next time
+            self.scanned = false;
+            return Err(CommandError::Mismatch);
+        }
+        Ok(())
+    }
+
+    /// waits for a reply from the light whose data `accept` takes
+    async fn receive(
+        &mut self,
+        timeout_ms: u64,
+        accept: impl Fn(&GoveeReplyData) -> bool,
+    ) -> Option<()> {
+        let address = self.address;
+        let socket = &mut self.socket;
+        let wait = async {
+            let mut packet = [0u8; 512];
+            loop {
+                let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                    continue;
+                };
+                if meta.endpoint.addr != address {
+                    continue;
+                }
+                let Some(reply) = decode(&packet[..len]) else {
+                    continue;
+                };
+                info!("govee {} reply", reply.cmd);
+                if accept(&reply.data) {
+                    return;
+                }
+            }
+        };
+        with_timeout(Duration::from_millis(timeout_ms), wait)
+            .await
+            .ok()
+    }
+}
+
+impl LightBackend for Govee {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let (color, brightness, on) = match *command {
+            LightCommand::Color(h, s, b) => {
+                // the color goes out at full brightness, the light scales it
+                let (r, g, bl) = MarkerColor::Custom(h, s, 100).rgb();
+                let color = ColorWc {
+                    color: Rgb { r, g, b: bl },
+                    color_temperature: 0,
+                };
+                (Some(color), Some(b), None)
+            }
+            LightCommand::White(brightness) => {
+                let color = ColorWc {
+                    color: Rgb { r: 0, g: 0, b: 0 },
+                    color_temperature: WHITE_TEMPERATURE,
+                };
+                (Some(color), Some(brightness), None)
+            }
+            LightCommand::Brightness(brightness) => (None, Some(brightness), None),
+            LightCommand::Power(on) => (None, None, Some(on)),
+            // govee lights switch at once
+            LightCommand::Transition(_) => return Ok(()),
+            LightCommand::ColorTemperature(kelvin) => {
+                let color = ColorWc {
+                    color: Rgb { r: 0, g: 0, b: 0 },
+                    color_temperature: kelvin.clamp(2000, 9000),
+                };
+                (Some(color), None, None)
+            }
+            // scenes are only reachable through the cloud api
+            LightCommand::Effect(_) => return Ok(()),
+        };
+        // govee brightness starts at 1
+        let brightness = brightness.map(|brightness| brightness.clamp(1, 100));
+
+        if let Some(color) = color {
+            self.send_message("colorwc", color).await?;
+        }
+        if let Some(brightness) = brightness {
+            self.send_message("brightness", Value { value: brightness })
+                .await?;
+        }
+        if let Some(on) = on {
+            self.send_message("turn", Value { value: on as u8 }).await?;
+        }
+        self.confirm(on, brightness).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    fn encoded<D: Serialize>(cmd: &'static str, data: D) -> String {
+        String::from_utf8(encode(cmd, data).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn encodes_requests() {
+        let scan = Scan {
+            account_topic: "reserve",
+        };
+        assert_eq!(
+            encoded("scan", scan),
+            r#"{"msg":{"cmd":"scan","data":{"account_topic":"reserve"}}}"#
+        );
+        let red = ColorWc {
+            color: Rgb { r: 255, g: 0, b: 0 },
+            color_temperature: 0,
+        };
+        assert_eq!(
+            encoded("colorwc", red),
+            r#"{"msg":{"cmd":"colorwc","data":{"color":{"r":255,"g":0,"b":0},"colorTemInKelvin":0}}}"#
+        );
+        assert_eq!(
+            encoded("brightness", Value { value: 20 }),
+            r#"{"msg":{"cmd":"brightness","data":{"value":20}}}"#
+        );
+        assert_eq!(
+            encoded("turn", Value { value: 1 }),
+            r#"{"msg":{"cmd":"turn","data":{"value":1}}}"#
+        );
+        assert_eq!(
+            encoded("devStatus", Empty {}),
+            r#"{"msg":{"cmd":"devStatus","data":{}}}"#
+        );
+    }
+
+    #[test]
+    fn oversized_requests_are_an_error() {
+        let scan = Scan {
+            account_topic: "an account topic far too long for a govee message to hold, \
+                            which should fail to encode rather than panic",
+        };
+        assert!(encode("scan", scan).is_err());
+    }
+
+    #[test]
+    fn decodes_status_replies() {
+        let reply = br#"{"msg":{"cmd":"devStatus","data":{"onOff":1,"brightness":100,"color":{"r":255,"g":0,"b":0},"colorTemInKelvin":7200}}}"#;
+        let reply = decode(reply).unwrap();
+        assert_eq!(reply.cmd, "devStatus");
+        assert_eq!(reply.data.on_off, Some(1));
+        assert_eq!(reply.data.brightness, Some(100));
+        assert!(decode(b"not json").is_none());
+    }
+
+    /// a light's answer to the scan, as captured from an H6199
+    fn scan_answer(ip: &str) -> String {
+        format!(
+            r#"{{"msg":{{"cmd":"scan","data":{{"ip":"{}","device":"1F:80:C5:32:32:36:72:4E","sku":"H6199","bleVersionHard":"3.01.01","bleVersionSoft":"1.03.01","wifiVersionHard":"1.00.10","wifiVersionSoft":"1.02.03"}}}}}}"#,
+            ip
+        )
+    }
+
+    fn address(last: u8) -> IpAddress {
+        IpAddress::Ipv4(Ipv4Address::new(192, 168, 2, last))
+    }
+
+    #[test]
+    fn scan_takes_only_the_configured_light() {
+        let configured = scan_answer("192.168.2.2");
+        assert!(answers_scan(address(2), address(2), configured.as_bytes()));
+        // another light on the lan is never driven in its place
+        let other = scan_answer("192.168.2.40");
+        assert!(!answers_scan(address(2), address(40), other.as_bytes()));
+    }
+
+    #[test]
+    fn scan_ignores_other_traffic() {
+        // status replies and other traffic on the port are no scan answers
+        let status = br#"{"msg":{"cmd":"devStatus","data":{"onOff":1,"brightness":5}}}"#;
+        assert!(!answers_scan(address(2), address(2), status));
+        assert!(!answers_scan(address(2), address(2), b"garbage"));
+    }
+}
@@ -0,0 +1,233 @@
+extern crate alloc;
+use super::{BulbHttpClient, CommandError, LightBackend, LightCommand};
+use crate::constants::{
+    COMMAND_DELAY_MS, HOME_ASSISTANT_ENTITY_IDS, HOME_ASSISTANT_JSON_BUFFER_SIZE,
+    HOME_ASSISTANT_TOKEN, HOME_ASSISTANT_URL, HTTP_BUFFER_SIZE,
+};
+use alloc::{format, string::String};
+use defmt::{info, warn};
+use embassy_time::{Duration, Timer};
+use reqwless::headers::ContentType;
+use reqwless::request::{Method, RequestBuilder};
+use serde::Serialize;
+
+/// color temperature used for white, in kelvin
+const WHITE_TEMPERATURE: u16 = 4000;
+
+/// the data of a `light.turn_on` or `light.turn_off` service call. fields left as
+/// `None` are not sent, so the lights keep their current value
+#[derive(Serialize)]
+struct ServiceData {
+    entity_id: &'static [&'static str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hs_color: Option<[u16; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brightness_pct: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_temp_kelvin: Option<u16>,
+    /// fade time in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transition: Option<f32>,
+}
+
+/// the service and its data carrying out the command, or `None` for commands
+/// home assistant has no call for
+fn service_call(command: &LightCommand, duration_ms: u16) -> Option<(&'static str, ServiceData)> {
+    let mut data = ServiceData {
+        entity_id: HOME_ASSISTANT_ENTITY_IDS,
+        hs_color: None,
+        brightness_pct: None,
+        color_temp_kelvin: None,
+        transition: (duration_ms > 0).then(|| f32::from(duration_ms) / 1000.0),
+    };
+    let service = match *command {
+        LightCommand::Color(h, s, b) => {
+            data.hs_color = Some([h % 360, u16::from(s.min(100))]);
+            data.brightness_pct = Some(b.min(100));
+            "turn_on"
+        }
+        LightCommand::White(brightness) => {
+            data.color_temp_kelvin = Some(WHITE_TEMPERATURE);
+            data.brightness_pct = Some(brightness.min(100));
+            "turn_on"
+        }
+        LightCommand::Brightness(brightness) => {
+            data.brightness_pct = Some(brightness.min(100));
+            "turn_on"
+        }
+        LightCommand::Power(true) => "turn_on",
+        LightCommand::Power(false) => "turn_off",
+        LightCommand::ColorTemperature(kelvin) => {
+            data.color_temp_kelvin = Some(kelvin);
+            "turn_on"
+        }
+        // effect names differ between integrations, and the transition is sent with
+        // the other calls
+        LightCommand::Effect(_) | LightCommand::Transition(_) => return None,
+    };
+    Some((service, data))
+}
+
+/// lights home assistant can reach, driven through its rest api
+///
+/// every command calls `light.turn_on` or `light.turn_off` for all of
+/// `HOME_ASSISTANT_ENTITY_IDS`, authenticated with a long-lived access token.
+pub struct HomeAssistant {
+    client: &'static mut BulbHttpClient,
+    buffer: [u8; HTTP_BUFFER_SIZE],
+    authorization: String,
+    duration_ms: u16,
+}
+
+impl HomeAssistant {
+    pub fn new(client: &'static mut BulbHttpClient) -> Self {
+        Self {
+            client,
+            buffer: [0u8; HTTP_BUFFER_SIZE],
+            authorization: format!("Bearer {}", HOME_ASSISTANT_TOKEN),
+            duration_ms: 0,
+        }
+    }
+
+    async fn call_service(&mut self, service: &str, body: &str) -> Result<(), CommandError> {
+        let url = format!("{}/api/services/light/{}", HOME_ASSISTANT_URL, service);
+        info!("sending request: POST {} {}", url.as_str(), body);
+        let request = match self.client.request(Method::POST, url.as_str()).await {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("request build error: {:?}", e);
+                Timer::after(Duration::from_secs(2)).await;
+                return Err(CommandError::Unreachable);
+            }
+        };
+        let headers = [("Authorization", self.authorization.as_str())];
+        let mut request = request
+            .body(body.as_bytes())
+            .content_type(ContentType::ApplicationJson)
+            .headers(&headers);
+        let response = match request.send(&mut self.buffer).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("request send error: {:?}", e);
+                return Err(CommandError::Unreachable);
+            }
+        };
+        let status = response.status;
+        Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
+        if !status.is_successful() {
+            warn!("home assistant answered with status {}", status);
+            return Err(CommandError::Rejected);
+        }
+        Ok(())
+    }
+}
+
+impl LightBackend for HomeAssistant {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        if let LightCommand::Transition(ms) = *command {
+            // home assistant takes the duration with every call rather than as a setting
+            self.duration_ms = ms;
+            return Ok(());
+        }
+        let Some((service, data)) = service_call(command, self.duration_ms) else {
+            return Ok(());
+        };
+        let body = match serde_json_core::to_string::<_, HOME_ASSISTANT_JSON_BUFFER_SIZE>(&data) {
+            Ok(body) => body,
+            Err(_) => {
+                warn!("home assistant service call does not fit its buffer");
+                return Err(CommandError::TooLarge);
+            }
+        };
+        self.call_service(service, &body).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulb::LightEffect;
+
+    const IDS: &[&str] = &["light.desk", "light.shelf"];
+
+    /// the service and body of the call carrying out `command` for `IDS`
+    fn encoded(command: &LightCommand, duration_ms: u16) -> (&'static str, String) {
+        let (service, mut data) = service_call(command, duration_ms).unwrap();
+        data.entity_id = IDS;
+        let body = serde_json_core::to_vec::<_, HOME_ASSISTANT_JSON_BUFFER_SIZE>(&data).unwrap();
+        (service, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn encodes_colors_for_every_light() {
+        assert_eq!(
+            encoded(&LightCommand::Color(400, 80, 120), 0),
+            (
+                "turn_on",
+                String::from(
+                    r#"{"entity_id":["light.desk","light.shelf"],"hs_color":[40,80],"brightness_pct":100}"#
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn encodes_white_brightness_and_power() {
+        assert_eq!(
+            encoded(&LightCommand::White(30), 0),
+            (
+                "turn_on",
+                format!(
+                    r#"{{"entity_id":["light.desk","light.shelf"],"brightness_pct":30,"color_temp_kelvin":{}}}"#,
+                    WHITE_TEMPERATURE
+                )
+            )
+        );
+        assert_eq!(
+            encoded(&LightCommand::ColorTemperature(2700), 0).1,
+            r#"{"entity_id":["light.desk","light.shelf"],"color_temp_kelvin":2700}"#
+        );
+        assert_eq!(
+            encoded(&LightCommand::Power(true), 0),
+            (
+                "turn_on",
+                String::from(r#"{"entity_id":["light.desk","light.shelf"]}"#)
+            )
+        );
+        assert_eq!(encoded(&LightCommand::Power(false), 0).0, "turn_off");
+    }
+
+    #[test]
+    fn sends_the_transition_in_seconds() {
+        assert_eq!(
+            encoded(&LightCommand::Brightness(50), 1500).1,
+            r#"{"entity_id":["light.desk","light.shelf"],"brightness_pct":50,"transition":1.5}"#
+        );
+        assert_eq!(
+            encoded(&LightCommand::Brightness(50), 0).1,
+            r#"{"entity_id":["light.desk","light.shelf"],"brightness_pct":50}"#
+        );
+    }
+
+    #[test]
+    fn escapes_entity_ids() {
+        let data = ServiceData {
+            entity_id: &["light.\"desk\"", "light.back\\slash"],
+            hs_color: None,
+            brightness_pct: None,
+            color_temp_kelvin: None,
+            transition: None,
+        };
+        let body = serde_json_core::to_vec::<_, HOME_ASSISTANT_JSON_BUFFER_SIZE>(&data).unwrap();
+        assert_eq!(
+            body.as_slice(),
+            br#"{"entity_id":["light.\"desk\"","light.back\\slash"]}"#
+        );
+    }
+
+    #[test]
+    fn skips_commands_without_a_service() {
+        assert!(service_call(&LightCommand::Effect(LightEffect::ColorCycle), 0).is_none());
+        assert!(service_call(&LightCommand::Transition(500), 0).is_none());
+    }
+}
@@ -0,0 +1,275 @@
+extern crate alloc;
+use super::{BulbHttpClient, CommandError, LightBackend, LightCommand};
+use crate::constants::{COMMAND_DELAY_MS, HTTP_BUFFER_SIZE, HTTP_TEMPLATES};
+use crate::marker_color::MarkerColor;
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use defmt::{info, warn};
+use embassy_time::{Duration, Timer};
+use reqwless::request::{Method, RequestBuilder};
+
+/// a request sent for one kind of command. the url, header values and body can hold
+/// placeholders, filled in when the command is sent:
+///
+/// `{address}` - the bulb's address from `BULBS`
+/// `{h}` `{s}` `{b}` - hue 0-360, saturation and brightness 0-100 of the color
+/// `{r}` `{g}` `{bl}` - the color's 8 bit rgb components
+/// `{hex}` - the color as `rrggbb`
+/// `{dimmer}` - the light's brightness 0-100
+/// `{power}` - `on` or `off`
+/// `{transition}` - fade duration in milliseconds
+#[derive(Clone)]
+pub struct HttpTemplate {
+    pub method: Method,
+    pub url: &'static str,
+    pub headers: &'static [(&'static str, &'static str)],
+    pub body: Option<&'static str>,
+}
+
+/// the requests for each kind of command, commands without one are not sent
+pub struct HttpTemplates {
+    pub color: Option<HttpTemplate>,
+    pub white: Option<HttpTemplate>,
+    pub brightness: Option<HttpTemplate>,
+    pub power: Option<HttpTemplate>,
+}
+
+/// what the placeholders are filled with, updated as commands are sent
+struct TemplateValues {
+    address: &'static str,
+    color: (u16, u8, u8),
+    dimmer: u8,
+    power: bool,
+    duration_ms: u16,
+}
+
+impl TemplateValues {
+    /// replaces the placeholders in `template`, leaving unknown ones and unmatched
+    /// braces as they are
+    fn fill(&self, template: &str) -> String {
+        let (h, s, b) = self.color;
+        let (r, g, bl) = MarkerColor::Custom(h, s, b).rgb();
+        let mut filled = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            filled.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest[1..].find(['{', '}']).map(|end| end + 1) else {
+                break;
+            };
+            if rest.as_bytes()[end] == b'{' {
+                // a placeholder can only open at the last brace before the closing one
+                filled.push_str(&rest[..end]);
+                rest = &rest[end..];
+                continue;
+            }
+            let placeholder = &rest[1..end];
+            let _ = match placeholder {
+                "address" => write!(filled, "{}", self.address),
+                "h" => write!(filled, "{}", h),
+                "s" => write!(filled, "{}", s),
+                "b" => write!(filled, "{}", b),
+                "r" => write!(filled, "{}", r),
+                "g" => write!(filled, "{}", g),
+                "bl" => write!(filled, "{}", bl),
+                "hex" => write!(filled, "{:02x}{:02x}{:02x}", r, g, bl),
+                "dimmer" => write!(filled, "{}", self.dimmer),
+                "power" => write!(filled, "{}", if self.power { "on" } else { "off" }),
+                "transition" => write!(filled, "{}", self.duration_ms),
+                _ => write!(filled, "{{{}}}", placeholder),
+            };
+            rest = &rest[end + 1..];
+        }
+        filled.push_str(rest);
+        filled
+    }
+}
+
+/// any device with a local http api, driven by the requests in `HTTP_TEMPLATES`
+pub struct TemplatedHttp {
+    client: &'static mut BulbHttpClient,
+    buffer: [u8; HTTP_BUFFER_SIZE],
+    values: TemplateValues,
+}
+
+impl TemplatedHttp {
+    pub fn new(client: &'static mut BulbHttpClient, address: &'static str) -> Self {
+        Self {
+            client,
+            buffer: [0u8; HTTP_BUFFER_SIZE],
+            values: TemplateValues {
+                address,
+                color: (0, 0, 100),
+                dimmer: 100,
+                power: true,
+                duration_ms: 0,
+            },
+        }
+    }
+
+    async fn send_template(&mut self, template: &HttpTemplate) -> Result<(), CommandError> {
+        let url = self.values.fill(template.url);
+        let body = template.body.map(|body| self.values.fill(body));
+        let header_values: Vec<String> = template
+            .headers
+            .iter()
+            .map(|(_, value)| self.values.fill(value))
+            .collect();
+        let headers: Vec<(&str, &str)> = template
+            .headers
+            .iter()
+            .zip(&header_values)
+            .map(|((name, _), value)| (*name, value.as_str()))
+            .collect();
+
+        info!("sending request: {} {}", template.method, url.as_str());
+        let request = match self.client.request(template.method, url.as_str()).await {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("request build error: {:?}", e);
+                Timer::after(Duration::from_secs(2)).await;
+                return Err(CommandError::Unreachable);
+            }
+        };
+        let mut request = request
+            .body(body.as_deref().unwrap_or_default().as_bytes())
+            .headers(&headers);
+        let res = match request.send(&mut self.buffer).await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("request send error: {:?}", e);
+                return Err(CommandError::Unreachable);
+            }
+        };
+        let status = res.status;
+        let result = match res.body().read_to_end().await {
+            Ok(read) => {
+                match core::str::from_utf8(read) {
+                    Ok(body) => info!("response {}: {:?}", status, body),
+                    Err(_) => warn!("response body is not valid UTF-8"),
+                }
+                if status.is_successful() {
+                    Ok(())
+                } else {
+                    warn!("device answered with status {}", status);
+                    Err(CommandError::Rejected)
+                }
+            }
+            Err(e) => {
+                warn!("failed to read response body: {}", e);
+                Err(CommandError::Unreachable)
+            }
+        };
+        Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
+        result
+    }
+}
+
+impl LightBackend for TemplatedHttp {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let template = match *command {
+            LightCommand::Color(h, s, b) => {
+                self.values.color = (h, s, b);
+                self.values.dimmer = b;
+                self.values.power = true;
+                HTTP_TEMPLATES.color
+            }
+            LightCommand::White(brightness) => {
+                self.values.color = (0, 0, brightness);
+                self.values.dimmer = brightness;
+                self.values.power = true;
+                HTTP_TEMPLATES.white
+            }
+            LightCommand::Brightness(brightness) => {
+                self.values.dimmer = brightness;
+                HTTP_TEMPLATES.brightness
+            }
+            LightCommand::Power(on) => {
+                self.values.power = on;
+                HTTP_TEMPLATES.power
+            }
+            LightCommand::Transition(ms) => {
+                // the duration is only ever sent through the `{transition}` placeholder
+                self.values.duration_ms = ms;
+                return Ok(());
+            }
+            // no placeholders for these
+            LightCommand::ColorTemperature(_) | LightCommand::Effect(_) => return Ok(()),
+        };
+        match template {
+            Some(template) => self.send_template(&template).await,
+            None => {
+                info!("no request template for {}", command);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::BULBS;
+
+    fn values() -> TemplateValues {
+        TemplateValues {
+            address: "192.168.2.2",
+            color: (0, 100, 100),
+            dimmer: 40,
+            power: false,
+            duration_ms: 250,
+        }
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let values = values();
+        assert_eq!(
+            values.fill("http://{address}/color?hex={hex}&brightness={dimmer}"),
+            "http://192.168.2.2/color?hex=ff0000&brightness=40"
+        );
+        assert_eq!(
+            values.fill("{h},{s},{b} {r},{g},{bl} {power} {transition}"),
+            "0,100,100 255,0,0 off 250"
+        );
+    }
+
+    #[test]
+    fn fills_placeholders_inside_json() {
+        let values = values();
+        assert_eq!(values.fill(r#"{"on":{power}}"#), r#"{"on":off}"#);
+        assert_eq!(
+            values.fill(r#"{"state":{"bri":{dimmer},"fade":{transition}}}"#),
+            r#"{"state":{"bri":40,"fade":250}}"#
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders_and_unmatched_braces() {
+        let values = values();
+        assert_eq!(values.fill("a{b"), "a{b");
+        assert_eq!(values.fill("a}b"), "a}b");
+        assert_eq!(values.fill("{x}{dimmer}"), "{x}40");
+        assert_eq!(values.fill("{{dimmer}"), "{40");
+        assert_eq!(values.fill("{dimmer}{"), "40{");
+    }
+
+    #[test]
+    fn templates_go_to_the_bulb_address() {
+        let values = TemplateValues {
+            address: BULBS[0].address,
+            ..values()
+        };
+        let templates = [
+            HTTP_TEMPLATES.color,
+            HTTP_TEMPLATES.white,
+            HTTP_TEMPLATES.brightness,
+            HTTP_TEMPLATES.power,
+        ];
+        for template in templates.iter().flatten() {
+            let url = values.fill(template.url);
+            let host = url.strip_prefix("http://").unwrap().split('/').next();
+            assert_eq!(host, Some(BULBS[0].address));
+        }
+    }
+}
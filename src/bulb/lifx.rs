@@ -0,0 +1,520 @@
+use super::{CommandError, LightBackend, LightCommand};
+use crate::constants::{LIFX_ACK_TIMEOUT_MS, LIFX_DISCOVERY_ATTEMPTS, LIFX_PORT};
+use defmt::{info, warn, Format};
+use embassy_net::udp::UdpSocket;
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address};
+use embassy_time::{with_timeout, Duration};
+
+const PROTOCOL: u16 = 1024;
+const ADDRESSABLE: u16 = 1 << 12;
+const TAGGED: u16 = 1 << 13;
+const ACK_REQUIRED: u8 = 1 << 1;
+const HEADER_SIZE: usize = 36;
+/// large enough for every message used here
+pub const PACKET_SIZE: usize = HEADER_SIZE + 16;
+
+const GET_SERVICE: u16 = 2;
+const STATE_SERVICE: u16 = 3;
+const ACKNOWLEDGEMENT: u16 = 45;
+const SET_COLOR: u16 = 102;
+const SET_LIGHT_POWER: u16 = 117;
+
+const SERVICE_UDP: u8 = 1;
+/// identifies our packets, bulbs echo it back in their replies
+const SOURCE: u32 = 0x6d6b_7273;
+/// color temperature sent with colors, it only matters for whites
+const KELVIN: u16 = 3500;
+
+/// the lifx lan messages used here
+#[derive(Format, Clone, Copy, Debug, PartialEq)]
+pub enum LifxMessage {
+    GetService,
+    StateService {
+        service: u8,
+        port: u32,
+    },
+    Acknowledgement,
+    SetColor {
+        hue: u16,
+        saturation: u16,
+        brightness: u16,
+        kelvin: u16,
+        duration_ms: u32,
+    },
+    SetLightPower {
+        on: bool,
+        duration_ms: u32,
+    },
+}
+
+/// the addressing part of the lifx header
+#[derive(Format, Clone, Copy, Debug, PartialEq)]
+pub struct LifxHeader {
+    pub source: u32,
+    /// device mac in the first six bytes, all zero for every device
+    pub target: [u8; 8],
+    pub sequence: u8,
+    pub ack_required: bool,
+}
+
+impl LifxMessage {
+    fn message_type(&self) -> u16 {
+        match self {
+            LifxMessage::GetService => GET_SERVICE,
+            LifxMessage::StateService { .. } => STATE_SERVICE,
+            LifxMessage::Acknowledgement => ACKNOWLEDGEMENT,
+            LifxMessage::SetColor { .. } => SET_COLOR,
+            LifxMessage::SetLightPower { .. } => SET_LIGHT_POWER,
+        }
+    }
+
+    /// writes the packet for the message into `packet`, returning its length
+    pub fn encode(&self, header: &LifxHeader, packet: &mut [u8; PACKET_SIZE]) -> usize {
+        let mut payload = [0u8; PACKET_SIZE - HEADER_SIZE];
+        let payload_len = match *self {
+            LifxMessage::GetService | LifxMessage::Acknowledgement => 0,
+            LifxMessage::StateService { service, port } => {
+                payload[0] = service;
+                payload[1..5].copy_from_slice(&port.to_le_bytes());
+                5
+            }
+            LifxMessage::SetColor {
+                hue,
+                saturation,
+                brightness,
+                kelvin,
+                duration_ms,
+            } => {
+                // the first byte is reserved
+                payload[1..3].copy_from_slice(&hue.to_le_bytes());
+                payload[3..5].copy_from_slice(&saturation.to_le_bytes());
+                payload[5..7].copy_from_slice(&brightness.to_le_bytes());
+                payload[7..9].copy_from_slice(&kelvin.to_le_bytes());
+                payload[9..13].copy_from_slice(&duration_ms.to_le_bytes());
+                13
+            }
+            LifxMessage::SetLightPower { on, duration_ms } => {
+                let level: u16 = if on { u16::MAX } else { 0 };
+                payload[0..2].copy_from_slice(&level.to_le_bytes());
+                payload[2..6].copy_from_slice(&duration_ms.to_le_bytes());
+                6
+            }
+        };
+        let len = HEADER_SIZE + payload_len;
+        let tagged = if header.target == [0; 8] { TAGGED } else { 0 };
+
+        packet.fill(0);
+        // frame
+        packet[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+        packet[2..4].copy_from_slice(&(PROTOCOL | ADDRESSABLE | tagged).to_le_bytes());
+        packet[4..8].copy_from_slice(&header.source.to_le_bytes());
+        // frame address, followed by six reserved bytes
+        packet[8..16].copy_from_slice(&header.target);
+        packet[22] = if header.ack_required { ACK_REQUIRED } else { 0 };
+        packet[23] = header.sequence;
+        // protocol header, between eight and two reserved bytes
+        packet[32..34].copy_from_slice(&self.message_type().to_le_bytes());
+        packet[HEADER_SIZE..len].copy_from_slice(&payload[..payload_len]);
+        len
+    }
+
+    /// parses a packet, returning `None` for malformed packets and messages not used here
+    pub fn decode(packet: &[u8]) -> Option<(LifxHeader, LifxMessage)> {
+        if packet.len() < HEADER_SIZE {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([packet[at], packet[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]])
+        };
+        let len = u16_at(0) as usize;
+        if len < HEADER_SIZE || len > packet.len() || u16_at(2) & 0x0fff != PROTOCOL {
+            return None;
+        }
+        let mut target = [0u8; 8];
+        target.copy_from_slice(&packet[8..16]);
+        let header = LifxHeader {
+            source: u32_at(4),
+            target,
+            sequence: packet[23],
+            ack_required: packet[22] & ACK_REQUIRED != 0,
+        };
+        let payload_len = len - HEADER_SIZE;
+        let message = match u16_at(32) {
+            GET_SERVICE => LifxMessage::GetService,
+            STATE_SERVICE if payload_len >= 5 => LifxMessage::StateService {
+                service: packet[HEADER_SIZE],
+                port: u32_at(HEADER_SIZE + 1),
+            },
+            ACKNOWLEDGEMENT => LifxMessage::Acknowledgement,
+            SET_COLOR if payload_len >= 13 => LifxMessage::SetColor {
+                hue: u16_at(HEADER_SIZE + 1),
+                saturation: u16_at(HEADER_SIZE + 3),
+                brightness: u16_at(HEADER_SIZE + 5),
+                kelvin: u16_at(HEADER_SIZE + 7),
+                duration_ms: u32_at(HEADER_SIZE + 9),
+            },
+            SET_LIGHT_POWER if payload_len >= 6 => LifxMessage::SetLightPower {
+                on: u16_at(HEADER_SIZE) != 0,
+                duration_ms: u32_at(HEADER_SIZE + 2),
+            },
+            _ => return None,
+        };
+        Some((header, message))
+    }
+}
+
+/// lifx bulbs, driven with their binary lan protocol over udp
+///
+/// the bulb is found by broadcasting `GetService` and waiting for the one at the
+/// configured address to answer. lifx has no brightness-only message, so the last
+/// color is kept to resend it at the new brightness.
+pub struct Lifx {
+    socket: UdpSocket<'static>,
+    address: IpAddress,
+    /// the bulb's udp endpoint and mac, once it answered discovery
+    device: Option<(IpEndpoint, [u8; 8])>,
+    sequence: u8,
+    hue: u16,
+    saturation: u16,
+    brightness: u16,
+    kelvin: u16,
+    duration_ms: u32,
+}
+
+impl Lifx {
+    /// drives the bulb at `address` through `socket`, which has to be bound already
+    pub fn new(socket: UdpSocket<'static>, address: &'static str) -> Self {
+        let address: Ipv4Address = address
+            .parse()
+            .expect("bulb addresses are checked when building");
+        Self {
+            socket,
+            address: IpAddress::Ipv4(address),
+            device: None,
+            sequence: 0,
+            hue: 0,
+            saturation: 0,
+            brightness: u16::MAX,
+            kelvin: KELVIN,
+            duration_ms: 0,
+        }
+    }
+
+    /// broadcasts `GetService` until the bulb answers with its udp port
+    async fn discover(&mut self) -> Option<(IpEndpoint, [u8; 8])> {
+        let broadcast = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), LIFX_PORT);
+        for _ in 0..LIFX_DISCOVERY_ATTEMPTS {
+            let sequence = self.next_sequence();
+            let header = LifxHeader {
+                source: SOURCE,
+                target: [0; 8],
+                sequence,
+                ack_required: false,
+            };
+            let mut packet = [0u8; PACKET_SIZE];
+            let len = LifxMessage::GetService.encode(&header, &mut packet);
+            if self
+                .socket
+                .send_to(&packet[..len], broadcast)
+                .await
+                .is_err()
+            {
+                continue;
+            }
+            let address = self.address;
+            let reply = self
+                .receive(sequence, |from, message| {
+                    from.addr == address
+                        && matches!(
+                            message,
+                            LifxMessage::StateService {
+                                service: SERVICE_UDP,
+                                ..
+                            }
+                        )
+                })
+                .await;
+            if let Some((header, LifxMessage::StateService { port, .. }, from)) = reply {
+                info!("found lifx bulb at {} on port {}", from.addr, port);
+                return Some((IpEndpoint::new(from.addr, port as u16), header.target));
+            }
+        }
+        warn!("lifx bulb at {} did not answer discovery", self.address);
+        None
+    }
+
+    /// sends the message to the bulb and waits for it to be acknowledged
+    async fn request(&mut self, message: LifxMessage) -> Result<(), CommandError> {
+        let (endpoint, target) = match self.device {
+            Some(device) => device,
+            None => match self.discover().await {
+                Some(device) => {
+                    self.device = Some(device);
+                    device
+                }
+                None => return Err(CommandError::Unreachable),
+            },
+        };
+        let sequence = self.next_sequence();
+        let header = LifxHeader {
+            source: SOURCE,
+            target,
+            sequence,
+            ack_required: true,
+        };
+        let mut packet = [0u8; PACKET_SIZE];
+        let len = message.encode(&header, &mut packet);
+        info!("sending lifx {} to {}", message, endpoint);
+        if let Err(e) = self.socket.send_to(&packet[..len], endpoint).await {
+            warn!("lifx send error: {:?}", e);
+            return Err(CommandError::Unreachable);
+        }
+        let acknowledged = self
+            .receive(sequence, |_, message| {
+                message == LifxMessage::Acknowledgement
+            })
+            .await
+            .is_some();
+        if !acknowledged {
+            // the bulb may have moved or restarted, look for it again next time
+            warn!("lifx bulb did not acknowledge {}", message);
+            self.device = None;
+            return Err(CommandError::Unreachable);
+        }
+        Ok(())
+    }
+
+    /// waits for a reply to our packet with `sequence` that `accept` takes
+    async fn receive(
+        &mut self,
+        sequence: u8,
+        accept: impl Fn(IpEndpoint, LifxMessage) -> bool,
+    ) -> Option<(LifxHeader, LifxMessage, IpEndpoint)> {
+        let socket = &mut self.socket;
+        let wait = async {
+            let mut packet = [0u8; PACKET_SIZE];
+            loop {
+                let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                    continue;
+                };
+                let Some((header, message)) = reply_to(&packet[..len], sequence) else {
+                    continue;
+                };
+                if accept(meta.endpoint, message) {
+                    return (header, message, meta.endpoint);
+                }
+            }
+        };
+        with_timeout(Duration::from_millis(LIFX_ACK_TIMEOUT_MS), wait)
+            .await
+            .ok()
+    }
+
+    fn next_sequence(&mut self) -> u8 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    fn set_color(&self) -> LifxMessage {
+        LifxMessage::SetColor {
+            hue: self.hue,
+            saturation: self.saturation,
+            brightness: self.brightness,
+            kelvin: self.kelvin,
+            duration_ms: self.duration_ms,
+        }
+    }
+}
+
+impl LightBackend for Lifx {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let message = match *command {
+            LightCommand::Color(h, s, b) => {
+                self.hue = (h as u32 % 360 * 0x10000 / 360) as u16;
+                self.saturation = percent_to_level(s);
+                self.brightness = percent_to_level(b);
+                self.set_color()
+            }
+            LightCommand::White(brightness) => {
+                self.saturation = 0;
+                self.brightness = percent_to_level(brightness);
+                self.kelvin = KELVIN;
+                self.set_color()
+            }
+            LightCommand::Brightness(brightness) => {
+                self.brightness = percent_to_level(brightness);
+                self.set_color()
+            }
+            LightCommand::Power(on) => LifxMessage::SetLightPower {
+                on,
+                duration_ms: self.duration_ms,
+            },
+            LightCommand::Transition(ms) => {
+                // lifx takes the duration with every message rather than as a setting
+                self.duration_ms = ms as u32;
+                return Ok(());
+            }
+            LightCommand::ColorTemperature(kelvin) => {
+                self.saturation = 0;
+                self.kelvin = kelvin.clamp(1500, 9000);
+                self.set_color()
+            }
+            // lifx bulbs only animate through waveforms, which have no color cycle
+            LightCommand::Effect(_) => return Ok(()),
+        };
+        self.request(message).await
+    }
+}
+
+/// decodes a packet answering ours with `sequence`, skipping other clients' traffic
+fn reply_to(packet: &[u8], sequence: u8) -> Option<(LifxHeader, LifxMessage)> {
+    LifxMessage::decode(packet)
+        .filter(|(header, _)| header.source == SOURCE && header.sequence == sequence)
+}
+
+/// scales a 0-100 percentage to lifx's 16 bit levels
+fn percent_to_level(percent: u8) -> u16 {
+    (percent.min(100) as u32 * u16::MAX as u32 / 100) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 8] = [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0, 0];
+
+    /// the broadcast `GetService` from the lifx lan protocol documentation
+    const GET_SERVICE_PACKET: [u8; 36] = [
+        0x24, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+    ];
+
+    /// the documentation's example `SetColor`, a green at full saturation and
+    /// brightness, 3500k, fading over 1024ms
+    const SET_COLOR_PACKET: [u8; 49] = [
+        0x31, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x66, 0x00, 0x00, 0x00, 0x00, 0x55, 0x55, 0xff, 0xff, 0xff, 0xff, 0xac, 0x0d,
+        0x00, 0x04, 0x00, 0x00,
+    ];
+
+    /// a bulb's answer to our discovery, offering udp on port 56700
+    const STATE_SERVICE_PACKET: [u8; 41] = [
+        0x29, 0x00, 0x00, 0x14, 0x73, 0x72, 0x6b, 0x6d, 0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x7c, 0xdd, 0x00, 0x00,
+    ];
+
+    /// switching a bulb on over a second, asking for an acknowledgement
+    const SET_LIGHT_POWER_PACKET: [u8; 42] = [
+        0x2a, 0x00, 0x00, 0x14, 0x73, 0x72, 0x6b, 0x6d, 0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x75, 0x00, 0x00, 0x00, 0xff, 0xff, 0xe8, 0x03, 0x00, 0x00,
+    ];
+
+    /// the bulb acknowledging the message above
+    const ACKNOWLEDGEMENT_PACKET: [u8; 36] = [
+        0x24, 0x00, 0x00, 0x14, 0x73, 0x72, 0x6b, 0x6d, 0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x2d, 0x00, 0x00, 0x00,
+    ];
+
+    fn header(source: u32, target: [u8; 8], sequence: u8, ack_required: bool) -> LifxHeader {
+        LifxHeader {
+            source,
+            target,
+            sequence,
+            ack_required,
+        }
+    }
+
+    fn assert_round_trip(message: LifxMessage, header: LifxHeader, bytes: &[u8]) {
+        let mut packet = [0u8; PACKET_SIZE];
+        let len = message.encode(&header, &mut packet);
+        assert_eq!(&packet[..len], bytes, "encoding {:?}", message);
+        assert_eq!(LifxMessage::decode(bytes), Some((header, message)));
+    }
+
+    #[test]
+    fn get_service() {
+        let header = header(0, [0; 8], 0, false);
+        assert_round_trip(LifxMessage::GetService, header, &GET_SERVICE_PACKET);
+    }
+
+    #[test]
+    fn state_service() {
+        let message = LifxMessage::StateService {
+            service: SERVICE_UDP,
+            port: LIFX_PORT as u32,
+        };
+        let header = header(SOURCE, MAC, 7, false);
+        assert_round_trip(message, header, &STATE_SERVICE_PACKET);
+    }
+
+    #[test]
+    fn set_color() {
+        let message = LifxMessage::SetColor {
+            hue: 0x5555,
+            saturation: u16::MAX,
+            brightness: u16::MAX,
+            kelvin: 3500,
+            duration_ms: 1024,
+        };
+        let header = header(0, [0; 8], 0, false);
+        assert_round_trip(message, header, &SET_COLOR_PACKET);
+    }
+
+    #[test]
+    fn set_light_power() {
+        let message = LifxMessage::SetLightPower {
+            on: true,
+            duration_ms: 1000,
+        };
+        let header = header(SOURCE, MAC, 8, true);
+        assert_round_trip(message, header, &SET_LIGHT_POWER_PACKET);
+    }
+
+    #[test]
+    fn acknowledgement() {
+        let header = header(SOURCE, MAC, 8, false);
+        assert_round_trip(
+            LifxMessage::Acknowledgement,
+            header,
+            &ACKNOWLEDGEMENT_PACKET,
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert_eq!(LifxMessage::decode(&GET_SERVICE_PACKET[..35]), None);
+        // a size field claiming more than was received
+        assert_eq!(LifxMessage::decode(&SET_COLOR_PACKET[..48]), None);
+        let mut wrong_protocol = GET_SERVICE_PACKET;
+        wrong_protocol[2] = 0x01;
+        assert_eq!(LifxMessage::decode(&wrong_protocol), None);
+    }
+
+    #[test]
+    fn takes_only_replies_to_our_packet() {
+        // the bulb's answer to discovery with sequence 7, and to a command with 8
+        let (found, message) = reply_to(&STATE_SERVICE_PACKET, 7).unwrap();
+        assert_eq!(found.target, MAC);
+        assert_eq!(
+            message,
+            LifxMessage::StateService {
+                service: SERVICE_UDP,
+                port: LIFX_PORT as u32
+            }
+        );
+        assert_eq!(
+            reply_to(&ACKNOWLEDGEMENT_PACKET, 8).map(|(_, message)| message),
+            Some(LifxMessage::Acknowledgement)
+        );
+        // a late answer to an earlier packet is not taken for this one
+        assert_eq!(reply_to(&ACKNOWLEDGEMENT_PACKET, 9), None);
+        // another client's broadcast carries its own source
+        assert_eq!(reply_to(&GET_SERVICE_PACKET, 0), None);
+    }
+}
@@ -0,0 +1,414 @@
+extern crate alloc;
+use super::{BulbHttpClient, CommandError, LightBackend, LightCommand};
+use crate::constants::{
+    COMMAND_DELAY_MS, HTTP_BUFFER_SIZE, SHELLY_COMPONENT, SHELLY_PASSWORD, SHELLY_USERNAME,
+};
+use crate::marker_color::MarkerColor;
+use alloc::{format, string::String};
+use core::fmt::Write;
+use defmt::{info, warn, Format};
+use embassy_time::{Duration, Instant, Timer};
+use md5::Md5;
+use reqwless::headers::ContentType;
+use reqwless::request::{Method, RequestBuilder};
+use reqwless::response::Status;
+use sha2::{Digest, Sha256};
+
+/// the shelly component driving the light
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum ShellyComponent {
+    /// a dimmable white light, colors only set its brightness
+    Light,
+    /// an rgbw strip controller or color bulb
+    Rgbw,
+}
+
+impl ShellyComponent {
+    fn set_method(&self) -> &'static str {
+        match self {
+            ShellyComponent::Light => "Light.Set",
+            ShellyComponent::Rgbw => "RGBW.Set",
+        }
+    }
+}
+
+/// the hash a digest challenge asks for
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    fn parse(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("MD5") {
+            Some(DigestAlgorithm::Md5)
+        } else if name.eq_ignore_ascii_case("SHA-256") {
+            Some(DigestAlgorithm::Sha256)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    /// hex hash of the parts joined with colons, as digest authentication hashes them
+    fn hex(&self, parts: &[&str]) -> String {
+        match self {
+            DigestAlgorithm::Md5 => hex_digest::<Md5>(parts),
+            DigestAlgorithm::Sha256 => hex_digest::<Sha256>(parts),
+        }
+    }
+}
+
+/// what a shelly digest challenge asks us to hash into the response
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    algorithm: DigestAlgorithm,
+}
+
+impl DigestChallenge {
+    /// parses a `WWW-Authenticate: Digest qop="auth", realm="..", nonce="..",
+    /// algorithm=SHA-256` header. a challenge naming no algorithm asks for md5
+    fn parse(header: &str) -> Option<Self> {
+        let params = header.trim().strip_prefix("Digest")?;
+        let mut realm = None;
+        let mut nonce = None;
+        let mut algorithm = DigestAlgorithm::Md5;
+        for param in params.split(',') {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"');
+            match name.trim() {
+                "realm" => realm = Some(String::from(value)),
+                "nonce" => nonce = Some(String::from(value)),
+                "algorithm" => algorithm = DigestAlgorithm::parse(value)?,
+                _ => {}
+            }
+        }
+        Some(Self {
+            realm: realm?,
+            nonce: nonce?,
+            algorithm,
+        })
+    }
+
+    /// the `Authorization` header answering the challenge for a `method` request to
+    /// `uri`
+    fn answer(
+        &self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        nonce_count: u32,
+        cnonce: &str,
+    ) -> String {
+        let nonce_count = format!("{:08x}", nonce_count);
+        let ha1 = self.algorithm.hex(&[username, &self.realm, password]);
+        let ha2 = self.algorithm.hex(&[method, uri]);
+        let response = self
+            .algorithm
+            .hex(&[&ha1, &self.nonce, &nonce_count, cnonce, "auth", &ha2]);
+        format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", \
+             algorithm={}, response=\"{}\", qop=auth, nc={}, cnonce=\"{}\"",
+            username,
+            self.realm,
+            self.nonce,
+            uri,
+            self.algorithm.name(),
+            response,
+            nonce_count,
+            cnonce
+        )
+    }
+}
+
+/// shelly gen2 lights, driven through their http json-rpc
+///
+/// password protected shellys answer with a digest challenge, which is kept and
+/// answered on every request until the shelly rejects it and sends a new one.
+pub struct Shelly {
+    client: &'static mut BulbHttpClient,
+    buffer: [u8; HTTP_BUFFER_SIZE],
+    address: &'static str,
+    challenge: Option<DigestChallenge>,
+    nonce_count: u32,
+    duration_ms: u16,
+}
+
+impl Shelly {
+    pub fn new(client: &'static mut BulbHttpClient, address: &'static str) -> Self {
+        Self {
+            client,
+            buffer: [0u8; HTTP_BUFFER_SIZE],
+            address,
+            challenge: None,
+            nonce_count: 0,
+            duration_ms: 0,
+        }
+    }
+
+    /// the `Authorization` header answering the last challenge, if there was one
+    fn authorization(&mut self, method: Method, uri: &str) -> Option<String> {
+        let challenge = self.challenge.as_ref()?;
+        self.nonce_count += 1;
+        let cnonce = format!("{:016x}", Instant::now().as_ticks());
+        Some(challenge.answer(
+            SHELLY_USERNAME,
+            SHELLY_PASSWORD,
+            method.as_str(),
+            uri,
+            self.nonce_count,
+            &cnonce,
+        ))
+    }
+
+    /// posts the rpc call, answering a digest challenge once if the shelly sends one
+    async fn call(&mut self, method: &str, params: &str) -> Result<(), CommandError> {
+        let uri = format!("/rpc/{}", method);
+        let url = format!("http://{}{}", self.address, uri);
+        for _ in 0..2 {
+            let authorization = self.authorization(Method::POST, &uri);
+            info!("sending request: POST {} {}", url.as_str(), params);
+            let request = match self.client.request(Method::POST, url.as_str()).await {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("request build error: {:?}", e);
+                    Timer::after(Duration::from_secs(2)).await;
+                    return Err(CommandError::Unreachable);
+                }
+            };
+            let headers = authorization
+                .as_deref()
+                .map(|authorization| [("Authorization", authorization)]);
+            let request = request
+                .body(params.as_bytes())
+                .content_type(ContentType::ApplicationJson);
+            let mut request = match &headers {
+                Some(headers) => request.headers(headers),
+                None => request,
+            };
+            let response = match request.send(&mut self.buffer).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("request send error: {:?}", e);
+                    return Err(CommandError::Unreachable);
+                }
+            };
+            if response.status == Status::Unauthorized {
+                let challenge = response
+                    .headers()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("www-authenticate"))
+                    .and_then(|(_, value)| core::str::from_utf8(value).ok())
+                    .and_then(DigestChallenge::parse);
+                if challenge.is_none() {
+                    warn!("shelly sent no md5 or sha-256 digest challenge");
+                    return Err(CommandError::Rejected);
+                }
+                info!("answering shelly digest challenge");
+                self.challenge = challenge;
+                self.nonce_count = 0;
+                continue;
+            }
+            let status = response.status;
+            Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
+            if !status.is_successful() {
+                warn!("shelly answered with status {}", status);
+                return Err(CommandError::Rejected);
+            }
+            return Ok(());
+        }
+        warn!("shelly rejected the credentials");
+        Err(CommandError::Rejected)
+    }
+}
+
+impl LightBackend for Shelly {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let mut params = String::from("{\"id\":0");
+        match (command, SHELLY_COMPONENT) {
+            (&LightCommand::Color(h, s, b), ShellyComponent::Rgbw) => {
+                // the color goes out at full brightness, the shelly scales it
+                let (r, g, bl) = MarkerColor::Custom(h, s, 100).rgb();
+                let _ = write!(
+                    params,
+                    ",\"on\":true,\"rgb\":[{},{},{}],\"white\":0,\"brightness\":{}",
+                    r,
+                    g,
+                    bl,
+                    b.min(100)
+                );
+            }
+            (&LightCommand::White(brightness), ShellyComponent::Rgbw) => {
+                let _ = write!(
+                    params,
+                    ",\"on\":true,\"rgb\":[0,0,0],\"white\":255,\"brightness\":{}",
+                    brightness.min(100)
+                );
+            }
+            (&LightCommand::Color(_, _, brightness), ShellyComponent::Light)
+            | (&LightCommand::White(brightness), ShellyComponent::Light) => {
+                let _ = write!(
+                    params,
+                    ",\"on\":true,\"brightness\":{}",
+                    brightness.min(100)
+                );
+            }
+            (&LightCommand::Brightness(brightness), _) => {
+                let _ = write!(params, ",\"brightness\":{}", brightness.min(100));
+            }
+            (&LightCommand::Power(on), _) => {
+                let _ = write!(params, ",\"on\":{}", on);
+            }
+            (&LightCommand::Transition(ms), _) => {
+                // shelly takes the duration with every call rather than as a setting
+                self.duration_ms = ms;
+                return Ok(());
+            }
+            // a single white channel has no temperature, and shelly's effects are
+            // only on its bulbs' own firmware
+            (&LightCommand::ColorTemperature(_), _) | (&LightCommand::Effect(_), _) => {
+                return Ok(());
+            }
+        }
+        if self.duration_ms > 0 {
+            let _ = write!(
+                params,
+                ",\"transition_duration\":{}.{:03}",
+                self.duration_ms / 1000,
+                self.duration_ms % 1000
+            );
+        }
+        params.push('}');
+        self.call(SHELLY_COMPONENT.set_method(), &params).await
+    }
+}
+
+fn hex_digest<D: Digest>(parts: &[&str]) -> String {
+    let mut hasher = D::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            hasher.update(b":");
+        }
+        hasher.update(part.as_bytes());
+    }
+    let mut hex = String::new();
+    for byte in hasher.finalize() {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the digest examples of rfc 7616 section 3.9.1
+    const RFC_CHALLENGE: &str = "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+         algorithm={}, nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+         opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"";
+    const RFC_CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    /// the value of a `name=value` or `name="value"` parameter of a digest header
+    fn param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+        header
+            .trim()
+            .strip_prefix("Digest")?
+            .split(',')
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim() == name)
+            .map(|(_, value)| value.trim().trim_matches('"'))
+    }
+
+    fn rfc_response(algorithm: &str) -> String {
+        let header = RFC_CHALLENGE.replace("{}", algorithm);
+        let challenge = DigestChallenge::parse(&header).unwrap();
+        let answer = challenge.answer(
+            "Mufasa",
+            "Circle of Life",
+            "GET",
+            "/dir/index.html",
+            1,
+            RFC_CNONCE,
+        );
+        String::from(param(&answer, "response").unwrap())
+    }
+
+    #[test]
+    fn answers_the_rfc_examples() {
+        assert_eq!(rfc_response("MD5"), "8ca523f5e9506fed4657c9700eebdbec");
+        assert_eq!(
+            rfc_response("SHA-256"),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+    }
+
+    #[test]
+    fn parses_challenges() {
+        let challenge =
+            DigestChallenge::parse("Digest qop=\"auth\", realm=\"shelly\", nonce=\"60dc59c6\"")
+                .unwrap();
+        assert_eq!(challenge.realm, "shelly");
+        assert_eq!(challenge.nonce, "60dc59c6");
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Md5);
+        let challenge = DigestChallenge::parse(
+            "Digest qop=\"auth\", realm=\"shelly\", nonce=\"60dc59c6\", algorithm=SHA-256",
+        )
+        .unwrap();
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+        assert!(DigestChallenge::parse(
+            "Digest realm=\"shelly\", nonce=\"60dc59c6\", algorithm=SHA-512-256"
+        )
+        .is_none());
+        assert!(DigestChallenge::parse("Basic realm=\"shelly\"").is_none());
+        assert!(DigestChallenge::parse("Digest realm=\"shelly\"").is_none());
+    }
+
+    /// the answer to a shelly's challenge, `algorithm` being the parameter it sent
+    fn shelly_answer(algorithm: &str) -> String {
+        let challenge = DigestChallenge::parse(&format!(
+            "Digest qop=\"auth\", realm=\"shellyplusrgbwpm-a0a3b3\", nonce=\"1731523524\"{}",
+            algorithm
+        ))
+        .unwrap();
+        challenge.answer(
+            SHELLY_USERNAME,
+            SHELLY_PASSWORD,
+            "POST",
+            "/rpc/RGBW.Set",
+            1,
+            "abc",
+        )
+    }
+
+    #[test]
+    fn answers_md5_challenges() {
+        let expected = "Digest username=\"admin\", realm=\"shellyplusrgbwpm-a0a3b3\", \
+             nonce=\"1731523524\", uri=\"/rpc/RGBW.Set\", algorithm=MD5, \
+             response=\"967e5d4251ee24ec1d88bbcac6de57ef\", qop=auth, nc=00000001, \
+             cnonce=\"abc\"";
+        // shellys leave out the algorithm when it is md5
+        assert_eq!(shelly_answer(""), expected);
+        assert_eq!(shelly_answer(", algorithm=MD5"), expected);
+    }
+
+    #[test]
+    fn answers_sha256_challenges() {
+        assert_eq!(
+            shelly_answer(", algorithm=SHA-256"),
+            "Digest username=\"admin\", realm=\"shellyplusrgbwpm-a0a3b3\", \
+             nonce=\"1731523524\", uri=\"/rpc/RGBW.Set\", algorithm=SHA-256, \
+             response=\"316c479b8ef500615a86289fc3eb42496715043bb9259fcf7479ac97afbde4a7\", \
+             qop=auth, nc=00000001, cnonce=\"abc\""
+        );
+    }
+}
@@ -0,0 +1,520 @@
+extern crate alloc;
+use super::{
+    close, BulbEvent, BulbHttpClient, BulbReport, CommandError, LightBackend, LightCommand,
+    LightEffect, QueryError, MAX_LIGHT_COMMANDS, REPORT_TOLERANCE,
+};
+use crate::constants::{COMMAND_DELAY_MS, HTTP_BUFFER_SIZE, TASMOTA_BACKLOG_DELAY_MS};
+use alloc::{format, string::String};
+use core::fmt;
+use defmt::{info, warn, Format};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use reqwless::request::Method;
+use reqwless::response::Status;
+use serde::Deserialize;
+
+#[derive(Format, Clone, Debug)]
+pub enum TasmotaCommand {
+    HSBColor(u16, u8, u8),
+    White(u16),
+    Dimmer(u8),
+    Power(bool),
+    /// fade speed in half seconds (1-40), 0 turning fading off
+    Fade(u8),
+    /// white color temperature in mireds (153-500)
+    CT(u16),
+    /// 0 for a steady light, otherwise one of tasmota's animations
+    Scheme(u8),
+}
+
+impl From<&LightCommand> for TasmotaCommand {
+    fn from(command: &LightCommand) -> Self {
+        match *command {
+            LightCommand::Color(h, s, b) => TasmotaCommand::HSBColor(h, s, b),
+            LightCommand::White(brightness) => TasmotaCommand::White(brightness as u16),
+            LightCommand::Brightness(brightness) => TasmotaCommand::Dimmer(brightness),
+            LightCommand::Power(on) => TasmotaCommand::Power(on),
+            LightCommand::Transition(0) => TasmotaCommand::Fade(0),
+            LightCommand::Transition(ms) => {
+                TasmotaCommand::Fade(((ms as u32 + 250) / 500).clamp(1, 40) as u8)
+            }
+            LightCommand::ColorTemperature(kelvin) => {
+                TasmotaCommand::CT((1_000_000 / kelvin.max(1) as u32).clamp(153, 500) as u16)
+            }
+            LightCommand::Effect(LightEffect::None) => TasmotaCommand::Scheme(0),
+            // scheme 2 cycles up through the colors
+            LightCommand::Effect(LightEffect::ColorCycle) => TasmotaCommand::Scheme(2),
+        }
+    }
+}
+
+impl TasmotaCommand {
+    /// the command's name and argument, as published to its mqtt `cmnd` topic
+    pub fn mqtt(&self) -> (&'static str, String) {
+        match self {
+            TasmotaCommand::HSBColor(h, s, b) => ("HSBColor", format!("{},{},{}", h, s, b)),
+            TasmotaCommand::White(value) => ("White", format!("{}", value)),
+            TasmotaCommand::Dimmer(value) => ("Dimmer", format!("{}", value)),
+            TasmotaCommand::Power(on) => ("Power", String::from(if *on { "ON" } else { "OFF" })),
+            TasmotaCommand::Fade(0) => ("Fade", String::from("0")),
+            TasmotaCommand::Fade(speed) => ("Backlog", format!("Fade 1; Speed {}", speed)),
+            TasmotaCommand::CT(mireds) => ("CT", format!("{}", mireds)),
+            TasmotaCommand::Scheme(scheme) => ("Scheme", format!("{}", scheme)),
+        }
+    }
+
+    /// the field the bulb's mqtt `RESULT` to the command has, tasmota spelling `POWER`
+    /// in capitals and publishing a result for every backlog command in turn
+    pub fn result_key(&self) -> &'static str {
+        match self {
+            TasmotaCommand::HSBColor(..) => "HSBColor",
+            // tasmota answers with the whole light state, verified by its dimmer
+            TasmotaCommand::White(_) | TasmotaCommand::Dimmer(_) => "Dimmer",
+            TasmotaCommand::Power(_) => "POWER",
+            TasmotaCommand::Fade(0) => "Fade",
+            TasmotaCommand::Fade(_) => "Speed",
+            TasmotaCommand::CT(_) => "CT",
+            TasmotaCommand::Scheme(_) => "Scheme",
+        }
+    }
+
+    /// writes the url encoded command, a fade with its speed being two commands
+    fn write_commands(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            TasmotaCommand::HSBColor(h, s, b) => {
+                write!(f, "hsbcolor%20{},{},{}", h, s, b)
+            }
+            TasmotaCommand::White(value) => write!(f, "white%20{}", value),
+            TasmotaCommand::Dimmer(value) => write!(f, "dimmer%20{}", value),
+            TasmotaCommand::Power(on) => write!(f, "power%20{}", if *on { "on" } else { "off" }),
+            TasmotaCommand::Fade(0) => write!(f, "fade%200"),
+            TasmotaCommand::Fade(speed) => write!(f, "fade%201%3B%20speed%20{}", speed),
+            TasmotaCommand::CT(mireds) => write!(f, "ct%20{}", mireds),
+            TasmotaCommand::Scheme(scheme) => write!(f, "scheme%20{}", scheme),
+        }
+    }
+}
+
+impl fmt::Display for TasmotaCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        if matches!(self, TasmotaCommand::Fade(speed) if *speed > 0) {
+            write!(f, "backlog%20")?;
+        }
+        self.write_commands(f)
+    }
+}
+
+/// several commands run one after the other through a single request
+struct Backlog<'a>(&'a [TasmotaCommand]);
+
+impl fmt::Display for Backlog<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "backlog")?;
+        for (i, command) in self.0.iter().enumerate() {
+            write!(f, "{}", if i > 0 { "%3B%20" } else { "%20" })?;
+            command.write_commands(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// the light state in tasmota's json replies and telemetry, other fields are skipped
+#[derive(Deserialize)]
+struct TasmotaState<'a> {
+    #[serde(rename = "POWER")]
+    power: Option<&'a str>,
+    #[serde(rename = "Dimmer")]
+    dimmer: Option<u8>,
+    #[serde(rename = "HSBColor")]
+    hsb_color: Option<&'a str>,
+    /// `Unknown` when tasmota did not understand the command
+    #[serde(rename = "Command")]
+    command: Option<&'a str>,
+    /// set instead of a result when the command needs a password
+    #[serde(rename = "WARNING")]
+    warning: Option<&'a str>,
+}
+
+/// the commands that, sent without an argument, report the light state
+pub const QUERY_COMMANDS: [&str; 3] = ["HSBColor", "Dimmer", "Power"];
+
+/// the command answered with the whole state, as in `STATE` telemetry
+const STATE_COMMAND: &str = "State";
+
+/// the warning tasmota answers a command that has no reply of its own with, as a
+/// backlog has none
+const NO_REPLY_WARNING: &str = "Enable weblog 2 if response expected";
+
+/// why a tasmota bulb did not apply a command
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum TasmotaError {
+    /// the request could not be sent, or its response not read
+    Request,
+    /// the bulb answered with an http error
+    Status(Status),
+    /// the reply is not tasmota's json light state
+    Malformed,
+    /// tasmota did not understand the command or refused to run it
+    Rejected,
+    /// the bulb reports a state other than the one commanded
+    Mismatch,
+}
+
+impl From<TasmotaError> for CommandError {
+    fn from(error: TasmotaError) -> Self {
+        match error {
+            TasmotaError::Request => CommandError::Unreachable,
+            TasmotaError::Status(_) | TasmotaError::Malformed | TasmotaError::Rejected => {
+                CommandError::Rejected
+            }
+            TasmotaError::Mismatch => CommandError::Mismatch,
+        }
+    }
+}
+
+/// parses a tasmota json reply or `STATE` telemetry into the light state it reports
+pub fn parse_reply(json: &[u8]) -> Result<BulbReport, TasmotaError> {
+    let (state, _) =
+        serde_json_core::from_slice::<TasmotaState>(json).map_err(|_| TasmotaError::Malformed)?;
+    let refused = state
+        .warning
+        .is_some_and(|warning| warning != NO_REPLY_WARNING);
+    if state.command == Some("Unknown") || refused {
+        return Err(TasmotaError::Rejected);
+    }
+    let color = match state.hsb_color {
+        Some(hsb) => {
+            let mut values = hsb.split(',').map(|value| value.parse::<u16>().ok());
+            match (values.next(), values.next(), values.next()) {
+                (Some(Some(h)), Some(Some(s)), Some(Some(b))) if s <= 100 && b <= 100 => {
+                    Some((h, s as u8, b as u8))
+                }
+                _ => return Err(TasmotaError::Malformed),
+            }
+        }
+        None => None,
+    };
+    Ok(BulbReport {
+        power: state.power.map(|power| power == "ON"),
+        brightness: state.dimmer,
+        color,
+    })
+}
+
+impl TasmotaCommand {
+    /// checks the state in a bulb's reply against the command it answers
+    pub fn verify(&self, report: &BulbReport) -> Result<(), TasmotaError> {
+        let applied = match *self {
+            TasmotaCommand::HSBColor(h, s, b) => {
+                let (rh, rs, rb) = report.color.ok_or(TasmotaError::Malformed)?;
+                // hue wraps around, and means nothing without saturation
+                let hue_distance = (rh % 360).abs_diff(h % 360);
+                let hue_close = s == 0 || hue_distance.min(360 - hue_distance) <= REPORT_TOLERANCE;
+                hue_close && close(rs as u16, s as u16) && close(rb as u16, b as u16)
+            }
+            TasmotaCommand::White(value) => {
+                let dimmer = report.brightness.ok_or(TasmotaError::Malformed)?;
+                close(dimmer as u16, value)
+            }
+            TasmotaCommand::Dimmer(value) => {
+                let dimmer = report.brightness.ok_or(TasmotaError::Malformed)?;
+                close(dimmer as u16, value as u16)
+            }
+            TasmotaCommand::Power(on) => report.power.ok_or(TasmotaError::Malformed)? == on,
+            // none of these are part of the light state
+            TasmotaCommand::Fade(_) | TasmotaCommand::CT(_) | TasmotaCommand::Scheme(_) => true,
+        };
+        if applied {
+            Ok(())
+        } else {
+            Err(TasmotaError::Mismatch)
+        }
+    }
+}
+
+/// checks the state tasmota reports after a backlog against each of its commands
+fn verify_backlog(backlog: &[TasmotaCommand], report: &BulbReport) -> Result<(), TasmotaError> {
+    backlog
+        .iter()
+        .try_for_each(|command| command.verify(report))
+}
+
+/// tasmota bulbs, commanded one request at a time through `/cm?cmnd=`
+///
+/// a command counts as applied once the bulb's json reply shows the commanded state.
+pub struct TasmotaHttp {
+    client: &'static mut BulbHttpClient,
+    buffer: [u8; HTTP_BUFFER_SIZE],
+    bulb_ip_addr: &'static str,
+    /// the state in the last reply, handed out by `poll`
+    report: Option<BulbReport>,
+}
+
+impl TasmotaHttp {
+    pub fn new(client: &'static mut BulbHttpClient, bulb_ip_addr: &'static str) -> Self {
+        Self {
+            client,
+            buffer: [0u8; HTTP_BUFFER_SIZE],
+            bulb_ip_addr,
+            report: None,
+        }
+    }
+
+    /// sends the command, returning the light state the bulb replied with
+    async fn request(&mut self, command: impl fmt::Display) -> Result<BulbReport, TasmotaError> {
+        let url = format!("http://{}/cm?cmnd={}", self.bulb_ip_addr, command);
+        let method = Method::POST;
+        info!("sending request: {} {}", method, url.as_str());
+        let mut req = match self.client.request(method, url.as_str()).await {
+            Ok(req) => req,
+            Err(e) => {
+                warn!("request build error: {:?}", e);
+                Timer::after(Duration::from_secs(2)).await;
+                return Err(TasmotaError::Request);
+            }
+        };
+        let res = match req.send(&mut self.buffer).await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("request send error: {:?}", e);
+                return Err(TasmotaError::Request);
+            }
+        };
+        let status = res.status;
+        let body = match res.body().read_to_end().await {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("failed to read response body: {}", e);
+                return Err(TasmotaError::Request);
+            }
+        };
+        match core::str::from_utf8(body) {
+            Ok(body) => info!("response {}: {:?}", status, body),
+            Err(_) => warn!("response body is not valid UTF-8"),
+        }
+        if !status.is_successful() {
+            return Err(TasmotaError::Status(status));
+        }
+        parse_reply(body)
+    }
+}
+
+impl LightBackend for TasmotaHttp {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let command = TasmotaCommand::from(command);
+        let result = match self.request(&command).await {
+            Ok(report) => command.verify(&report).map(|()| report),
+            Err(e) => Err(e),
+        };
+        Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
+        match result {
+            Ok(report) => {
+                self.report = Some(report);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("tasmota bulb did not apply {}: {}", command, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// runs several commands through one `Backlog` request. tasmota answers it before
+    /// running them one `TASMOTA_BACKLOG_DELAY_MS` apart, so once they are through the
+    /// state is asked for with `State` and checked against each command
+    async fn apply(&mut self, commands: &[LightCommand]) -> Result<(), CommandError> {
+        if commands.len() < 2 {
+            return match commands.first() {
+                Some(command) => self.send(command).await,
+                None => Ok(()),
+            };
+        }
+        let backlog: Vec<TasmotaCommand, MAX_LIGHT_COMMANDS> =
+            commands.iter().map(TasmotaCommand::from).collect();
+        let result = match self.request(Backlog(&backlog)).await {
+            Ok(_) => {
+                // with room for a fade, which takes two commands
+                let backlog_ms = TASMOTA_BACKLOG_DELAY_MS * (backlog.len() as u64 + 1);
+                Timer::after(Duration::from_millis(backlog_ms)).await;
+                match self.request(STATE_COMMAND).await {
+                    Ok(report) => verify_backlog(&backlog, &report).map(|()| report),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
+        match result {
+            Ok(report) => {
+                self.report = Some(report);
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    "tasmota bulb did not apply {} commands: {}",
+                    commands.len(),
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+
+    /// asks for the color, brightness and power one at a time, tasmota answering each
+    /// of them only with its own value
+    async fn query(&mut self) -> Result<BulbReport, QueryError> {
+        let mut report = BulbReport::default();
+        for command in QUERY_COMMANDS {
+            match self.request(command).await {
+                Ok(reply) => report.merge(reply),
+                Err(e) => {
+                    warn!("tasmota bulb did not answer {}: {}", command, e);
+                    return Err(QueryError::Failed);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// hands out the state from the last reply
+    async fn poll(&mut self) -> BulbEvent {
+        match self.report.take() {
+            Some(report) => BulbEvent::Reported(report),
+            None => core::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    /// what tasmota answers `hsbcolor 37,85,40` with
+    const COLOR_REPLY: &[u8] = br#"{"POWER":"ON","Dimmer":40,"Color":"66290F","HSBColor":"37,85,40","White":0,"CT":153,"Channel":[40,16,6]}"#;
+    /// what tasmota answers `backlog power on; hsbcolor 37,85,40; scheme 0` with
+    const BACKLOG_REPLY: &[u8] = br#"{"WARNING":"Enable weblog 2 if response expected"}"#;
+    /// what tasmota answers `state` with once that backlog ran
+    const STATE_REPLY: &[u8] = br#"{"Time":"2024-05-01T12:00:00","Uptime":"0T01:02:03","UptimeSec":3723,"Heap":25,"SleepMode":"Dynamic","Sleep":50,"LoadAvg":19,"MqttCount":0,"POWER":"ON","Dimmer":40,"Color":"66290F","HSBColor":"37,85,40","White":0,"CT":153,"Channel":[40,16,6],"Scheme":0,"Fade":"OFF","Speed":1,"LedTable":"ON","Wifi":{"AP":1,"SSId":"magic-markers","BSSId":"AA:BB:CC:DD:EE:FF","Channel":1,"Mode":"11n","RSSI":80,"Signal":-60,"LinkCount":1,"Downtime":"0T00:00:04"}}"#;
+
+    #[test]
+    fn parses_light_states() {
+        let report = parse_reply(COLOR_REPLY).unwrap();
+        assert_eq!(report.power, Some(true));
+        assert_eq!(report.brightness, Some(40));
+        assert_eq!(report.color, Some((37, 85, 40)));
+
+        let report = parse_reply(br#"{"Dimmer":70}"#).unwrap();
+        assert_eq!(
+            report,
+            BulbReport {
+                brightness: Some(70),
+                ..BulbReport::default()
+            }
+        );
+        let report = parse_reply(br#"{"POWER":"OFF"}"#).unwrap();
+        assert_eq!(report.power, Some(false));
+    }
+
+    #[test]
+    fn rejected_commands_are_an_error() {
+        assert_eq!(
+            parse_reply(br#"{"Command":"Unknown"}"#),
+            Err(TasmotaError::Rejected)
+        );
+        assert_eq!(
+            parse_reply(br#"{"WARNING":"Need user=<username>&password=<password>"}"#),
+            Err(TasmotaError::Rejected)
+        );
+    }
+
+    #[test]
+    fn malformed_replies_are_an_error() {
+        for reply in [
+            &b"<html>not json</html>"[..],
+            br#"{"HSBColor":"37,85"}"#,
+            br#"{"HSBColor":"37,185,40"}"#,
+            br#"{"Dimmer":"high"}"#,
+        ] {
+            assert_eq!(parse_reply(reply), Err(TasmotaError::Malformed));
+        }
+    }
+
+    #[test]
+    fn verifies_the_commanded_state() {
+        let report = parse_reply(COLOR_REPLY).unwrap();
+        assert_eq!(TasmotaCommand::HSBColor(36, 86, 41).verify(&report), Ok(()));
+        assert_eq!(TasmotaCommand::Dimmer(40).verify(&report), Ok(()));
+        assert_eq!(TasmotaCommand::Power(true).verify(&report), Ok(()));
+        assert_eq!(
+            TasmotaCommand::HSBColor(200, 85, 40).verify(&report),
+            Err(TasmotaError::Mismatch)
+        );
+        assert_eq!(
+            TasmotaCommand::Power(false).verify(&report),
+            Err(TasmotaError::Mismatch)
+        );
+        // a reply leaving out the commanded value shows nothing
+        let report = parse_reply(br#"{"POWER":"ON"}"#).unwrap();
+        assert_eq!(
+            TasmotaCommand::Dimmer(40).verify(&report),
+            Err(TasmotaError::Malformed)
+        );
+    }
+
+    #[test]
+    fn backlog_replies_show_no_state() {
+        let report = parse_reply(BACKLOG_REPLY).unwrap();
+        assert_eq!(report, BulbReport::default());
+        // so the commands cannot be checked against it
+        let backlog = [TasmotaCommand::Power(true), TasmotaCommand::Dimmer(40)];
+        assert_eq!(
+            verify_backlog(&backlog, &report),
+            Err(TasmotaError::Malformed)
+        );
+        // a fade being a backlog, its reply is all there is to check
+        assert_eq!(TasmotaCommand::Fade(4).verify(&report), Ok(()));
+    }
+
+    #[test]
+    fn verifies_every_backlog_command() {
+        let backlog = [
+            TasmotaCommand::Power(true),
+            TasmotaCommand::HSBColor(37, 85, 40),
+            TasmotaCommand::Scheme(0),
+        ];
+        let report = parse_reply(STATE_REPLY).unwrap();
+        assert_eq!(verify_backlog(&backlog, &report), Ok(()));
+        let report = parse_reply(br#"{"POWER":"ON"}"#).unwrap();
+        assert_eq!(
+            verify_backlog(&backlog, &report),
+            Err(TasmotaError::Malformed)
+        );
+        let report = parse_reply(br#"{"POWER":"ON","HSBColor":"120,85,40"}"#).unwrap();
+        assert_eq!(
+            verify_backlog(&backlog, &report),
+            Err(TasmotaError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(
+            TasmotaCommand::HSBColor(37, 85, 40).to_string(),
+            "hsbcolor%2037,85,40"
+        );
+        assert_eq!(
+            TasmotaCommand::Fade(4).to_string(),
+            "backlog%20fade%201%3B%20speed%204"
+        );
+        let backlog = [TasmotaCommand::Power(true), TasmotaCommand::Dimmer(40)];
+        assert_eq!(
+            Backlog(&backlog).to_string(),
+            "backlog%20power%20on%3B%20dimmer%2040"
+        );
+        assert_eq!(
+            TasmotaCommand::Fade(4).mqtt(),
+            ("Backlog", String::from("Fade 1; Speed 4"))
+        );
+    }
+}
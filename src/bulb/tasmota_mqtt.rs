@@ -0,0 +1,626 @@
+extern crate alloc;
+use super::tasmota::{parse_reply, TasmotaCommand, QUERY_COMMANDS};
+use super::{BulbEvent, BulbReport, CommandError, LightBackend, LightCommand, QueryError};
+use crate::constants::{
+    MQTT_BROKER_ADDRESS, MQTT_BROKER_PORT, MQTT_CLIENT_ID, MQTT_KEEP_ALIVE_SECS, MQTT_PACKET_SIZE,
+    MQTT_PASSWORD, MQTT_RECONNECT_INTERVAL_MS, MQTT_REPLY_TIMEOUT_MS, MQTT_USERNAME,
+};
+use crate::mqtt::broker::{Message, MqttBroker, LOCAL_SLOT};
+use crate::mqtt::{self, Filters, Packet, CONNACK_ACCEPTED, SUBACK_FAILURE};
+use alloc::{format, string::String};
+use defmt::{info, warn};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address};
+use embassy_time::{with_deadline, with_timeout, Duration, Instant, Timer};
+use embedded_io_async::Write;
+use heapless::{Deque, Vec};
+
+/// what an incoming packet means to us
+enum Incoming {
+    ConnAck(u8),
+    SubAck(bool),
+    /// the bulb's answer on `stat/<topic>/RESULT` to the command waiting for it
+    Result(BulbReport),
+    Event(BulbEvent),
+    Other,
+}
+
+/// where the backend finds the mqtt broker
+pub enum MqttLink {
+    /// a broker elsewhere on the network
+    Remote {
+        socket: TcpSocket<'static>,
+        broker: IpEndpoint,
+    },
+    /// the broker running in this firmware, used without a network connection
+    Embedded(&'static MqttBroker),
+}
+
+impl MqttLink {
+    /// the broker at `MQTT_BROKER_ADDRESS`, reached through `socket`
+    pub fn remote(socket: TcpSocket<'static>) -> Self {
+        let address: Ipv4Address = MQTT_BROKER_ADDRESS.parse().expect("invalid broker address");
+        MqttLink::Remote {
+            socket,
+            broker: IpEndpoint::new(IpAddress::Ipv4(address), MQTT_BROKER_PORT),
+        }
+    }
+}
+
+/// tasmota bulbs, commanded through an mqtt broker
+///
+/// commands are published to `cmnd/<topic>/<command>` and count as applied once the
+/// bulb answers on `stat/<topic>/RESULT`. the bulb's `tele/<topic>/STATE` telemetry
+/// and its `tele/<topic>/LWT` online status are reported as they arrive.
+pub struct TasmotaMqtt {
+    link: MqttLink,
+    /// the bulb's tasmota `Topic`
+    topic: &'static str,
+    client_id: String,
+    connected: bool,
+    /// bytes received after the last complete packet
+    pending: Vec<u8, MQTT_PACKET_SIZE>,
+    packet_id: u16,
+    last_sent: Instant,
+    /// events heard while waiting for something else, handed out by `poll`
+    events: Deque<BulbEvent, 4>,
+    /// the field of the result the command in flight waits for, results without it
+    /// answering an earlier command
+    awaited: Option<&'static str>,
+}
+
+impl TasmotaMqtt {
+    /// the bulb with the tasmota `topic` at index `bulb` of `BULBS`, which tells its
+    /// client id apart from the other bulbs'
+    pub fn new(link: MqttLink, topic: &'static str, bulb: usize) -> Self {
+        Self {
+            link,
+            topic,
+            client_id: format!("{}-{}", MQTT_CLIENT_ID, bulb),
+            connected: false,
+            pending: Vec::new(),
+            packet_id: 0,
+            last_sent: Instant::now(),
+            events: Deque::new(),
+            awaited: None,
+        }
+    }
+
+    /// connects to the broker and subscribes to the bulb's result and telemetry topics
+    async fn connect(&mut self) -> bool {
+        let topics = bulb_topics(self.topic);
+        let topics = topics.each_ref().map(String::as_str);
+        self.connected = false;
+        self.pending.clear();
+        match &mut self.link {
+            MqttLink::Embedded(broker) => {
+                broker.connect(LOCAL_SLOT, &self.client_id, None);
+                for topic in topics {
+                    broker.subscribe(LOCAL_SLOT, topic, 0);
+                }
+                info!("subscribed to the embedded mqtt broker");
+                self.connected = true;
+                return true;
+            }
+            MqttLink::Remote { socket, broker } => {
+                // a connection attempt cut short leaves the socket half open
+                socket.abort();
+                if let Err(e) = socket.connect(*broker).await {
+                    warn!("mqtt connect error: {:?}", e);
+                    return false;
+                }
+            }
+        }
+        let client_id = self.client_id.clone();
+        if !self.write(&connect_packet(&client_id)).await {
+            return false;
+        }
+        match self
+            .wait_for(|incoming| matches!(incoming, Incoming::ConnAck(_)))
+            .await
+        {
+            Some(Incoming::ConnAck(CONNACK_ACCEPTED)) => {}
+            Some(Incoming::ConnAck(code)) => {
+                warn!("mqtt broker refused the connection: {}", code);
+                return false;
+            }
+            _ => {
+                warn!("mqtt broker did not answer the connection");
+                return false;
+            }
+        }
+
+        let Ok(filters) = mqtt::subscribe_filters(&topics.map(|topic| (topic, 0))) else {
+            return false;
+        };
+        let subscribe = Packet::Subscribe {
+            packet_id: self.next_packet_id(),
+            filters: Filters::subscribe(&filters),
+        };
+        if !self.write(&subscribe).await {
+            return false;
+        }
+        match self
+            .wait_for(|incoming| matches!(incoming, Incoming::SubAck(_)))
+            .await
+        {
+            Some(Incoming::SubAck(true)) => {
+                info!("connected to mqtt broker at {}", MQTT_BROKER_ADDRESS);
+                self.connected = true;
+                true
+            }
+            _ => {
+                warn!("mqtt broker refused the subscriptions");
+                false
+            }
+        }
+    }
+
+    async fn write(&mut self, packet: &Packet<'_>) -> bool {
+        let socket = match &mut self.link {
+            MqttLink::Remote { socket, .. } => socket,
+            MqttLink::Embedded(broker) => {
+                // only publishing means anything to the embedded broker
+                if let Packet::Publish {
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                    ..
+                } = *packet
+                {
+                    match Message::new(topic, payload, qos, retain) {
+                        Some(message) => broker.publish(message),
+                        None => return false,
+                    }
+                }
+                return true;
+            }
+        };
+        let Ok(encoded) = packet.encode() else {
+            warn!("mqtt packet does not fit its buffer");
+            return false;
+        };
+        match socket.write_all(&encoded).await {
+            Ok(()) => {
+                self.last_sent = Instant::now();
+                true
+            }
+            Err(e) => {
+                warn!("mqtt write error: {:?}", e);
+                self.connected = false;
+                false
+            }
+        }
+    }
+
+    /// reads the next packet, returning `None` once the connection is gone
+    async fn receive(&mut self) -> Option<Incoming> {
+        let (topic, awaited) = (self.topic, self.awaited);
+        let socket = match &mut self.link {
+            MqttLink::Remote { socket, .. } => socket,
+            MqttLink::Embedded(broker) => {
+                let message = broker.receive_local().await;
+                return Some(incoming(&message.as_publish(None), topic, awaited));
+            }
+        };
+        loop {
+            match Packet::decode(&self.pending) {
+                Ok(Some((packet, len))) => {
+                    let incoming = incoming(&packet, topic, awaited);
+                    self.pending.rotate_left(len);
+                    self.pending.truncate(self.pending.len() - len);
+                    return Some(incoming);
+                }
+                Ok(None) if !self.pending.is_full() => {}
+                Ok(None) | Err(_) => {
+                    warn!("mqtt broker sent a malformed or oversized packet");
+                    self.connected = false;
+                    return None;
+                }
+            }
+            let mut chunk = [0u8; 256];
+            let room = (self.pending.capacity() - self.pending.len()).min(chunk.len());
+            match socket.read(&mut chunk[..room]).await {
+                Ok(0) | Err(_) => {
+                    self.connected = false;
+                    return None;
+                }
+                Ok(len) => self.pending.extend_from_slice(&chunk[..len]).ok()?,
+            }
+        }
+    }
+
+    /// reads packets until one `accept` takes, keeping the events read on the way
+    async fn wait_for(&mut self, accept: impl Fn(&Incoming) -> bool) -> Option<Incoming> {
+        let wait = async {
+            loop {
+                match self.receive().await? {
+                    incoming if accept(&incoming) => return Some(incoming),
+                    Incoming::Event(event) => queue_event(&mut self.events, event),
+                    _ => {}
+                }
+            }
+        };
+        with_timeout(Duration::from_millis(MQTT_REPLY_TIMEOUT_MS), wait)
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// reads the packets that arrived already, so that a late result to an earlier
+    /// command is not taken for the next one's. the events among them are kept
+    async fn drain(&mut self) {
+        while let Ok(Some(incoming)) = with_timeout(Duration::MIN, self.receive()).await {
+            if let Incoming::Event(event) = incoming {
+                queue_event(&mut self.events, event);
+            }
+        }
+    }
+
+    /// publishes a command to the bulb and waits for the result with its `result_key`
+    async fn command(
+        &mut self,
+        name: &str,
+        payload: &str,
+        result_key: &'static str,
+    ) -> Option<BulbReport> {
+        if !self.connected && !self.connect().await {
+            return None;
+        }
+        self.drain().await;
+        let topic = format!("cmnd/{}/{}", self.topic, name);
+        info!("publishing {} {}", topic.as_str(), payload);
+        let publish = Packet::Publish {
+            topic: &topic,
+            payload: payload.as_bytes(),
+            qos: 0,
+            retain: false,
+            dup: false,
+            packet_id: None,
+        };
+        if !self.write(&publish).await {
+            return None;
+        }
+        self.awaited = Some(result_key);
+        let result = self
+            .wait_for(|incoming| matches!(incoming, Incoming::Result(_)))
+            .await;
+        self.awaited = None;
+        match result {
+            Some(Incoming::Result(report)) => Some(report),
+            _ => None,
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        // packet ids start at 1
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+        self.packet_id
+    }
+}
+
+impl LightBackend for TasmotaMqtt {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let tasmota_command = TasmotaCommand::from(command);
+        let (name, payload) = tasmota_command.mqtt();
+        let result_key = tasmota_command.result_key();
+        let Some(report) = self.command(name, &payload, result_key).await else {
+            warn!("tasmota bulb did not answer {}", command);
+            return Err(CommandError::Unreachable);
+        };
+        let applied = tasmota_command.verify(&report);
+        queue_event(&mut self.events, BulbEvent::Reported(report));
+        if let Err(e) = applied {
+            warn!("tasmota bulb did not apply {}: {}", command, e);
+        }
+        applied.map_err(CommandError::from)
+    }
+
+    /// publishes the query commands without a payload, tasmota answering each of them
+    /// only with its own value
+    async fn query(&mut self) -> Result<BulbReport, QueryError> {
+        let mut report = BulbReport::default();
+        for name in QUERY_COMMANDS {
+            match self.command(name, "", name).await {
+                Some(reply) => report.merge(reply),
+                None => {
+                    warn!("tasmota bulb did not answer {}", name);
+                    return Err(QueryError::Failed);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// hands out reports and online changes, keeping the broker connection alive. a
+    /// lost broker connection is reported as the bulb going offline
+    async fn poll(&mut self) -> BulbEvent {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return event;
+            }
+            if !self.connected && !self.connect().await {
+                Timer::after(Duration::from_millis(MQTT_RECONNECT_INTERVAL_MS)).await;
+                continue;
+            }
+            // the embedded broker needs no keep alive
+            let ping_at = match self.link {
+                MqttLink::Remote { .. } => {
+                    self.last_sent + Duration::from_secs(MQTT_KEEP_ALIVE_SECS as u64 / 2)
+                }
+                MqttLink::Embedded(_) => Instant::MAX,
+            };
+            match with_deadline(ping_at, self.receive()).await {
+                Ok(Some(Incoming::Event(event))) => return event,
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    warn!("lost the mqtt broker connection");
+                    return BulbEvent::Online(false);
+                }
+                Err(_) => {
+                    self.write(&Packet::PingReq).await;
+                }
+            }
+        }
+    }
+}
+
+/// the result, telemetry and online status topics of the bulb with the tasmota `topic`
+fn bulb_topics(topic: &str) -> [String; 3] {
+    [
+        format!("stat/{}/RESULT", topic),
+        format!("tele/{}/STATE", topic),
+        format!("tele/{}/LWT", topic),
+    ]
+}
+
+fn connect_packet(client_id: &str) -> Packet<'_> {
+    Packet::Connect {
+        protocol_level: 4,
+        client_id,
+        keep_alive_secs: MQTT_KEEP_ALIVE_SECS,
+        clean_session: true,
+        will: None,
+        username: (!MQTT_USERNAME.is_empty()).then_some(MQTT_USERNAME),
+        password: (!MQTT_PASSWORD.is_empty()).then_some(MQTT_PASSWORD.as_bytes()),
+    }
+}
+
+/// queues an event for `poll`, dropping the oldest ones when the queue is full
+fn queue_event(events: &mut Deque<BulbEvent, 4>, event: BulbEvent) {
+    let mut event = event;
+    while let Err(rejected) = events.push_back(event) {
+        if let Some(dropped) = events.pop_front() {
+            warn!("dropping unhandled bulb event {}", dropped);
+        }
+        event = rejected;
+    }
+}
+
+/// sorts a packet from the broker by what it means for the bulb with the tasmota
+/// `bulb_topic`, taking only a result with the `awaited` field as the answer to a command
+fn incoming(packet: &Packet, bulb_topic: &str, awaited: Option<&str>) -> Incoming {
+    match *packet {
+        Packet::ConnAck { return_code, .. } => Incoming::ConnAck(return_code),
+        Packet::SubAck { return_codes, .. } => {
+            Incoming::SubAck(!return_codes.contains(&SUBACK_FAILURE))
+        }
+        Packet::Publish { topic, payload, .. } => {
+            let is_ours = |prefix, suffix| is_bulb_topic(topic, bulb_topic, prefix, suffix);
+            if is_ours("stat/", "/RESULT") {
+                match awaited {
+                    Some(key) if has_key(payload, key) => {
+                        parse_reply(payload).map_or(Incoming::Other, Incoming::Result)
+                    }
+                    _ => Incoming::Other,
+                }
+            } else if is_ours("tele/", "/STATE") {
+                parse_reply(payload).map_or(Incoming::Other, |report| {
+                    Incoming::Event(BulbEvent::Reported(report))
+                })
+            } else if is_ours("tele/", "/LWT") {
+                Incoming::Event(BulbEvent::Online(payload == b"Online"))
+            } else {
+                Incoming::Other
+            }
+        }
+        _ => Incoming::Other,
+    }
+}
+
+/// whether tasmota's json has a `key` field, whatever its case
+fn has_key(json: &[u8], key: &str) -> bool {
+    json.windows(key.len() + 3).any(|window| {
+        window[0] == b'"'
+            && window[1..=key.len()].eq_ignore_ascii_case(key.as_bytes())
+            && window[key.len() + 1..] == *b"\":"
+    })
+}
+
+/// whether `topic` is `<prefix><bulb_topic><suffix>`
+fn is_bulb_topic(topic: &str, bulb_topic: &str, prefix: &str, suffix: &str) -> bool {
+    topic
+        .strip_prefix(prefix)
+        .and_then(|topic| topic.strip_prefix(bulb_topic))
+        == Some(suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const TOPIC: &str = "magic-markers-bulb";
+
+    /// what the backend makes of an encoded packet, checking that it takes all of it
+    fn sorted(encoded: &[u8], awaited: Option<&str>) -> Incoming {
+        let (packet, len) = Packet::decode(encoded).unwrap().unwrap();
+        assert_eq!(len, encoded.len());
+        incoming(&packet, TOPIC, awaited)
+    }
+
+    fn publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let publish = Packet::Publish {
+            topic,
+            payload,
+            qos: 0,
+            retain: false,
+            dup: false,
+            packet_id: None,
+        };
+        publish.encode().unwrap().to_vec()
+    }
+
+    fn result(payload: &[u8]) -> Vec<u8> {
+        publish(&format!("stat/{}/RESULT", TOPIC), payload)
+    }
+
+    #[test]
+    fn sorts_broker_answers() {
+        // mosquitto's connack and a suback granting qos 0 to all three topics
+        assert!(matches!(
+            sorted(&[0x20, 0x02, 0x00, 0x00], None),
+            Incoming::ConnAck(CONNACK_ACCEPTED)
+        ));
+        assert!(matches!(
+            sorted(&[0x20, 0x02, 0x00, 0x05], None),
+            Incoming::ConnAck(5)
+        ));
+        assert!(matches!(
+            sorted(&[0x90, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00], None),
+            Incoming::SubAck(true)
+        ));
+        assert!(matches!(
+            sorted(&[0x90, 0x05, 0x00, 0x01, 0x00, SUBACK_FAILURE, 0x00], None),
+            Incoming::SubAck(false)
+        ));
+        assert!(matches!(sorted(&[0xd0, 0x00], None), Incoming::Other));
+    }
+
+    #[test]
+    fn subscribes_to_the_bulb_topics() {
+        let topics = bulb_topics(TOPIC);
+        let filters = topics.each_ref().map(|topic| (topic.as_str(), 0));
+        let filters = mqtt::subscribe_filters(&filters).unwrap();
+        let subscribe = Packet::Subscribe {
+            packet_id: 1,
+            filters: Filters::subscribe(&filters),
+        };
+        let encoded = subscribe.encode().unwrap();
+        let Some((Packet::Subscribe { filters, .. }, _)) = Packet::decode(&encoded).unwrap() else {
+            panic!("not a subscribe packet");
+        };
+        let filters: Vec<_> = filters.iter().collect();
+        assert_eq!(
+            filters,
+            [
+                ("stat/magic-markers-bulb/RESULT", 0),
+                ("tele/magic-markers-bulb/STATE", 0),
+                ("tele/magic-markers-bulb/LWT", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn takes_the_result_to_the_awaited_command() {
+        let dimmer = TasmotaCommand::Dimmer(40);
+        let answer = result(br#"{"POWER":"ON","Dimmer":40}"#);
+        let Incoming::Result(report) = sorted(&answer, Some(dimmer.result_key())) else {
+            panic!("the dimmer result was not taken");
+        };
+        assert_eq!(dimmer.verify(&report), Ok(()));
+
+        let power = TasmotaCommand::Power(false);
+        let Incoming::Result(report) = sorted(&result(br#"{"POWER":"OFF"}"#), Some("POWER")) else {
+            panic!("the power result was not taken");
+        };
+        assert_eq!(power.verify(&report), Ok(()));
+
+        // queries name the field the way tasmota does not
+        assert!(matches!(
+            sorted(&result(br#"{"POWER":"OFF"}"#), Some("Power")),
+            Incoming::Result(_)
+        ));
+    }
+
+    #[test]
+    fn leaves_late_results_to_earlier_commands() {
+        // the answer to `Power ON` coming in while `Dimmer 40` waits
+        let late = result(br#"{"POWER":"ON"}"#);
+        assert!(matches!(sorted(&late, Some("Dimmer")), Incoming::Other));
+        // and any result while no command waits
+        let late = result(br#"{"POWER":"ON","Dimmer":40}"#);
+        assert!(matches!(sorted(&late, None), Incoming::Other));
+        // a value mentioning the field is not the field
+        let late = result(br#"{"Scheme":"Dimmer"}"#);
+        assert!(matches!(sorted(&late, Some("Dimmer")), Incoming::Other));
+        let unknown = result(br#"{"Command":"Unknown"}"#);
+        assert!(matches!(sorted(&unknown, Some("Dimmer")), Incoming::Other));
+    }
+
+    #[test]
+    fn a_backlog_fade_is_answered_by_its_speed() {
+        let fade = TasmotaCommand::Fade(4);
+        assert!(matches!(
+            sorted(&result(br#"{"Fade":"ON"}"#), Some(fade.result_key())),
+            Incoming::Other
+        ));
+        assert!(matches!(
+            sorted(&result(br#"{"Speed":4}"#), Some(fade.result_key())),
+            Incoming::Result(_)
+        ));
+    }
+
+    #[test]
+    fn telemetry_arrives_as_events() {
+        let state = publish(
+            &format!("tele/{}/STATE", TOPIC),
+            br#"{"Time":"2024-05-01T12:00:00","POWER":"OFF","Dimmer":100,"Fade":"OFF"}"#,
+        );
+        let Incoming::Event(BulbEvent::Reported(report)) = sorted(&state, Some("Dimmer")) else {
+            panic!("telemetry was not reported");
+        };
+        assert_eq!(report.brightness, Some(100));
+        assert_eq!(report.power, Some(false));
+
+        let lwt = publish(&format!("tele/{}/LWT", TOPIC), b"Offline");
+        assert!(matches!(
+            sorted(&lwt, None),
+            Incoming::Event(BulbEvent::Online(false))
+        ));
+        let lwt = publish(&format!("tele/{}/LWT", TOPIC), b"Online");
+        assert!(matches!(
+            sorted(&lwt, None),
+            Incoming::Event(BulbEvent::Online(true))
+        ));
+    }
+
+    #[test]
+    fn ignores_other_bulbs() {
+        let other = publish("tele/kitchen/LWT", b"Offline");
+        assert!(matches!(sorted(&other, None), Incoming::Other));
+        let other = publish("stat/magic-markers-bulb2/RESULT", br#"{"Dimmer":40}"#);
+        assert!(matches!(sorted(&other, Some("Dimmer")), Incoming::Other));
+    }
+
+    #[test]
+    fn full_event_queue_drops_the_oldest() {
+        let mut events = Deque::new();
+        for brightness in 0..6 {
+            let report = BulbReport {
+                brightness: Some(brightness),
+                ..BulbReport::default()
+            };
+            queue_event(&mut events, BulbEvent::Reported(report));
+        }
+        let kept: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                BulbEvent::Reported(report) => report.brightness.unwrap(),
+                BulbEvent::Online(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(kept, [2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,371 @@
+extern crate alloc;
+use super::{BulbHttpClient, CommandError, LightBackend, LightCommand, LightEffect};
+use crate::constants::{COMMAND_DELAY_MS, HTTP_BUFFER_SIZE, WLED_JSON_BUFFER_SIZE};
+use crate::marker_color::MarkerColor;
+use alloc::format;
+use defmt::{info, warn};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use reqwless::headers::ContentType;
+use reqwless::request::{Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+/// the part of wled's `/json/state` a command changes. fields left as `None` are not
+/// sent, so wled keeps their current value
+#[derive(Serialize, Default)]
+struct WledStateUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on: Option<bool>,
+    /// brightness 0-255
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bri: Option<u8>,
+    /// crossfade time in tenths of a second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transition: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seg: Option<[WledSegment; 1]>,
+    /// asks wled to answer with the full state after applying the change
+    v: bool,
+}
+
+/// the main segment's primary color, white temperature and effect
+#[derive(Serialize, Default)]
+struct WledSegment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    col: Option<[[u8; 3]; 1]>,
+    /// white temperature in kelvin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cct: Option<u16>,
+    /// effect id, 0 being a solid color
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fx: Option<u8>,
+}
+
+/// the most segments wled allows on a strip
+const WLED_MAX_SEGMENTS: usize = 32;
+
+/// the part of wled's state reply that confirms a change, everything else is skipped
+#[derive(Deserialize)]
+struct WledState {
+    on: bool,
+    bri: u8,
+    #[serde(default)]
+    seg: Vec<WledSegmentState, WLED_MAX_SEGMENTS>,
+}
+
+/// a segment in wled's state reply
+#[derive(Deserialize)]
+struct WledSegmentState {
+    /// primary, secondary and tertiary color, with a fourth white value on rgbw strips
+    col: Vec<Vec<u8, 4>, 3>,
+    fx: u8,
+}
+
+impl From<&LightCommand> for WledStateUpdate {
+    fn from(command: &LightCommand) -> Self {
+        let update = WledStateUpdate {
+            v: true,
+            ..Default::default()
+        };
+        match *command {
+            LightCommand::Color(h, s, b) => {
+                // the color goes out at full brightness, wled scales it by `bri`
+                let (r, g, bl) = MarkerColor::Custom(h, s, 100).rgb();
+                WledStateUpdate {
+                    on: Some(true),
+                    bri: Some(percent_to_brightness(b)),
+                    seg: Some([WledSegment {
+                        col: Some([[r, g, bl]]),
+                        ..Default::default()
+                    }]),
+                    ..update
+                }
+            }
+            LightCommand::White(brightness) => WledStateUpdate {
+                on: Some(true),
+                bri: Some(percent_to_brightness(brightness)),
+                seg: Some([WledSegment {
+                    col: Some([[255, 255, 255]]),
+                    ..Default::default()
+                }]),
+                ..update
+            },
+            LightCommand::Brightness(brightness) => WledStateUpdate {
+                bri: Some(percent_to_brightness(brightness)),
+                ..update
+            },
+            LightCommand::Power(on) => WledStateUpdate {
+                on: Some(on),
+                ..update
+            },
+            LightCommand::Transition(ms) => WledStateUpdate {
+                transition: Some(ms.div_ceil(100)),
+                ..update
+            },
+            LightCommand::ColorTemperature(kelvin) => WledStateUpdate {
+                seg: Some([WledSegment {
+                    col: Some([[255, 255, 255]]),
+                    cct: Some(kelvin.clamp(1900, 10091)),
+                    ..Default::default()
+                }]),
+                ..update
+            },
+            LightCommand::Effect(effect) => WledStateUpdate {
+                seg: Some([WledSegment {
+                    // effect 9 is wled's rainbow
+                    fx: Some(if effect == LightEffect::ColorCycle {
+                        9
+                    } else {
+                        0
+                    }),
+                    ..Default::default()
+                }]),
+                ..update
+            },
+        }
+    }
+}
+
+impl WledStateUpdate {
+    /// whether wled's reply shows the update applied to the main segment
+    fn applied(&self, state: &WledState) -> bool {
+        let segment = state.seg.first();
+        let segment_applied = self.seg.as_ref().is_none_or(|[update]| {
+            let Some(segment) = segment else {
+                return false;
+            };
+            let color_applied = update.col.is_none_or(|[color]| {
+                segment
+                    .col
+                    .first()
+                    .is_some_and(|primary| primary.get(..3) == Some(&color[..]))
+            });
+            color_applied && update.fx.is_none_or(|fx| fx == segment.fx)
+        });
+        self.on.is_none_or(|on| on == state.on)
+            && self.bri.is_none_or(|bri| bri == state.bri)
+            && segment_applied
+    }
+
+    /// whether `reply`, wled's answer to the update, shows it applied
+    fn confirmed_by(&self, reply: &[u8]) -> bool {
+        match serde_json_core::from_slice::<WledState>(reply) {
+            Ok((state, _)) => {
+                info!("wled state: on {}, bri {}", state.on, state.bri);
+                self.applied(&state)
+            }
+            Err(_) => {
+                warn!("wled reply is not a state object");
+                false
+            }
+        }
+    }
+}
+
+/// led strips running wled, driven through its json api
+pub struct Wled {
+    client: &'static mut BulbHttpClient,
+    buffer: [u8; HTTP_BUFFER_SIZE],
+    address: &'static str,
+}
+
+impl Wled {
+    pub fn new(client: &'static mut BulbHttpClient, address: &'static str) -> Self {
+        Self {
+            client,
+            buffer: [0u8; HTTP_BUFFER_SIZE],
+            address,
+        }
+    }
+}
+
+impl LightBackend for Wled {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        let update = WledStateUpdate::from(command);
+        let body = match serde_json_core::to_vec::<_, WLED_JSON_BUFFER_SIZE>(&update) {
+            Ok(body) => body,
+            Err(_) => {
+                warn!("wled state update does not fit its buffer");
+                return Err(CommandError::TooLarge);
+            }
+        };
+        let url = format!("http://{}/json/state", self.address);
+        info!(
+            "sending request: POST {} {}",
+            url.as_str(),
+            core::str::from_utf8(&body).unwrap_or_default()
+        );
+        let request = match self.client.request(Method::POST, url.as_str()).await {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("request build error: {:?}", e);
+                Timer::after(Duration::from_secs(2)).await;
+                return Err(CommandError::Unreachable);
+            }
+        };
+        let mut request = request
+            .body(body.as_slice())
+            .content_type(ContentType::ApplicationJson);
+        let response = match request.send(&mut self.buffer).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("request send error: {:?}", e);
+                return Err(CommandError::Unreachable);
+            }
+        };
+        if !response.status.is_successful() {
+            warn!("wled answered with status {}", response.status);
+            return Err(CommandError::Rejected);
+        }
+        let applied = match response.body().read_to_end().await {
+            Ok(read) if update.confirmed_by(read) => Ok(()),
+            Ok(_) => {
+                warn!("wled did not apply {}", command);
+                Err(CommandError::Mismatch)
+            }
+            Err(e) => {
+                warn!("failed to read response body: {}", e);
+                Err(CommandError::Unreachable)
+            }
+        };
+        Timer::after(Duration::from_millis(COMMAND_DELAY_MS)).await;
+        applied
+    }
+}
+
+/// scales a 0-100 percentage to wled's 0-255 brightness
+fn percent_to_brightness(percent: u8) -> u8 {
+    ((percent.min(100) as u16 * 255 + 50) / 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    fn encode(command: &LightCommand) -> String {
+        let update = WledStateUpdate::from(command);
+        let body = serde_json_core::to_vec::<_, WLED_JSON_BUFFER_SIZE>(&update).unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    /// wled's answer to a state update with `"v":true`, with the given number of
+    /// segments, the first one showing `col` and `fx`
+    fn state_reply(on: bool, bri: u8, col: [u8; 3], fx: u8, segments: usize) -> String {
+        let mut seg = String::new();
+        for id in 0..segments {
+            let (col, fx) = if id == 0 { (col, fx) } else { ([0, 0, 0], 0) };
+            if id > 0 {
+                seg.push(',');
+            }
+            seg.push_str(&format!(
+                "{{\"id\":{id},\"start\":{},\"stop\":{},\"len\":10,\"grp\":1,\"spc\":0,\
+                 \"of\":0,\"on\":true,\"frz\":false,\"bri\":255,\"cct\":127,\
+                 \"col\":[[{},{},{},0],[0,0,0,0],[0,0,0,0]],\"fx\":{fx},\"sx\":128,\
+                 \"ix\":128,\"pal\":0,\"sel\":true,\"rev\":false,\"mi\":false}}",
+                id * 10,
+                id * 10 + 10,
+                col[0],
+                col[1],
+                col[2],
+            ));
+        }
+        format!(
+            "{{\"on\":{on},\"bri\":{bri},\"transition\":7,\"ps\":-1,\"pl\":-1,\
+             \"nl\":{{\"on\":false,\"dur\":60,\"mode\":1,\"tbri\":0,\"rem\":-1}},\
+             \"udpn\":{{\"send\":false,\"recv\":true}},\"lor\":0,\"mainseg\":0,\
+             \"seg\":[{seg}]}}"
+        )
+    }
+
+    #[test]
+    fn encodes_color_at_full_brightness_scaled_by_bri() {
+        assert_eq!(
+            encode(&LightCommand::Color(240, 100, 50)),
+            r#"{"on":true,"bri":128,"seg":[{"col":[[0,0,255]]}],"v":true}"#
+        );
+    }
+
+    #[test]
+    fn encodes_white_and_color_temperature() {
+        assert_eq!(
+            encode(&LightCommand::White(100)),
+            r#"{"on":true,"bri":255,"seg":[{"col":[[255,255,255]]}],"v":true}"#
+        );
+        assert_eq!(
+            encode(&LightCommand::ColorTemperature(2700)),
+            r#"{"seg":[{"col":[[255,255,255]],"cct":2700}],"v":true}"#
+        );
+        // wled's kelvin range ends at 1900 and 10091
+        assert_eq!(
+            encode(&LightCommand::ColorTemperature(1000)),
+            r#"{"seg":[{"col":[[255,255,255]],"cct":1900}],"v":true}"#
+        );
+    }
+
+    #[test]
+    fn encodes_power_brightness_transition_and_effect() {
+        assert_eq!(
+            encode(&LightCommand::Power(false)),
+            r#"{"on":false,"v":true}"#
+        );
+        assert_eq!(
+            encode(&LightCommand::Brightness(20)),
+            r#"{"bri":51,"v":true}"#
+        );
+        // tenths of a second, rounded up
+        assert_eq!(
+            encode(&LightCommand::Transition(750)),
+            r#"{"transition":8,"v":true}"#
+        );
+        assert_eq!(
+            encode(&LightCommand::Effect(LightEffect::ColorCycle)),
+            r#"{"seg":[{"fx":9}],"v":true}"#
+        );
+        assert_eq!(
+            encode(&LightCommand::Effect(LightEffect::None)),
+            r#"{"seg":[{"fx":0}],"v":true}"#
+        );
+    }
+
+    #[test]
+    fn state_reply_confirms_the_update() {
+        let update = WledStateUpdate::from(&LightCommand::Color(0, 100, 100));
+        let reply = state_reply(true, 255, [255, 0, 0], 0, 1);
+        assert!(update.confirmed_by(reply.as_bytes()));
+        let update = WledStateUpdate::from(&LightCommand::Effect(LightEffect::ColorCycle));
+        let reply = state_reply(true, 255, [255, 0, 0], 9, 1);
+        assert!(update.confirmed_by(reply.as_bytes()));
+    }
+
+    #[test]
+    fn reads_the_main_segment_of_a_strip_with_every_segment_used() {
+        let update = WledStateUpdate::from(&LightCommand::Color(0, 100, 100));
+        let reply = state_reply(true, 255, [255, 0, 0], 0, WLED_MAX_SEGMENTS);
+        assert!(update.confirmed_by(reply.as_bytes()));
+    }
+
+    #[test]
+    fn reply_with_another_state_does_not_confirm() {
+        let update = WledStateUpdate::from(&LightCommand::Color(240, 100, 50));
+        // the segment kept its old color
+        let reply = state_reply(true, 128, [255, 160, 0], 0, 1);
+        assert!(!update.confirmed_by(reply.as_bytes()));
+        // the strip stayed off
+        let reply = state_reply(false, 128, [0, 0, 255], 0, 1);
+        assert!(!update.confirmed_by(reply.as_bytes()));
+        // no segment at all
+        let reply = state_reply(true, 128, [0, 0, 255], 0, 0);
+        assert!(!update.confirmed_by(reply.as_bytes()));
+        // changes outside the segment are confirmed without one
+        let update = WledStateUpdate::from(&LightCommand::Brightness(50));
+        assert!(update.confirmed_by(reply.as_bytes()));
+    }
+
+    #[test]
+    fn rejects_replies_that_are_not_a_state() {
+        let update = WledStateUpdate::from(&LightCommand::Power(true));
+        assert!(!update.confirmed_by(b"{\"error\":9}"));
+        assert!(!update.confirmed_by(b"<html>not found</html>"));
+    }
+}
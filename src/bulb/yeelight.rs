@@ -0,0 +1,427 @@
+extern crate alloc;
+use super::{CommandError, LightBackend, LightCommand, LightEffect};
+use crate::constants::{
+    YEELIGHT_DISCOVERY_TIMEOUT_MS, YEELIGHT_LINE_SIZE, YEELIGHT_PORT, YEELIGHT_REPLY_TIMEOUT_MS,
+};
+use alloc::{format, string::String};
+use defmt::{info, warn};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::UdpSocket;
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Write;
+use heapless::Vec;
+use serde::Deserialize;
+
+const SEARCH_ADDRESS: Ipv4Address = Ipv4Address::new(239, 255, 255, 250);
+const SEARCH_PORT: u16 = 1982;
+const SEARCH_REQUEST: &[u8] = b"M-SEARCH * HTTP/1.1\r\n\
+HOST: 239.255.255.250:1982\r\n\
+MAN: \"ssdp:discover\"\r\n\
+ST: wifi_bulb\r\n\r\n";
+/// color temperature used for white, in kelvin
+const WHITE_TEMPERATURE: u16 = 4000;
+/// the shortest transition yeelight accepts for a smooth change
+const MIN_SMOOTH_DURATION_MS: u16 = 30;
+/// a color flow fading through red, green and blue for good, in `start_cf`'s
+/// count, end action and `duration,mode,value,brightness` steps
+const COLOR_CYCLE_FLOW: &str = "0,0,\"2000,1,16711680,-1,2000,1,65280,-1,2000,1,255,-1\"";
+
+/// a line yeelight sends back. replies carry the id of the request, notifications
+/// about property changes carry none
+#[derive(Deserialize)]
+struct YeelightReply<'a> {
+    id: Option<u32>,
+    #[serde(borrow)]
+    result: Option<[&'a str; 1]>,
+    error: Option<YeelightError>,
+}
+
+#[derive(Deserialize)]
+struct YeelightError {
+    code: i32,
+}
+
+/// yeelight bulbs, driven with newline delimited json over tcp
+///
+/// the bulb's control port is looked up with yeelight's ssdp-like multicast search,
+/// falling back to the default port when the bulb does not answer. the connection is
+/// kept open between commands and reopened after a failure.
+pub struct Yeelight {
+    socket: TcpSocket<'static>,
+    discovery: UdpSocket<'static>,
+    address: IpAddress,
+    endpoint: Option<IpEndpoint>,
+    connected: bool,
+    /// bytes received after the last complete line
+    pending: Vec<u8, YEELIGHT_LINE_SIZE>,
+    request_id: u32,
+    /// brightness last set, so colors only send `set_bright` when it changes
+    brightness: Option<u8>,
+    duration_ms: u16,
+}
+
+impl Yeelight {
+    pub fn new(
+        socket: TcpSocket<'static>,
+        discovery: UdpSocket<'static>,
+        address: &'static str,
+    ) -> Self {
+        let address: Ipv4Address = address
+            .parse()
+            .expect("bulb addresses are checked when building");
+        Self {
+            socket,
+            discovery,
+            address: IpAddress::Ipv4(address),
+            endpoint: None,
+            connected: false,
+            pending: Vec::new(),
+            request_id: 0,
+            brightness: None,
+            duration_ms: 0,
+        }
+    }
+
+    /// multicasts a search and waits for the bulb to answer with its control endpoint
+    async fn discover(&mut self) -> IpEndpoint {
+        let fallback = IpEndpoint::new(self.address, YEELIGHT_PORT);
+        let search = IpEndpoint::new(IpAddress::Ipv4(SEARCH_ADDRESS), SEARCH_PORT);
+        if let Err(e) = self.discovery.send_to(SEARCH_REQUEST, search).await {
+            warn!("yeelight search send error: {:?}", e);
+            return fallback;
+        }
+        let address = self.address;
+        let discovery = &mut self.discovery;
+        let wait = async {
+            let mut response = [0u8; 512];
+            loop {
+                let Ok((len, _)) = discovery.recv_from(&mut response).await else {
+                    continue;
+                };
+                let Ok(response) = core::str::from_utf8(&response[..len]) else {
+                    continue;
+                };
+                if let Some(endpoint) = parse_location(response).filter(|e| e.addr == address) {
+                    return endpoint;
+                }
+            }
+        };
+        match with_timeout(Duration::from_millis(YEELIGHT_DISCOVERY_TIMEOUT_MS), wait).await {
+            Ok(endpoint) => {
+                info!("found yeelight bulb at {}", endpoint);
+                endpoint
+            }
+            Err(_) => {
+                warn!(
+                    "yeelight bulb did not answer the search, using {}",
+                    fallback
+                );
+                fallback
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> bool {
+        let endpoint = match self.endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                let endpoint = self.discover().await;
+                self.endpoint = Some(endpoint);
+                endpoint
+            }
+        };
+        match self.socket.connect(endpoint).await {
+            Ok(()) => {
+                self.connected = true;
+                self.pending.clear();
+                true
+            }
+            Err(e) => {
+                warn!("yeelight connect error: {:?}", e);
+                self.endpoint = None;
+                false
+            }
+        }
+    }
+
+    /// drops the connection, so the next command reconnects
+    fn disconnect(&mut self) {
+        self.socket.abort();
+        self.connected = false;
+        self.brightness = None;
+    }
+
+    /// sends a method call and waits for the bulb to answer it with "ok"
+    async fn call(&mut self, method: &str, params: &str) -> Result<(), CommandError> {
+        if !self.connected && !self.connect().await {
+            return Err(CommandError::Unreachable);
+        }
+        self.request_id = self.request_id.wrapping_add(1);
+        let id = self.request_id;
+        let request = request_line(id, method, params);
+        info!("sending yeelight request: {}", request.trim_end());
+        if let Err(e) = self.socket.write_all(request.as_bytes()).await {
+            warn!("yeelight write error: {:?}", e);
+            self.disconnect();
+            return Err(CommandError::Unreachable);
+        }
+        match with_timeout(
+            Duration::from_millis(YEELIGHT_REPLY_TIMEOUT_MS),
+            self.reply(id),
+        )
+        .await
+        {
+            Ok(Some(true)) => Ok(()),
+            Ok(Some(false)) => Err(CommandError::Rejected),
+            Ok(None) | Err(_) => {
+                warn!("yeelight bulb did not answer {}", method);
+                self.disconnect();
+                Err(CommandError::Unreachable)
+            }
+        }
+    }
+
+    /// reads lines until the reply to `id`, returning whether it was "ok", or `None`
+    /// if the connection broke
+    async fn reply(&mut self, id: u32) -> Option<bool> {
+        let mut line = [0u8; YEELIGHT_LINE_SIZE];
+        loop {
+            let len = self.read_line(&mut line).await?;
+            if let Some(ok) = reply_result(&line[..len], id) {
+                return Some(ok);
+            }
+        }
+    }
+
+    async fn read_line(&mut self, line: &mut [u8; YEELIGHT_LINE_SIZE]) -> Option<usize> {
+        loop {
+            if let Some(end) = self.pending.iter().position(|byte| *byte == b'\n') {
+                line[..end].copy_from_slice(&self.pending[..end]);
+                self.pending.rotate_left(end + 1);
+                self.pending.truncate(self.pending.len() - end - 1);
+                return Some(end);
+            }
+            if self.pending.is_full() {
+                // a line longer than we care about, drop it
+                self.pending.clear();
+            }
+            let mut chunk = [0u8; 128];
+            let room = (self.pending.capacity() - self.pending.len()).min(chunk.len());
+            match self.socket.read(&mut chunk[..room]).await {
+                Ok(0) | Err(_) => return None,
+                Ok(len) => self.pending.extend_from_slice(&chunk[..len]).ok()?,
+            }
+        }
+    }
+
+    /// the effect and duration parameters every state change takes
+    fn effect(&self) -> String {
+        if self.duration_ms < MIN_SMOOTH_DURATION_MS {
+            String::from("\"sudden\",0")
+        } else {
+            format!("\"smooth\",{}", self.duration_ms)
+        }
+    }
+
+    async fn set_bright(&mut self, brightness: u8) -> Result<(), CommandError> {
+        // yeelight brightness starts at 1
+        let brightness = brightness.clamp(1, 100);
+        if self.brightness == Some(brightness) {
+            return Ok(());
+        }
+        let params = format!("{},{}", brightness, self.effect());
+        self.call("set_bright", &params).await?;
+        self.brightness = Some(brightness);
+        Ok(())
+    }
+}
+
+impl LightBackend for Yeelight {
+    async fn send(&mut self, command: &LightCommand) -> Result<(), CommandError> {
+        if let Some((method, params)) = method_call(command, &self.effect()) {
+            self.call(method, &params).await?;
+        }
+        match *command {
+            LightCommand::Color(_, _, brightness)
+            | LightCommand::White(brightness)
+            | LightCommand::Brightness(brightness) => self.set_bright(brightness).await,
+            LightCommand::Transition(ms) => {
+                // yeelight takes the duration with every call rather than as a setting
+                self.duration_ms = ms;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// the method and params carrying out `command`, `effect` being the effect and
+/// duration every state change takes. brightness goes out with `set_bright` after
+/// the call, and transitions only change the effect, so these have none
+fn method_call(command: &LightCommand, effect: &str) -> Option<(&'static str, String)> {
+    match *command {
+        LightCommand::Color(h, s, _) => {
+            Some(("set_hsv", format!("{},{},{}", h % 360, s.min(100), effect)))
+        }
+        LightCommand::White(_) => Some(("set_ct_abx", format!("{},{}", WHITE_TEMPERATURE, effect))),
+        LightCommand::ColorTemperature(kelvin) => Some((
+            "set_ct_abx",
+            format!("{},{}", kelvin.clamp(1700, 6500), effect),
+        )),
+        LightCommand::Power(on) => Some((
+            "set_power",
+            format!("\"{}\",{}", if on { "on" } else { "off" }, effect),
+        )),
+        // repeats forever, keeping the current brightness (-1)
+        LightCommand::Effect(LightEffect::ColorCycle) => {
+            Some(("start_cf", String::from(COLOR_CYCLE_FLOW)))
+        }
+        LightCommand::Effect(LightEffect::None) => Some(("stop_cf", String::new())),
+        LightCommand::Brightness(_) | LightCommand::Transition(_) => None,
+    }
+}
+
+/// a method call as the json line yeelight reads
+fn request_line(id: u32, method: &str, params: &str) -> String {
+    format!(
+        "{{\"id\":{},\"method\":\"{}\",\"params\":[{}]}}\r\n",
+        id, method, params
+    )
+}
+
+/// whether `line` answers the request `id` with "ok", or `None` when it is a
+/// notification, another request's reply, or not json
+fn reply_result(line: &[u8], id: u32) -> Option<bool> {
+    let (reply, _) = serde_json_core::from_slice::<YeelightReply>(line).ok()?;
+    if reply.id != Some(id) {
+        return None;
+    }
+    if let Some(error) = reply.error {
+        warn!("yeelight error {}", error.code);
+        return Some(false);
+    }
+    Some(reply.result == Some(["ok"]))
+}
+
+/// finds the control endpoint in a search response's `Location: yeelight://ip:port`
+fn parse_location(response: &str) -> Option<IpEndpoint> {
+    let location = response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim())
+    })?;
+    let (address, port) = location.strip_prefix("yeelight://")?.split_once(':')?;
+    let address: Ipv4Address = address.parse().ok()?;
+    Some(IpEndpoint::new(
+        IpAddress::Ipv4(address),
+        port.parse().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_search_response_location() {
+        let response = "HTTP/1.1 200 OK\r\n\
+            Cache-Control: max-age=3600\r\n\
+            Date: \r\n\
+            Ext: \r\n\
+            Location: yeelight://192.168.2.2:55443\r\n\
+            Server: POSIX UPnP/1.0 YGLC/1\r\n\
+            id: 0x000000000015243f\r\n\
+            model: color\r\n\
+            fw_ver: 18\r\n\
+            support: get_prop set_default set_power toggle set_bright start_cf stop_cf\r\n\
+            power: on\r\n\
+            bright: 100\r\n\r\n";
+        assert_eq!(
+            parse_location(response),
+            Some(IpEndpoint::new(
+                IpAddress::Ipv4(Ipv4Address::new(192, 168, 2, 2)),
+                55443
+            ))
+        );
+        // header names are case insensitive
+        assert_eq!(
+            parse_location("LOCATION: yeelight://192.168.2.9:1234\r\n")
+                .map(|endpoint| endpoint.port),
+            Some(1234)
+        );
+    }
+
+    #[test]
+    fn rejects_search_responses_without_a_location() {
+        assert_eq!(parse_location("HTTP/1.1 200 OK\r\nid: 0x1\r\n\r\n"), None);
+        assert_eq!(
+            parse_location("Location: http://192.168.2.2:55443\r\n"),
+            None
+        );
+        assert_eq!(parse_location("Location: yeelight://bulb:55443\r\n"), None);
+        assert_eq!(parse_location("Location: yeelight://192.168.2.2\r\n"), None);
+    }
+
+    fn encoded(command: &LightCommand, effect: &str) -> String {
+        let (method, params) = method_call(command, effect).unwrap();
+        request_line(7, method, &params)
+    }
+
+    #[test]
+    fn encodes_method_calls() {
+        assert_eq!(
+            encoded(&LightCommand::Color(400, 120, 50), "\"smooth\",500"),
+            "{\"id\":7,\"method\":\"set_hsv\",\"params\":[40,100,\"smooth\",500]}\r\n"
+        );
+        assert_eq!(
+            encoded(&LightCommand::White(80), "\"sudden\",0"),
+            "{\"id\":7,\"method\":\"set_ct_abx\",\"params\":[4000,\"sudden\",0]}\r\n"
+        );
+        assert_eq!(
+            encoded(&LightCommand::ColorTemperature(10000), "\"sudden\",0"),
+            "{\"id\":7,\"method\":\"set_ct_abx\",\"params\":[6500,\"sudden\",0]}\r\n"
+        );
+        assert_eq!(
+            encoded(&LightCommand::Power(false), "\"sudden\",0"),
+            "{\"id\":7,\"method\":\"set_power\",\"params\":[\"off\",\"sudden\",0]}\r\n"
+        );
+        assert_eq!(
+            encoded(
+                &LightCommand::Effect(LightEffect::ColorCycle),
+                "\"sudden\",0"
+            ),
+            "{\"id\":7,\"method\":\"start_cf\",\"params\":[0,0,\
+             \"2000,1,16711680,-1,2000,1,65280,-1,2000,1,255,-1\"]}\r\n"
+        );
+        assert_eq!(
+            encoded(&LightCommand::Effect(LightEffect::None), "\"sudden\",0"),
+            "{\"id\":7,\"method\":\"stop_cf\",\"params\":[]}\r\n"
+        );
+        assert!(method_call(&LightCommand::Brightness(50), "\"sudden\",0").is_none());
+        assert!(method_call(&LightCommand::Transition(500), "\"sudden\",0").is_none());
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(reply_result(br#"{"id":7, "result":["ok"]}"#, 7), Some(true));
+        assert_eq!(
+            reply_result(
+                br#"{"id":7, "error":{"code":-5000, "message":"general error"}}"#,
+                7
+            ),
+            Some(false)
+        );
+        // replies to other requests and property notifications are skipped
+        assert_eq!(reply_result(br#"{"id":6, "result":["ok"]}"#, 7), None);
+        assert_eq!(
+            reply_result(
+                br#"{"method":"props","params":{"power":"on", "bright":"10"}}"#,
+                7
+            ),
+            None
+        );
+        assert_eq!(reply_result(b"garbage", 7), None);
+    }
+}
@@ -1,16 +1,51 @@
-use crate::state::{StateCommand, StateSignal};
-use embassy_time::{Duration, Timer};
+#[cfg(target_os = "none")]
+use crate::constants::{BUTTON_LONG_PRESS_MS, BUTTON_POLL_INTERVAL_MS};
+#[cfg(target_os = "none")]
+use crate::state::{StateChannel, StateCommand};
+use defmt::Format;
+#[cfg(target_os = "none")]
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(target_os = "none")]
 use esp_hal::gpio::Input;
 
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum ButtonGesture {
+    /// pressed and released before the long press threshold
+    Short,
+    /// held for at least `BUTTON_LONG_PRESS_MS`, reported while still held
+    Long,
+}
+
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
-pub async fn button_task(button: Input<'static>, state_signal: &'static StateSignal) {
-    let mut was_pressed = false;
+pub async fn button_task(button: Input<'static>, state_channel: &'static StateChannel) {
+    let mut pressed_at: Option<Instant> = None;
+    let mut long_press_sent = false;
     loop {
         let is_pressed = button.is_low();
-        if is_pressed && !was_pressed {
-            state_signal.signal(StateCommand::ToggleDimmer);
+        match pressed_at {
+            None if is_pressed => {
+                pressed_at = Some(Instant::now());
+                long_press_sent = false;
+            }
+            Some(at) if is_pressed => {
+                if !long_press_sent && at.elapsed() >= Duration::from_millis(BUTTON_LONG_PRESS_MS) {
+                    state_channel
+                        .send(StateCommand::ButtonPress(ButtonGesture::Long))
+                        .await;
+                    long_press_sent = true;
+                }
+            }
+            Some(_) => {
+                if !long_press_sent {
+                    state_channel
+                        .send(StateCommand::ButtonPress(ButtonGesture::Short))
+                        .await;
+                }
+                pressed_at = None;
+            }
+            None => {}
         }
-        was_pressed = is_pressed;
-        Timer::after(Duration::from_millis(100)).await;
+        Timer::after(Duration::from_millis(BUTTON_POLL_INTERVAL_MS)).await;
     }
 }
@@ -1,14 +1,133 @@
+use crate::bulb::http_template::{HttpTemplate, HttpTemplates};
+use crate::bulb::shelly::ShellyComponent;
+use crate::bulb::{BulbBackend, BulbSelection, BulbTarget};
+use crate::state::RemovalPolicy;
+use reqwless::request::Method;
+
 pub const SSID: &str = "magic-markers";
 pub const PASSWORD: &str = "magic-markers";
-pub const BULB_IP_ADDRESS: &str = "192.168.2.2";
+/// the bulbs the firmware drives, each in its own task. the tasmota mqtt backend takes
+/// the bulb's tasmota `Topic` as its address
+pub const BULBS: &[BulbTarget] = &[BulbTarget {
+    name: "bulb",
+    address: "192.168.2.2",
+    groups: &[],
+}];
+/// most bulbs `BULBS` can list
+pub const MAX_BULBS: usize = 4;
+/// the bulbs markers and the button change
+pub const MARKER_BULBS: BulbSelection = BulbSelection::All;
+/// color temperature of plain white light, in kelvin
+pub const DEFAULT_WHITE_TEMPERATURE: u16 = 4000;
+/// protocol the bulbs in `BULBS` speak
+pub const BULB_BACKEND: BulbBackend = BulbBackend::Tasmota;
 pub const GATEWAY_IP_ADDRESS: &str = "192.168.2.1";
 pub const RFID_I2C_ADDRESS: u8 = 0x28;
+pub const PN532_I2C_ADDRESS: u8 = 0x24;
 
 pub const HEAP_SIZE: usize = 72 * 1024;
 pub const HTTP_BUFFER_SIZE: usize = 4096;
 pub const I2C_FREQUENCY_KHZ: u32 = 100;
+pub const SPI_FREQUENCY_MHZ: u32 = 4;
 pub const HTTP_TIMEOUT_SECS: u64 = 5;
+/// largest json body sent to a wled strip
+pub const WLED_JSON_BUFFER_SIZE: usize = 128;
+pub const UDP_BUFFER_SIZE: usize = 1024;
+pub const LIFX_PORT: u16 = 56700;
+/// how long a lifx bulb gets to answer discovery or acknowledge a message
+pub const LIFX_ACK_TIMEOUT_MS: u64 = 500;
+pub const LIFX_DISCOVERY_ATTEMPTS: u8 = 3;
+pub const TCP_BUFFER_SIZE: usize = 1024;
+pub const YEELIGHT_PORT: u16 = 55443;
+/// longest reply line kept from a yeelight bulb, longer notifications are dropped
+pub const YEELIGHT_LINE_SIZE: usize = 256;
+pub const YEELIGHT_REPLY_TIMEOUT_MS: u64 = 1000;
+pub const YEELIGHT_DISCOVERY_TIMEOUT_MS: u64 = 1000;
+/// govee lights answer scans and status queries on this port
+pub const GOVEE_LISTEN_PORT: u16 = 4002;
+pub const GOVEE_COMMAND_PORT: u16 = 4003;
+/// largest json message sent to a govee light
+pub const GOVEE_JSON_BUFFER_SIZE: usize = 128;
+pub const GOVEE_SCAN_TIMEOUT_MS: u64 = 1000;
+pub const GOVEE_REPLY_TIMEOUT_MS: u64 = 1000;
+pub const SHELLY_COMPONENT: ShellyComponent = ShellyComponent::Rgbw;
+/// gen2 shellys always authenticate the `admin` user
+pub const SHELLY_USERNAME: &str = "admin";
+pub const SHELLY_PASSWORD: &str = "magic-markers";
+/// home assistant's base url, used instead of the bulb addresses by its backend
+pub const HOME_ASSISTANT_URL: &str = "http://192.168.2.3:8123";
+/// a long-lived access token, created on the home assistant profile page
+pub const HOME_ASSISTANT_TOKEN: &str = "";
+/// the lights every command goes to
+pub const HOME_ASSISTANT_ENTITY_IDS: &[&str] = &["light.magic_markers"];
+/// largest json body sent to home assistant, which grows with the entity ids
+pub const HOME_ASSISTANT_JSON_BUFFER_SIZE: usize = 512;
+/// the broker tasmota bulbs publish to, for the tasmota mqtt backend
+pub const MQTT_BROKER_ADDRESS: &str = "192.168.2.3";
+pub const MQTT_BROKER_PORT: u16 = 1883;
+/// each bulb connects as this, followed by `-` and its index in `BULBS`
+pub const MQTT_CLIENT_ID: &str = "magic-markers";
+/// left empty for brokers without authentication
+pub const MQTT_USERNAME: &str = "";
+pub const MQTT_PASSWORD: &str = "";
+pub const MQTT_KEEP_ALIVE_SECS: u16 = 30;
+/// largest mqtt packet sent or received, tasmota's `STATE` telemetry is the largest
+pub const MQTT_PACKET_SIZE: usize = 1024;
+/// how long the broker gets to answer and the bulb to report a command's result
+pub const MQTT_REPLY_TIMEOUT_MS: u64 = 2000;
+pub const MQTT_RECONNECT_INTERVAL_MS: u64 = 5000;
+/// runs an mqtt broker on the access point for the bulbs to connect to, which the
+/// tasmota mqtt backend then uses instead of the one at `MQTT_BROKER_ADDRESS`
+pub const MQTT_EMBEDDED_BROKER: bool = false;
+pub const MQTT_BROKER_MAX_CLIENTS: usize = 4;
+pub const MQTT_MAX_SUBSCRIPTIONS: usize = 8;
+pub const MQTT_MAX_RETAINED: usize = 8;
+/// messages queued for a client before new ones are dropped
+pub const MQTT_OUTBOX_SIZE: usize = 4;
+/// largest message payload the broker keeps
+pub const MQTT_PAYLOAD_SIZE: usize = 768;
+/// how long a new connection gets to send its connect packet
+pub const MQTT_BROKER_CONNECT_TIMEOUT_SECS: u64 = 10;
+/// the tasmota `DevGroupName` the device group backend sends to
+pub const DEVICE_GROUP_NAME: &str = "magic-markers";
+pub const DEVICE_GROUP_PORT: u16 = 4447;
+pub const DEVICE_GROUP_PACKET_SIZE: usize = 128;
+/// how long the members get to acknowledge a message before it is sent again
+pub const DEVICE_GROUP_ACK_TIMEOUT_MS: u64 = 200;
+pub const DEVICE_GROUP_SEND_ATTEMPTS: u8 = 5;
+/// sockets the network stack holds: dns, up to two for every bulb, plus one for every
+/// client of the embedded mqtt broker
+pub const NETWORK_SOCKETS: usize = 1 + 2 * MAX_BULBS + MQTT_BROKER_MAX_CLIENTS;
+/// requests sent by the templated http backend, see `HttpTemplate` for the placeholders
+pub const HTTP_TEMPLATES: HttpTemplates = HttpTemplates {
+    color: Some(HttpTemplate {
+        method: Method::GET,
+        url: "http://{address}/color?hex={hex}&brightness={dimmer}",
+        headers: &[],
+        body: None,
+    }),
+    white: Some(HttpTemplate {
+        method: Method::GET,
+        url: "http://{address}/white?brightness={dimmer}",
+        headers: &[],
+        body: None,
+    }),
+    brightness: Some(HttpTemplate {
+        method: Method::GET,
+        url: "http://{address}/brightness?value={dimmer}",
+        headers: &[],
+        body: None,
+    }),
+    power: Some(HttpTemplate {
+        method: Method::GET,
+        url: "http://{address}/{power}",
+        headers: &[],
+        body: None,
+    }),
+};
 pub const COMMAND_DELAY_MS: u64 = 500;
+/// the pause tasmota takes between backlog commands, its `SetOption34`
+pub const TASMOTA_BACKLOG_DELAY_MS: u64 = 200;
 
 pub const LED_FLASH_ON_TIME_MS: u32 = 100;
 pub const LED_FLASH_OFF_TIME_MS: u32 = 100;
@@ -16,5 +135,44 @@ pub const LED_FLASH_CYCLE_TIME_MS: u32 = 300;
 pub const LED_SLOW_BLINK_ON_TIME_MS: u32 = 500;
 pub const LED_SLOW_BLINK_OFF_TIME_MS: u32 = 1500;
 pub const LED_BUTTON_FLASH_TIME_MS: u32 = 150;
+pub const LED_ENROLLMENT_BLINK_TIME_MS: u32 = 100;
+pub const LED_TAG_WRITER_CYCLE_TIME_MS: u32 = 1000;
+pub const LED_TAG_RESULT_TIME_MS: u32 = 1000;
+pub const LED_TAG_FAILURE_FLASH_TIME_MS: u32 = 50;
+pub const LED_READER_FAULT_CYCLE_TIME_MS: u32 = 2000;
+
+pub const BUTTON_POLL_INTERVAL_MS: u64 = 100;
+pub const BUTTON_LONG_PRESS_MS: u64 = 2000;
+pub const ENROLLMENT_TIMEOUT_SECS: u64 = 30;
 
 pub const PERIODIC_SYNC_INTERVAL_SECS: u64 = 10;
+
+/// offset of the `markers` data partition, see partitions.csv
+pub const MARKER_REGISTRY_FLASH_OFFSET: u32 = 0x210000;
+pub const MARKER_REGISTRY_CAPACITY: usize = 32;
+pub const MARKER_NAME_LENGTH: usize = 16;
+/// how much ntag user memory to read when looking for an ndef color record
+pub const NDEF_READ_LIMIT: usize = 64;
+
+pub const RFID_POLL_INTERVAL_MS: u64 = 10;
+/// upper bound on a single reader transceive, the reader's own timer gives up at 25ms
+pub const RFID_TRANSCEIVE_TIMEOUT_MS: u64 = 50;
+/// upper bound on the pn532 acknowledging or answering a command
+pub const PN532_RESPONSE_TIMEOUT_MS: u64 = 100;
+/// first and last delay between attempts to bring up a faulted reader, doubling in between
+pub const RFID_INIT_BACKOFF_MIN_MS: u64 = 500;
+pub const RFID_INIT_BACKOFF_MAX_MS: u64 = 30_000;
+/// bus errors in a row after which the reader is considered wedged and reset
+pub const RFID_MAX_CONSECUTIVE_ERRORS: u8 = 5;
+/// how often the reader's version register is read back to make sure it is alive
+pub const RFID_HEALTH_CHECK_INTERVAL_MS: u64 = 5000;
+/// how long the field is switched off to reset the tags in it, and then given to power up
+pub const RFID_FIELD_RESET_MS: u64 = 5;
+/// markers that can rest on the reader at once, their colors are blended
+pub const MAX_PRESENT_MARKERS: usize = 4;
+/// how often every tag on the reader is enumerated to check they are still there
+pub const MARKER_PRESENCE_CHECK_INTERVAL_MS: u64 = 250;
+/// how long a tag may go unseen before it counts as removed
+pub const MARKER_REMOVAL_GRACE_PERIOD_MS: u64 = 1000;
+/// what happens to the bulb when a marker is lifted off the reader
+pub const MARKER_REMOVAL_POLICY: RemovalPolicy = RemovalPolicy::Latch;
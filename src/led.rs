@@ -1,15 +1,21 @@
+#[cfg(target_os = "none")]
 use crate::constants::{
-    LED_BUTTON_FLASH_TIME_MS, LED_FLASH_CYCLE_TIME_MS, LED_FLASH_OFF_TIME_MS, LED_FLASH_ON_TIME_MS,
-    LED_SLOW_BLINK_OFF_TIME_MS, LED_SLOW_BLINK_ON_TIME_MS,
+    LED_BUTTON_FLASH_TIME_MS, LED_ENROLLMENT_BLINK_TIME_MS, LED_FLASH_CYCLE_TIME_MS,
+    LED_FLASH_OFF_TIME_MS, LED_FLASH_ON_TIME_MS, LED_READER_FAULT_CYCLE_TIME_MS,
+    LED_SLOW_BLINK_OFF_TIME_MS, LED_SLOW_BLINK_ON_TIME_MS, LED_TAG_FAILURE_FLASH_TIME_MS,
+    LED_TAG_RESULT_TIME_MS, LED_TAG_WRITER_CYCLE_TIME_MS,
 };
 use crate::state::State;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::signal::Signal;
+#[cfg(target_os = "none")]
 use embassy_time::{Duration, Instant, Timer};
+#[cfg(target_os = "none")]
 use esp_hal::gpio::Output;
 
 pub type LedStateSignal = Signal<NoopRawMutex, State>;
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 pub async fn led_task(mut led: Output<'static>, led_state_signal: &'static LedStateSignal) {
     led.set_low();
@@ -24,9 +30,50 @@ pub async fn led_task(mut led: Output<'static>, led_state_signal: &'static LedSt
         }
 
         let now = Instant::now().as_millis() as u32;
+        let time_since_tag_result = now - current_state.last_tag_result_at;
 
-        if !current_state.is_connected {
-            // Slow blink while disconnected
+        if let Some(success) = current_state
+            .last_tag_result
+            .filter(|_| time_since_tag_result < LED_TAG_RESULT_TIME_MS)
+        {
+            // Solid on when a tag was enrolled or written, rapid flicker when it failed
+            if success
+                || time_since_tag_result % (2 * LED_TAG_FAILURE_FLASH_TIME_MS)
+                    < LED_TAG_FAILURE_FLASH_TIME_MS
+            {
+                led.set_high();
+            } else {
+                led.set_low();
+            }
+        } else if current_state.reader_fault {
+            // Repeating triple flash while the rfid reader is down
+            let cycle_time = now % LED_READER_FAULT_CYCLE_TIME_MS;
+            let flash_time = LED_FLASH_ON_TIME_MS + LED_FLASH_OFF_TIME_MS;
+            if cycle_time < 3 * flash_time && cycle_time % flash_time < LED_FLASH_ON_TIME_MS {
+                led.set_high();
+            } else {
+                led.set_low();
+            }
+        } else if current_state.enrollment.is_some() {
+            // Fast blink while waiting for a tag to enroll
+            if (now / LED_ENROLLMENT_BLINK_TIME_MS) % 2 == 0 {
+                led.set_high();
+            } else {
+                led.set_low();
+            }
+        } else if current_state.tag_writer.is_some() {
+            // Repeating double flash while writing tags
+            let cycle_time = now % LED_TAG_WRITER_CYCLE_TIME_MS;
+            if cycle_time < LED_FLASH_ON_TIME_MS
+                || (cycle_time > (LED_FLASH_ON_TIME_MS + LED_FLASH_OFF_TIME_MS)
+                    && cycle_time < LED_FLASH_CYCLE_TIME_MS)
+            {
+                led.set_high();
+            } else {
+                led.set_low();
+            }
+        } else if !current_state.is_connected() {
+            // Slow blink while no bulb is reachable
             let slow_blink_time =
                 (now - slow_blink_start) % (LED_SLOW_BLINK_ON_TIME_MS + LED_SLOW_BLINK_OFF_TIME_MS);
             if slow_blink_time < LED_SLOW_BLINK_ON_TIME_MS {
@@ -1,12 +1,38 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub mod bulb;
 pub mod button;
 pub mod constants;
 pub mod led;
+pub mod light_state;
 pub mod macros;
 pub mod marker_color;
+pub mod marker_registry;
+pub mod mfrc522;
+pub mod mqtt;
+pub mod ndef;
+#[cfg(target_os = "none")]
 pub mod networking;
+#[cfg(target_os = "none")]
 pub mod peripherals;
+pub mod pn532;
 pub mod rfid;
 pub mod state;
+pub mod tag_reader;
+pub mod tag_uid;
+
+/// discards defmt output in host tests, the firmware logs over rtt instead
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+}
@@ -0,0 +1,172 @@
+use crate::bulb::{BulbReport, LightCommand, LightCommands, LightEffect, MAX_LIGHT_COMMANDS};
+use crate::constants::DEFAULT_WHITE_TEMPERATURE;
+use crate::marker_color::MarkerColor;
+use defmt::Format;
+
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum LightMode {
+    /// hue 0-360 and saturation 0-100
+    Color { hue: u16, saturation: u8 },
+    /// white light at a color temperature in kelvin
+    White { temperature: u16 },
+}
+
+/// the whole state the bulbs should be in, which commands are worked out from rather
+/// than remembered
+#[derive(Debug, Format, PartialEq, Clone)]
+pub struct LightState {
+    pub power: bool,
+    pub mode: LightMode,
+    /// brightness 0-100
+    pub brightness: u8,
+    pub effect: LightEffect,
+}
+
+impl Default for LightState {
+    fn default() -> Self {
+        Self::white(100)
+    }
+}
+
+impl LightState {
+    /// the color at its own brightness
+    pub fn color(color: &MarkerColor) -> Self {
+        let (hue, saturation, brightness) = color.hsb();
+        Self {
+            power: true,
+            mode: LightMode::Color { hue, saturation },
+            brightness,
+            effect: LightEffect::None,
+        }
+    }
+
+    pub fn white(brightness: u8) -> Self {
+        Self {
+            power: true,
+            mode: LightMode::White {
+                temperature: DEFAULT_WHITE_TEMPERATURE,
+            },
+            brightness,
+            effect: LightEffect::None,
+        }
+    }
+
+    /// the commands that set the mode at the state's brightness
+    fn mode_commands(&self) -> [Option<LightCommand>; 2] {
+        match self.mode {
+            LightMode::Color { hue, saturation } => [
+                Some(LightCommand::Color(hue, saturation, self.brightness)),
+                None,
+            ],
+            LightMode::White { temperature } => [
+                Some(LightCommand::White(self.brightness)),
+                Some(LightCommand::ColorTemperature(temperature)),
+            ],
+        }
+    }
+
+    /// every command it takes to reach the state, whatever the bulb shows now
+    pub fn commands(&self) -> LightCommands {
+        if !self.power {
+            return [LightCommand::Power(false)].into_iter().collect();
+        }
+        let [mode, temperature] = self.mode_commands();
+        let commands: [Option<LightCommand>; MAX_LIGHT_COMMANDS] = [
+            Some(LightCommand::Power(true)),
+            mode,
+            temperature,
+            Some(LightCommand::Effect(self.effect)),
+        ];
+        commands.into_iter().flatten().collect()
+    }
+
+    /// the fewest commands that take a bulb in the `previous` state to this one
+    pub fn changes_from(&self, previous: &LightState) -> LightCommands {
+        if !self.power {
+            return previous
+                .power
+                .then_some(LightCommand::Power(false))
+                .into_iter()
+                .collect();
+        }
+        let [mode, temperature] = if self.mode != previous.mode {
+            self.mode_commands()
+        } else if self.brightness != previous.brightness {
+            [Some(LightCommand::Brightness(self.brightness)), None]
+        } else {
+            [None, None]
+        };
+        let commands: [Option<LightCommand>; MAX_LIGHT_COMMANDS] = [
+            (!previous.power).then_some(LightCommand::Power(true)),
+            mode,
+            temperature,
+            (self.effect != previous.effect).then_some(LightCommand::Effect(self.effect)),
+        ];
+        commands.into_iter().flatten().collect()
+    }
+
+    /// the commands that correct what `report` shows differing from the state
+    pub fn drift(&self, report: &BulbReport) -> LightCommands {
+        self.commands()
+            .into_iter()
+            .filter(|command| !report.shows(command))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a red light, switched off
+    fn off() -> LightState {
+        LightState {
+            power: false,
+            ..LightState::color(&MarkerColor::Custom(0, 100, 100))
+        }
+    }
+
+    #[test]
+    fn white_from_off_keeps_every_command() {
+        let white = LightState {
+            effect: LightEffect::ColorCycle,
+            ..LightState::white(60)
+        };
+        let expected = [
+            LightCommand::Power(true),
+            LightCommand::White(60),
+            LightCommand::ColorTemperature(DEFAULT_WHITE_TEMPERATURE),
+            LightCommand::Effect(LightEffect::ColorCycle),
+        ];
+        assert_eq!(white.commands(), expected);
+        assert_eq!(white.changes_from(&off()), expected);
+    }
+
+    #[test]
+    fn changes_only_what_differs() {
+        let red = LightState::color(&MarkerColor::Custom(0, 100, 80));
+        let dimmer_red = LightState {
+            brightness: 30,
+            ..red.clone()
+        };
+        assert_eq!(
+            dimmer_red.changes_from(&red),
+            [LightCommand::Brightness(30)]
+        );
+        assert!(red.changes_from(&red).is_empty());
+        assert_eq!(off().changes_from(&red), [LightCommand::Power(false)]);
+        assert!(off().changes_from(&off()).is_empty());
+        assert_eq!(off().commands(), [LightCommand::Power(false)]);
+    }
+
+    #[test]
+    fn drift_corrects_what_the_bulb_shows_wrong() {
+        let red = LightState::color(&MarkerColor::Custom(0, 100, 80));
+        let report = BulbReport {
+            power: Some(true),
+            brightness: Some(80),
+            color: Some((120, 100, 80)),
+        };
+        assert_eq!(red.drift(&report), [LightCommand::Color(0, 100, 80)]);
+    }
+}
@@ -1,5 +1,5 @@
 use defmt::Format;
-use mfrc522::GenericUid;
+use libm::{cbrt, pow, round};
 
 #[derive(Debug, Format, PartialEq, Clone)]
 pub enum MarkerColor {
@@ -15,9 +15,27 @@ pub enum MarkerColor {
     Yellow,
     Orange,
     Violet,
+    /// an arbitrary hue, saturation, and brightness not in the crayola palette
+    Custom(u16, u8, u8),
 }
 
 impl MarkerColor {
+    /// the crayola colors shipped with the original marker set, in slot order
+    pub const PALETTE: [MarkerColor; 12] = [
+        MarkerColor::Red,
+        MarkerColor::Brown,
+        MarkerColor::BlueLagoon,
+        MarkerColor::Green,
+        MarkerColor::Black,
+        MarkerColor::SandyTan,
+        MarkerColor::Gray,
+        MarkerColor::Pink,
+        MarkerColor::Blue,
+        MarkerColor::Yellow,
+        MarkerColor::Orange,
+        MarkerColor::Violet,
+    ];
+
     /// returns the hue, saturation, and brightness values for the marker color
     ///
     /// h - hue. 0-360
@@ -37,56 +55,227 @@ impl MarkerColor {
             MarkerColor::Yellow => (60, 100, 100),
             MarkerColor::Orange => (30, 100, 100),
             MarkerColor::Violet => (0, 0, 70),
+            MarkerColor::Custom(h, s, b) => (*h, *s, *b),
+        }
+    }
+
+    /// converts 8 bit rgb components to a custom hue, saturation, and brightness color
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let hue = if delta == 0 {
+            0
+        } else if max == r {
+            (60 * (g - b) / delta + 360) % 360
+        } else if max == g {
+            60 * (b - r) / delta + 120
+        } else {
+            60 * (r - g) / delta + 240
+        };
+        let saturation = if max == 0 { 0 } else { delta * 100 / max };
+        let brightness = (max * 100 + 127) / 255;
+        MarkerColor::Custom(hue as u16, saturation as u8, brightness as u8)
+    }
+
+    /// converts the hue, saturation, and brightness to 8 bit rgb components
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        let (h, s, b) = self.hsb();
+        let (h, s, b) = (h as u32 % 360, s as u32, b as u32);
+        let max = (b * 255 + 50) / 100;
+        let min = max * (100 - s) / 100;
+        let rising = min + (max - min) * (h % 60) / 60;
+        let falling = max - (max - min) * (h % 60) / 60;
+        let (r, g, b) = match h / 60 {
+            0 => (max, rising, min),
+            1 => (falling, max, min),
+            2 => (min, max, rising),
+            3 => (min, falling, max),
+            4 => (rising, min, max),
+            _ => (max, min, falling),
+        };
+        (r as u8, g as u8, b as u8)
+    }
+
+    /// blends colors by averaging them in oklab, where distances match how different
+    /// colors look, so red and blue make purple rather than the muddy mix of their rgb
+    pub fn blend(colors: &[MarkerColor]) -> Option<MarkerColor> {
+        match colors {
+            [] => None,
+            [color] => Some(color.clone()),
+            _ => {
+                let mut sum = [0.0f64; 3];
+                for color in colors {
+                    let (r, g, b) = color.rgb();
+                    let lab = linear_to_oklab([r, g, b].map(srgb_to_linear));
+                    for (total, value) in sum.iter_mut().zip(lab) {
+                        *total += value;
+                    }
+                }
+                let average = sum.map(|total| total / colors.len() as f64);
+                let [r, g, b] = oklab_to_linear(average).map(linear_to_srgb);
+                Some(MarkerColor::from_rgb(r, g, b))
+            }
         }
     }
 
-    /// maps the marker color to its rfid uid value
-    pub fn uid(&self) -> [u8; 7] {
+    /// human readable name of the marker color
+    pub fn name(&self) -> &'static str {
         match self {
-            MarkerColor::Red => [4, 61, 60, 18, 54, 30, 145],
-            MarkerColor::Brown => [4, 61, 59, 18, 54, 30, 145],
-            MarkerColor::BlueLagoon => [4, 61, 58, 18, 54, 30, 145],
-            MarkerColor::Green => [4, 61, 57, 18, 54, 30, 145],
-            MarkerColor::Black => [4, 61, 56, 18, 54, 30, 145],
-            MarkerColor::SandyTan => [4, 61, 55, 18, 54, 30, 145],
-            MarkerColor::Gray => [4, 61, 54, 18, 54, 30, 145],
-            MarkerColor::Pink => [4, 61, 53, 18, 54, 30, 145],
-            MarkerColor::Blue => [4, 61, 52, 18, 54, 30, 145],
-            MarkerColor::Yellow => [4, 61, 51, 18, 54, 30, 145],
-            MarkerColor::Orange => [4, 61, 50, 18, 54, 30, 145],
-            MarkerColor::Violet => [4, 61, 49, 18, 54, 30, 145],
+            MarkerColor::Red => "red",
+            MarkerColor::Brown => "brown",
+            MarkerColor::BlueLagoon => "blue lagoon",
+            MarkerColor::Green => "green",
+            MarkerColor::Black => "black",
+            MarkerColor::SandyTan => "sandy tan",
+            MarkerColor::Gray => "gray",
+            MarkerColor::Pink => "pink",
+            MarkerColor::Blue => "blue",
+            MarkerColor::Yellow => "yellow",
+            MarkerColor::Orange => "orange",
+            MarkerColor::Violet => "violet",
+            MarkerColor::Custom(..) => "custom",
         }
     }
 
-    /// maps a rfid uid value to a marker color
-    pub fn from_uid(generic_uid: &GenericUid<7>) -> Option<Self> {
-        let uid = generic_uid.as_bytes();
-        if uid == MarkerColor::Red.uid() {
-            Some(MarkerColor::Red)
-        } else if uid == MarkerColor::Brown.uid() {
-            Some(MarkerColor::Brown)
-        } else if uid == MarkerColor::BlueLagoon.uid() {
-            Some(MarkerColor::BlueLagoon)
-        } else if uid == MarkerColor::Green.uid() {
-            Some(MarkerColor::Green)
-        } else if uid == MarkerColor::Black.uid() {
-            Some(MarkerColor::Black)
-        } else if uid == MarkerColor::SandyTan.uid() {
-            Some(MarkerColor::SandyTan)
-        } else if uid == MarkerColor::Gray.uid() {
-            Some(MarkerColor::Gray)
-        } else if uid == MarkerColor::Pink.uid() {
-            Some(MarkerColor::Pink)
-        } else if uid == MarkerColor::Blue.uid() {
-            Some(MarkerColor::Blue)
-        } else if uid == MarkerColor::Yellow.uid() {
-            Some(MarkerColor::Yellow)
-        } else if uid == MarkerColor::Orange.uid() {
-            Some(MarkerColor::Orange)
-        } else if uid == MarkerColor::Violet.uid() {
-            Some(MarkerColor::Violet)
-        } else {
-            None
+    /// index of the color in the palette, or `None` for custom colors
+    pub fn palette_index(&self) -> Option<u8> {
+        MarkerColor::PALETTE
+            .iter()
+            .position(|color| color == self)
+            .map(|index| index as u8)
+    }
+}
+
+fn srgb_to_linear(component: u8) -> f64 {
+    let c = component as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        pow((c + 0.055) / 1.055, 2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * pow(c, 1.0 / 2.4) - 0.055
+    };
+    round(c * 255.0) as u8
+}
+
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = cbrt(0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b);
+    let m = cbrt(0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b);
+    let s = cbrt(0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b);
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([lightness, a, b]: [f64; 3]) -> [f64; 3] {
+    let l = lightness + 0.3963377774 * a + 0.2158037573 * b;
+    let m = lightness - 0.1055613458 * a - 0.0638541728 * b;
+    let s = lightness - 0.0894841775 * a - 1.2914855480 * b;
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// each component within `tolerance` of the expected value, to allow for the
+    /// rounding of the integer hue, saturation, and brightness
+    fn assert_rgb_near(actual: (u8, u8, u8), expected: (u8, u8, u8), tolerance: u8) {
+        let (a, e) = (
+            [actual.0, actual.1, actual.2],
+            [expected.0, expected.1, expected.2],
+        );
+        assert!(
+            a.iter().zip(e).all(|(a, e)| a.abs_diff(e) <= tolerance),
+            "{:?} is not near {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn palette_colors_convert_to_rgb() {
+        assert_eq!(MarkerColor::Red.rgb(), (255, 0, 0));
+        assert_eq!(MarkerColor::Green.rgb(), (0, 255, 0));
+        assert_eq!(MarkerColor::Blue.rgb(), (0, 0, 255));
+        assert_eq!(MarkerColor::Yellow.rgb(), (255, 255, 0));
+        assert_eq!(MarkerColor::BlueLagoon.rgb(), (0, 255, 255));
+        assert_eq!(MarkerColor::Gray.rgb(), (128, 128, 128));
+    }
+
+    #[test]
+    fn converts_rgb_to_hue_saturation_and_brightness() {
+        assert_eq!(
+            MarkerColor::from_rgb(255, 0, 0),
+            MarkerColor::Custom(0, 100, 100)
+        );
+        assert_eq!(
+            MarkerColor::from_rgb(0, 0, 255),
+            MarkerColor::Custom(240, 100, 100)
+        );
+        assert_eq!(
+            MarkerColor::from_rgb(255, 128, 0),
+            MarkerColor::Custom(30, 100, 100)
+        );
+        assert_eq!(MarkerColor::from_rgb(0, 0, 0), MarkerColor::Custom(0, 0, 0));
+        assert_eq!(
+            MarkerColor::from_rgb(255, 255, 255),
+            MarkerColor::Custom(0, 0, 100)
+        );
+    }
+
+    #[test]
+    fn rgb_round_trips() {
+        for rgb in [
+            (255, 0, 0),
+            (0, 255, 0),
+            (0, 0, 255),
+            (140, 83, 162),
+            (200, 50, 80),
+            (128, 128, 128),
+        ] {
+            let color = MarkerColor::from_rgb(rgb.0, rgb.1, rgb.2);
+            assert_rgb_near(color.rgb(), rgb, 3);
         }
     }
+
+    #[test]
+    fn red_and_blue_blend_to_purple() {
+        let purple = MarkerColor::blend(&[MarkerColor::Red, MarkerColor::Blue]).unwrap();
+        assert_rgb_near(purple.rgb(), (140, 83, 162), 3);
+    }
+
+    #[test]
+    fn blends_are_symmetric_and_idempotent() {
+        let yellow_blue = MarkerColor::blend(&[MarkerColor::Yellow, MarkerColor::Blue]);
+        let blue_yellow = MarkerColor::blend(&[MarkerColor::Blue, MarkerColor::Yellow]);
+        assert_eq!(yellow_blue, blue_yellow);
+        let red = MarkerColor::blend(&[MarkerColor::Red, MarkerColor::Red]).unwrap();
+        assert_rgb_near(red.rgb(), (255, 0, 0), 1);
+    }
+
+    #[test]
+    fn blending_nothing_or_one_color() {
+        assert_eq!(MarkerColor::blend(&[]), None);
+        assert_eq!(
+            MarkerColor::blend(&[MarkerColor::Pink]),
+            Some(MarkerColor::Pink)
+        );
+    }
 }
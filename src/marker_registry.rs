@@ -0,0 +1,413 @@
+use crate::constants::{
+    MARKER_NAME_LENGTH, MARKER_REGISTRY_CAPACITY, MARKER_REGISTRY_FLASH_OFFSET,
+};
+use crate::marker_color::MarkerColor;
+use crate::tag_uid::TagUid;
+use defmt::{info, warn, Format};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage::{ReadStorage, Storage};
+use heapless::{String, Vec};
+
+const MAGIC: [u8; 4] = *b"MMRK";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 12;
+/// uid length, uid padded to the longest size, then the marker's fields
+const RECORD_SIZE: usize = 1 + TagUid::MAX_LEN + FIELDS_SIZE;
+/// color kind, hue, saturation, brightness, options, name length, name
+const FIELDS_SIZE: usize = 7 + MARKER_NAME_LENGTH;
+const BLOB_SIZE: usize = HEADER_SIZE + RECORD_SIZE * MARKER_REGISTRY_CAPACITY;
+const CUSTOM_COLOR: u8 = 0xff;
+
+/// uids of the original crayola marker set
+const FACTORY_MARKERS: [(MarkerColor, TagUid); 12] = [
+    (
+        MarkerColor::Red,
+        TagUid::double([4, 61, 60, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Brown,
+        TagUid::double([4, 61, 59, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::BlueLagoon,
+        TagUid::double([4, 61, 58, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Green,
+        TagUid::double([4, 61, 57, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Black,
+        TagUid::double([4, 61, 56, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::SandyTan,
+        TagUid::double([4, 61, 55, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Gray,
+        TagUid::double([4, 61, 54, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Pink,
+        TagUid::double([4, 61, 53, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Blue,
+        TagUid::double([4, 61, 52, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Yellow,
+        TagUid::double([4, 61, 51, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Orange,
+        TagUid::double([4, 61, 50, 18, 54, 30, 145]),
+    ),
+    (
+        MarkerColor::Violet,
+        TagUid::double([4, 61, 49, 18, 54, 30, 145]),
+    ),
+];
+
+#[derive(Debug, Format, PartialEq, Clone, Copy, Default)]
+pub struct MarkerOptions(u8);
+
+impl MarkerOptions {
+    /// ignore taps of the marker without forgetting its uid
+    pub const DISABLED: MarkerOptions = MarkerOptions(1 << 0);
+
+    pub const fn empty() -> Self {
+        MarkerOptions(0)
+    }
+
+    pub fn contains(&self, other: MarkerOptions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: MarkerOptions) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: MarkerOptions) {
+        self.0 &= !other.0;
+    }
+}
+
+#[derive(Debug, Format, PartialEq, Clone)]
+pub struct Marker {
+    pub uid: TagUid,
+    pub name: String<MARKER_NAME_LENGTH>,
+    pub color: MarkerColor,
+    pub options: MarkerOptions,
+}
+
+impl Marker {
+    /// creates a marker, truncating the name to fit in flash
+    pub fn new(uid: TagUid, name: &str, color: MarkerColor) -> Self {
+        let mut truncated = String::new();
+        for c in name.chars() {
+            if truncated.push(c).is_err() {
+                break;
+            }
+        }
+        Self {
+            uid,
+            name: truncated,
+            color,
+            options: MarkerOptions::empty(),
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum RegistryError {
+    Full,
+    Flash,
+}
+
+pub type MarkerRegistryMutex = Mutex<NoopRawMutex, MarkerRegistry>;
+
+/// the storage holding the `markers` partition, the esp32c6 flash on the device
+#[cfg(target_os = "none")]
+pub type Flash = esp_storage::FlashStorage;
+/// the storage holding the `markers` partition, kept in ram on the host
+#[cfg(not(target_os = "none"))]
+pub type Flash = RamFlash;
+
+/// uid to marker mapping persisted in the `markers` flash partition
+pub struct MarkerRegistry<F = Flash> {
+    markers: Vec<Marker, MARKER_REGISTRY_CAPACITY>,
+    flash: F,
+}
+
+impl<F: Storage> MarkerRegistry<F> {
+    /// loads the registry from flash, falling back to the factory markers when the
+    /// partition is blank or corrupt
+    pub fn load(mut flash: F) -> Self {
+        let mut blob = [0u8; BLOB_SIZE];
+        let markers = match flash.read(MARKER_REGISTRY_FLASH_OFFSET, &mut blob) {
+            Ok(()) => decode(&blob),
+            Err(_) => {
+                warn!("failed to read marker registry from flash");
+                None
+            }
+        };
+        let markers = markers.unwrap_or_else(|| {
+            info!("no stored marker registry, using factory markers");
+            factory_markers()
+        });
+        info!("marker registry loaded with {} markers", markers.len());
+        Self { markers, flash }
+    }
+
+    pub fn markers(&self) -> &[Marker] {
+        &self.markers
+    }
+
+    /// finds the marker registered for a tag uid
+    pub fn lookup(&self, uid: &TagUid) -> Option<&Marker> {
+        self.markers.iter().find(|marker| marker.uid == *uid)
+    }
+
+    /// adds a marker or replaces the one with the same uid, then persists the registry
+    pub fn upsert(&mut self, marker: Marker) -> Result<(), RegistryError> {
+        if let Some(existing) = self.markers.iter_mut().find(|m| m.uid == marker.uid) {
+            *existing = marker;
+        } else {
+            self.markers.push(marker).map_err(|_| RegistryError::Full)?;
+        }
+        self.save()
+    }
+
+    /// forgets the marker with the given uid, then persists the registry
+    pub fn remove(&mut self, uid: &TagUid) -> Result<Option<Marker>, RegistryError> {
+        let Some(index) = self.markers.iter().position(|m| m.uid == *uid) else {
+            return Ok(None);
+        };
+        let removed = self.markers.swap_remove(index);
+        self.save()?;
+        Ok(Some(removed))
+    }
+
+    /// restores the original crayola markers, then persists the registry
+    pub fn reset_to_factory(&mut self) -> Result<(), RegistryError> {
+        self.markers = factory_markers();
+        self.save()
+    }
+
+    fn save(&mut self) -> Result<(), RegistryError> {
+        let mut blob = [0xffu8; BLOB_SIZE];
+        let len = encode(&self.markers, &mut blob);
+        self.flash
+            .write(MARKER_REGISTRY_FLASH_OFFSET, &blob[..len])
+            .map_err(|_| RegistryError::Flash)?;
+        info!("marker registry saved with {} markers", self.markers.len());
+        Ok(())
+    }
+}
+
+fn factory_markers() -> Vec<Marker, MARKER_REGISTRY_CAPACITY> {
+    FACTORY_MARKERS
+        .iter()
+        .map(|(color, uid)| Marker::new(*uid, color.name(), color.clone()))
+        .collect()
+}
+
+/// serializes the markers into `blob`, returning the number of bytes used
+fn encode(markers: &[Marker], blob: &mut [u8; BLOB_SIZE]) -> usize {
+    let records_len = markers.len() * RECORD_SIZE;
+    for (marker, record) in markers
+        .iter()
+        .zip(blob[HEADER_SIZE..].chunks_exact_mut(RECORD_SIZE))
+    {
+        let uid = marker.uid.as_bytes();
+        record[0] = uid.len() as u8;
+        record[1..1 + uid.len()].copy_from_slice(uid);
+        encode_fields(marker, &mut record[1 + TagUid::MAX_LEN..]);
+    }
+    let crc = crc32(&blob[HEADER_SIZE..HEADER_SIZE + records_len]);
+    blob[0..4].copy_from_slice(&MAGIC);
+    blob[4] = FORMAT_VERSION;
+    blob[5] = markers.len() as u8;
+    blob[6..8].copy_from_slice(&[0, 0]);
+    blob[8..12].copy_from_slice(&crc.to_le_bytes());
+    HEADER_SIZE + records_len
+}
+
+fn encode_fields(marker: &Marker, fields: &mut [u8]) {
+    let (h, s, b) = marker.color.hsb();
+    fields[0] = marker.color.palette_index().unwrap_or(CUSTOM_COLOR);
+    fields[1..3].copy_from_slice(&h.to_le_bytes());
+    fields[3] = s;
+    fields[4] = b;
+    fields[5] = marker.options.0;
+    fields[6] = marker.name.len() as u8;
+    fields[7..7 + marker.name.len()].copy_from_slice(marker.name.as_bytes());
+}
+
+/// deserializes a registry blob, returning `None` if it is blank, corrupt, or an
+/// unknown format
+fn decode(blob: &[u8; BLOB_SIZE]) -> Option<Vec<Marker, MARKER_REGISTRY_CAPACITY>> {
+    if blob[0..4] != MAGIC {
+        return None;
+    }
+    if blob[4] != FORMAT_VERSION {
+        return None;
+    }
+    let count = blob[5] as usize;
+    if count > MARKER_REGISTRY_CAPACITY {
+        return None;
+    }
+    let records = &blob[HEADER_SIZE..HEADER_SIZE + count * RECORD_SIZE];
+    let crc = u32::from_le_bytes(blob[8..12].try_into().ok()?);
+    if crc32(records) != crc {
+        warn!("marker registry checksum mismatch");
+        return None;
+    }
+
+    let mut markers = Vec::new();
+    for record in records.chunks_exact(RECORD_SIZE) {
+        let uid_len = (record[0] as usize).min(TagUid::MAX_LEN);
+        let uid = TagUid::new(&record[1..1 + uid_len])?;
+        markers
+            .push(decode_fields(uid, &record[1 + TagUid::MAX_LEN..])?)
+            .ok()?;
+    }
+    Some(markers)
+}
+
+fn decode_fields(uid: TagUid, fields: &[u8]) -> Option<Marker> {
+    let h = u16::from_le_bytes([fields[1], fields[2]]);
+    let color = match fields[0] {
+        CUSTOM_COLOR => MarkerColor::Custom(h, fields[3], fields[4]),
+        index => MarkerColor::PALETTE.get(index as usize)?.clone(),
+    };
+    let name_len = (fields[6] as usize).min(MARKER_NAME_LENGTH);
+    let name = core::str::from_utf8(&fields[7..7 + name_len]).ok()?;
+    let mut marker = Marker::new(uid, name, color);
+    marker.options = MarkerOptions(fields[5]);
+    Some(marker)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// the `markers` partition in memory, blank like erased flash
+#[cfg(not(target_os = "none"))]
+pub struct RamFlash([u8; BLOB_SIZE]);
+
+#[cfg(not(target_os = "none"))]
+impl RamFlash {
+    pub fn blank() -> Self {
+        Self([0xff; BLOB_SIZE])
+    }
+
+    fn range(&self, offset: u32, len: usize) -> core::ops::Range<usize> {
+        let start = (offset - MARKER_REGISTRY_FLASH_OFFSET) as usize;
+        start..start + len
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+impl ReadStorage for RamFlash {
+    type Error = ();
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), ()> {
+        let range = self.range(offset, bytes.len());
+        bytes.copy_from_slice(&self.0[range]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+impl Storage for RamFlash {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), ()> {
+        let range = self.range(offset, bytes.len());
+        self.0[range].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers_of_every_size() -> [Marker; 3] {
+        [
+            Marker::new(
+                TagUid::single([0xde, 0xad, 0xbe, 0xef]),
+                "classic",
+                MarkerColor::Red,
+            ),
+            Marker::new(
+                TagUid::double([4, 1, 2, 3, 4, 5, 6]),
+                "ntag",
+                MarkerColor::Custom(200, 50, 80),
+            ),
+            Marker::new(
+                TagUid::triple([8, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                "triple",
+                MarkerColor::Blue,
+            ),
+        ]
+    }
+
+    #[test]
+    fn blank_flash_loads_factory_markers() {
+        let registry = MarkerRegistry::load(RamFlash::blank());
+        assert_eq!(registry.markers().len(), FACTORY_MARKERS.len());
+        let (color, uid) = &FACTORY_MARKERS[0];
+        assert_eq!(registry.lookup(uid).map(|m| &m.color), Some(color));
+    }
+
+    #[test]
+    fn looks_up_every_uid_size() {
+        let mut registry = MarkerRegistry::load(RamFlash::blank());
+        for marker in markers_of_every_size() {
+            registry.upsert(marker).unwrap();
+        }
+        for marker in markers_of_every_size() {
+            assert_eq!(registry.lookup(&marker.uid), Some(&marker));
+        }
+        // a uid that only shares a prefix with a registered one does not match
+        let prefix = TagUid::single([4, 1, 2, 3]);
+        assert_eq!(registry.lookup(&prefix), None);
+    }
+
+    #[test]
+    fn every_uid_size_survives_a_reload() {
+        let mut registry = MarkerRegistry::load(RamFlash::blank());
+        for marker in markers_of_every_size() {
+            registry.upsert(marker).unwrap();
+        }
+        let reloaded = MarkerRegistry::load(registry.flash);
+        for marker in markers_of_every_size() {
+            assert_eq!(reloaded.lookup(&marker.uid), Some(&marker));
+        }
+    }
+
+    #[test]
+    fn corrupt_flash_loads_factory_markers() {
+        let mut registry = MarkerRegistry::load(RamFlash::blank());
+        registry.upsert(markers_of_every_size()[0].clone()).unwrap();
+        let mut flash = registry.flash;
+        flash.0[HEADER_SIZE] ^= 0xff;
+        let reloaded = MarkerRegistry::load(flash);
+        assert_eq!(reloaded.markers().len(), FACTORY_MARKERS.len());
+    }
+}
@@ -0,0 +1,510 @@
+use crate::constants::{RFID_FIELD_RESET_MS, RFID_TRANSCEIVE_TIMEOUT_MS};
+use crate::tag_reader::{Error, TagReader};
+use crate::tag_uid::TagUid;
+use defmt::Format;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+use embedded_hal_async::spi::SpiDevice;
+
+const COMMAND_REG: u8 = 0x01;
+const COM_I_EN_REG: u8 = 0x02;
+const DIV_I_EN_REG: u8 = 0x03;
+const COM_IRQ_REG: u8 = 0x04;
+const ERROR_REG: u8 = 0x06;
+const COLL_REG: u8 = 0x0e;
+const FIFO_DATA_REG: u8 = 0x09;
+const FIFO_LEVEL_REG: u8 = 0x0a;
+const CONTROL_REG: u8 = 0x0c;
+const BIT_FRAMING_REG: u8 = 0x0d;
+const MODE_REG: u8 = 0x11;
+const TX_CONTROL_REG: u8 = 0x14;
+const TX_ASK_REG: u8 = 0x15;
+const RF_CFG_REG: u8 = 0x26;
+const T_MODE_REG: u8 = 0x2a;
+const T_PRESCALER_REG: u8 = 0x2b;
+const T_RELOAD_REG_H: u8 = 0x2c;
+const T_RELOAD_REG_L: u8 = 0x2d;
+const VERSION_REG: u8 = 0x37;
+
+const CMD_IDLE: u8 = 0x00;
+const CMD_TRANSCEIVE: u8 = 0x0c;
+const CMD_SOFT_RESET: u8 = 0x0f;
+const POWER_DOWN: u8 = 0x10;
+
+const IRQ_INVERTED: u8 = 0x80;
+const IRQ_PUSH_PULL: u8 = 0x80;
+const RX_IRQ: u8 = 0x20;
+const IDLE_IRQ: u8 = 0x10;
+const TIMER_IRQ: u8 = 0x01;
+const START_SEND: u8 = 0x80;
+
+const ERR_PROTOCOL: u8 = 0x01;
+const ERR_PARITY: u8 = 0x02;
+const ERR_COLLISION: u8 = 0x08;
+const ERR_BUFFER_OVERFLOW: u8 = 0x10;
+const VALUES_AFTER_COLL: u8 = 0x80;
+const COLL_POS_NOT_VALID: u8 = 0x20;
+const TX_ANTENNA_ON: u8 = 0x03;
+
+const FIFO_SIZE: usize = 64;
+
+const PICC_REQA: u8 = 0x26;
+const PICC_WUPA: u8 = 0x52;
+const PICC_SEL_CL1: u8 = 0x93;
+const PICC_SEL_CL2: u8 = 0x95;
+const PICC_SEL_CL3: u8 = 0x97;
+const PICC_HLTA: u8 = 0x50;
+const PICC_READ: u8 = 0x30;
+const PICC_WRITE: u8 = 0xa2;
+const PICC_ACK: u8 = 0x0a;
+const CASCADE_BIT: u8 = 0x04;
+
+#[derive(Debug, Format, Clone, Copy)]
+pub enum RxGain {
+    DB18 = 0x02,
+    DB23 = 0x03,
+    DB33 = 0x04,
+    DB38 = 0x05,
+    DB43 = 0x06,
+    DB48 = 0x07,
+}
+
+/// answer to a REQA or WUPA
+#[derive(Debug, Format, Clone, Copy)]
+pub struct AtqA(pub [u8; 2]);
+
+/// register access over the bus the reader is attached to
+#[allow(async_fn_in_trait)]
+pub trait Interface {
+    /// reads `buffer.len()` bytes from a register, repeatedly for the fifo
+    async fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Error>;
+    async fn write(&mut self, register: u8, data: &[u8]) -> Result<(), Error>;
+}
+
+pub struct I2cInterface<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> I2cInterface<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+}
+
+impl<I2C: I2c> Interface for I2cInterface<I2C> {
+    async fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.i2c
+            .write_read(self.address, &[register], buffer)
+            .await
+            .map_err(|_| Error::Comm)
+    }
+
+    async fn write(&mut self, register: u8, data: &[u8]) -> Result<(), Error> {
+        let mut frame = [0u8; 1 + FIFO_SIZE];
+        frame[0] = register;
+        frame[1..=data.len()].copy_from_slice(data);
+        self.i2c
+            .write(self.address, &frame[..=data.len()])
+            .await
+            .map_err(|_| Error::Comm)
+    }
+}
+
+/// spi register addresses carry the register in bits 1-6 and the read flag in bit 7
+pub struct SpiInterface<SPI> {
+    spi: SPI,
+}
+
+impl<SPI: SpiDevice> SpiInterface<SPI> {
+    pub fn new(spi: SPI) -> Self {
+        Self { spi }
+    }
+}
+
+impl<SPI: SpiDevice> Interface for SpiInterface<SPI> {
+    async fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        // the address is clocked out again for every byte read, and the answer to
+        // each one arrives while the next is sent
+        let mut frame = [0x80 | (register << 1); 1 + FIFO_SIZE];
+        frame[buffer.len()] = 0;
+        self.spi
+            .transfer_in_place(&mut frame[..=buffer.len()])
+            .await
+            .map_err(|_| Error::Comm)?;
+        buffer.copy_from_slice(&frame[1..=buffer.len()]);
+        Ok(())
+    }
+
+    async fn write(&mut self, register: u8, data: &[u8]) -> Result<(), Error> {
+        let mut frame = [0u8; 1 + FIFO_SIZE];
+        frame[0] = register << 1;
+        frame[1..=data.len()].copy_from_slice(data);
+        self.spi
+            .write(&frame[..=data.len()])
+            .await
+            .map_err(|_| Error::Comm)
+    }
+}
+
+/// async driver for the mfrc522 (and the compatible ws1850s) rfid reader
+///
+/// every transfer awaits the bus, and the end of a transceive is awaited on the
+/// reader's irq line when one is wired up, or by polling its interrupt register
+/// between timer ticks otherwise, so the reader never blocks the executor.
+///
+/// the irq line only ends those waits early. the mfrc522 cannot notice a card
+/// entering the field by itself, so finding one still takes a REQA every poll, and
+/// the irq saves no power between polls.
+pub struct Mfrc522<COMM, IRQ> {
+    comm: COMM,
+    irq: Option<IRQ>,
+}
+
+impl<COMM: Interface, IRQ: Wait> Mfrc522<COMM, IRQ> {
+    /// creates the driver, `irq` being the input wired to the reader's irq pin if any
+    pub fn new(comm: COMM, irq: Option<IRQ>) -> Self {
+        Self { comm, irq }
+    }
+
+    /// resets the reader and configures its timer, modulation, and interrupt sources
+    pub async fn configure(&mut self) -> Result<(), Error> {
+        self.soft_reset().await?;
+        // timer fires 25ms after a transmission ends, bounding every transceive
+        self.write(T_MODE_REG, 0x80).await?;
+        self.write(T_PRESCALER_REG, 0xa9).await?;
+        self.write(T_RELOAD_REG_H, 0x03).await?;
+        self.write(T_RELOAD_REG_L, 0xe8).await?;
+        // 100% ask modulation and the iso 14443-3 crc preset
+        self.write(TX_ASK_REG, 0x40).await?;
+        self.write(MODE_REG, 0x3d).await?;
+        // drive irq low, push-pull, on receive, idle, or timer interrupts
+        self.write(COM_I_EN_REG, IRQ_INVERTED | RX_IRQ | IDLE_IRQ | TIMER_IRQ)
+            .await?;
+        self.write(DIV_I_EN_REG, IRQ_PUSH_PULL).await?;
+        self.set_antenna(true).await
+    }
+
+    /// switches the rf field on or off, a tag losing the field forgets its halt state
+    pub async fn set_antenna(&mut self, on: bool) -> Result<(), Error> {
+        let tx_control = self.read(TX_CONTROL_REG).await?;
+        let value = if on {
+            tx_control | TX_ANTENNA_ON
+        } else {
+            tx_control & !TX_ANTENNA_ON
+        };
+        if value != tx_control {
+            self.write(TX_CONTROL_REG, value).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn soft_reset(&mut self) -> Result<(), Error> {
+        self.write(COMMAND_REG, CMD_SOFT_RESET).await?;
+        for _ in 0..3 {
+            Timer::after(Duration::from_millis(50)).await;
+            if self.read(COMMAND_REG).await? & POWER_DOWN == 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    pub async fn set_antenna_gain(&mut self, gain: RxGain) -> Result<(), Error> {
+        self.write(RF_CFG_REG, (gain as u8) << 4).await
+    }
+
+    /// sends a REQA, which only idle tags answer
+    pub async fn new_card_present(&mut self) -> Result<AtqA, Error> {
+        self.request(PICC_REQA).await
+    }
+
+    /// sends a WUPA, which idle and halted tags answer
+    pub async fn wupa(&mut self) -> Result<AtqA, Error> {
+        self.request(PICC_WUPA).await
+    }
+
+    /// runs anticollision and select through every cascade level, picking a single
+    /// tag when several answered the last request
+    pub async fn select(&mut self) -> Result<TagUid, Error> {
+        let mut uid = [0u8; TagUid::MAX_LEN];
+        let mut len = 0;
+        for cascade_level in [PICC_SEL_CL1, PICC_SEL_CL2, PICC_SEL_CL3] {
+            let uid_part = self.anticollision(cascade_level).await?;
+            if uid_part[..4].iter().fold(0, |bcc, byte| bcc ^ byte) != uid_part[4] {
+                return Err(Error::Bcc);
+            }
+
+            let mut frame = [0u8; 9];
+            frame[0] = cascade_level;
+            frame[1] = 0x70;
+            frame[2..7].copy_from_slice(&uid_part);
+            frame[7..].copy_from_slice(&crc_a(&frame[..7]));
+            let mut sak = [0u8; 3];
+            let (received, _) = self.transceive(&frame, 0, 0, &mut sak).await?;
+            if received != sak.len() {
+                return Err(Error::IncompleteFrame);
+            }
+            if !check_crc(&sak) {
+                return Err(Error::Crc);
+            }
+
+            if sak[0] & CASCADE_BIT != 0 {
+                // the first byte is the cascade tag, the uid continues at the next level
+                uid[len..len + 3].copy_from_slice(&uid_part[1..4]);
+                len += 3;
+            } else {
+                uid[len..len + 4].copy_from_slice(&uid_part[..4]);
+                len += 4;
+                return TagUid::new(&uid[..len]).ok_or(Error::Protocol);
+            }
+        }
+        Err(Error::Protocol)
+    }
+
+    /// resolves the uid part of one cascade level bit by bit
+    ///
+    /// at each collision the bits received so far are kept, the colliding bit is set,
+    /// and the request is repeated with those known bits, so only the tags that have
+    /// that bit set keep answering, until a single one is left.
+    async fn anticollision(&mut self, cascade_level: u8) -> Result<[u8; 5], Error> {
+        // bits received after a collision are cleared, keeping the known bits intact
+        let coll = self.read(COLL_REG).await?;
+        self.write(COLL_REG, coll & !VALUES_AFTER_COLL).await?;
+        let mut uid_part = [0u8; 5];
+        let mut known_bits = 0;
+        loop {
+            let full_bytes = known_bits / 8;
+            let last_bits = (known_bits % 8) as u8;
+            // the partial byte is sent, and its remaining bits come back in place
+            let sent_bytes = full_bytes + usize::from(last_bits != 0);
+            let mut frame = [0u8; 2 + 5];
+            frame[0] = cascade_level;
+            frame[1] = (((2 + full_bytes) as u8) << 4) | last_bits;
+            frame[2..2 + sent_bytes].copy_from_slice(&uid_part[..sent_bytes]);
+            let response = &mut uid_part[full_bytes..];
+            let expected = response.len();
+            match self
+                .transceive(&frame[..2 + sent_bytes], last_bits, last_bits, response)
+                .await
+            {
+                Ok((received, _)) if received == expected => return Ok(uid_part),
+                Ok(_) => return Err(Error::IncompleteFrame),
+                Err(Error::Collision) => {
+                    let coll = self.read(COLL_REG).await?;
+                    if coll & COLL_POS_NOT_VALID != 0 {
+                        return Err(Error::Collision);
+                    }
+                    // 1 based position of the first colliding bit, 0 meaning the 32nd
+                    let position = match (coll & 0x1f) as usize {
+                        0 => 32,
+                        position => position,
+                    };
+                    if position <= known_bits {
+                        return Err(Error::Collision);
+                    }
+                    uid_part[(position - 1) / 8] |= 1 << ((position - 1) % 8);
+                    known_bits = position;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// puts the selected tag into the halt state, where only WUPA wakes it up
+    pub async fn hlta(&mut self) -> Result<(), Error> {
+        let mut frame = [PICC_HLTA, 0x00, 0, 0];
+        frame[2..].copy_from_slice(&crc_a(&frame[..2]));
+        // a halted tag does not answer, so silence is success
+        match self.transceive(&frame, 0, 0, &mut [0u8; 1]).await {
+            Err(Error::Timeout) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Err(Error::Protocol),
+        }
+    }
+
+    /// reads four ntag pages starting at `page`
+    pub async fn ntag_read(&mut self, page: u8) -> Result<[u8; 16], Error> {
+        let mut frame = [PICC_READ, page, 0, 0];
+        frame[2..].copy_from_slice(&crc_a(&frame[..2]));
+        let mut response = [0u8; 18];
+        let (received, last_bits) = self.transceive(&frame, 0, 0, &mut response).await?;
+        if received == 1 && last_bits == 4 {
+            return Err(Error::Nak);
+        }
+        if received != response.len() {
+            return Err(Error::IncompleteFrame);
+        }
+        if !check_crc(&response) {
+            return Err(Error::Crc);
+        }
+        let mut data = [0u8; 16];
+        data.copy_from_slice(&response[..16]);
+        Ok(data)
+    }
+
+    /// writes a single four byte ntag page
+    pub async fn ntag_write(&mut self, page: u8, data: [u8; 4]) -> Result<(), Error> {
+        let mut frame = [0u8; 8];
+        frame[0] = PICC_WRITE;
+        frame[1] = page;
+        frame[2..6].copy_from_slice(&data);
+        frame[6..].copy_from_slice(&crc_a(&frame[..6]));
+        let mut ack = [0u8; 1];
+        match self.transceive(&frame, 0, 0, &mut ack).await? {
+            (1, 4) if ack[0] & 0x0f == PICC_ACK => Ok(()),
+            _ => Err(Error::Nak),
+        }
+    }
+
+    async fn request(&mut self, command: u8) -> Result<AtqA, Error> {
+        let mut atqa = [0u8; 2];
+        // short frame, only 7 bits of the command byte are sent
+        let (received, _) = self.transceive(&[command], 7, 0, &mut atqa).await?;
+        if received != atqa.len() {
+            return Err(Error::IncompleteFrame);
+        }
+        Ok(AtqA(atqa))
+    }
+
+    /// sends `data` to the tag and receives its answer into `buffer`, returning the
+    /// number of bytes received and the valid bits in the last one (0 meaning all 8)
+    ///
+    /// the first received bit lands at bit `rx_align` of `buffer[0]`, keeping the bits
+    /// below it. on `Err(Error::Collision)` the bits up to the collision are in `buffer`.
+    async fn transceive(
+        &mut self,
+        data: &[u8],
+        tx_last_bits: u8,
+        rx_align: u8,
+        buffer: &mut [u8],
+    ) -> Result<(usize, u8), Error> {
+        let bit_framing = (rx_align << 4) | tx_last_bits;
+        self.write(COMMAND_REG, CMD_IDLE).await?;
+        self.write(COM_IRQ_REG, 0x7f).await?;
+        self.write(FIFO_LEVEL_REG, 0x80).await?;
+        self.comm.write(FIFO_DATA_REG, data).await?;
+        self.write(COMMAND_REG, CMD_TRANSCEIVE).await?;
+        self.write(BIT_FRAMING_REG, START_SEND | bit_framing)
+            .await?;
+        let irq = self.wait_for_irq().await;
+        self.write(BIT_FRAMING_REG, bit_framing).await?;
+        if irq? & RX_IRQ == 0 {
+            return Err(Error::Timeout);
+        }
+
+        let error = self.read(ERROR_REG).await?;
+        if error & ERR_BUFFER_OVERFLOW != 0 {
+            return Err(Error::BufferOverflow);
+        }
+        if error & ERR_PARITY != 0 {
+            return Err(Error::Parity);
+        }
+        if error & ERR_PROTOCOL != 0 {
+            return Err(Error::Protocol);
+        }
+
+        let received = (self.read(FIFO_LEVEL_REG).await? & 0x7f) as usize;
+        if received > buffer.len() {
+            return Err(Error::BufferOverflow);
+        }
+        let known = buffer.first().copied().unwrap_or(0);
+        self.comm
+            .read(FIFO_DATA_REG, &mut buffer[..received])
+            .await?;
+        if rx_align != 0 && received > 0 {
+            let mask = 0xff << rx_align;
+            buffer[0] = (known & !mask) | (buffer[0] & mask);
+        }
+        if error & ERR_COLLISION != 0 {
+            return Err(Error::Collision);
+        }
+        let last_bits = self.read(CONTROL_REG).await? & 0x07;
+        Ok((received, last_bits))
+    }
+
+    /// waits for the transceive in progress to finish, returning the interrupt flags
+    async fn wait_for_irq(&mut self) -> Result<u8, Error> {
+        let wait = async {
+            loop {
+                match self.irq.as_mut() {
+                    Some(irq) => irq.wait_for_low().await.map_err(|_| Error::Comm)?,
+                    None => Timer::after(Duration::from_millis(1)).await,
+                }
+                let irq = self.read(COM_IRQ_REG).await?;
+                if irq & (RX_IRQ | IDLE_IRQ | TIMER_IRQ) != 0 {
+                    return Ok(irq);
+                }
+            }
+        };
+        with_timeout(Duration::from_millis(RFID_TRANSCEIVE_TIMEOUT_MS), wait)
+            .await
+            .map_err(|_| Error::Unresponsive)?
+    }
+
+    async fn read(&mut self, register: u8) -> Result<u8, Error> {
+        let mut value = [0u8; 1];
+        self.comm.read(register, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn write(&mut self, register: u8, value: u8) -> Result<(), Error> {
+        self.comm.write(register, &[value]).await
+    }
+}
+
+impl<COMM: Interface, IRQ: Wait> TagReader for Mfrc522<COMM, IRQ> {
+    async fn init(&mut self) -> Result<u8, Error> {
+        self.configure().await?;
+        self.set_antenna_gain(RxGain::DB48).await?;
+        self.version().await
+    }
+
+    async fn version(&mut self) -> Result<u8, Error> {
+        self.read(VERSION_REG).await
+    }
+
+    async fn poll(&mut self) -> Result<TagUid, Error> {
+        self.new_card_present().await?;
+        self.select().await
+    }
+
+    async fn reset_field(&mut self) -> Result<(), Error> {
+        self.set_antenna(false).await?;
+        Timer::after(Duration::from_millis(RFID_FIELD_RESET_MS)).await;
+        self.set_antenna(true).await?;
+        // tags need a moment to power up before they answer
+        Timer::after(Duration::from_millis(RFID_FIELD_RESET_MS)).await;
+        Ok(())
+    }
+
+    async fn halt(&mut self) -> Result<(), Error> {
+        self.hlta().await
+    }
+
+    async fn read_pages(&mut self, page: u8) -> Result<[u8; 16], Error> {
+        self.ntag_read(page).await
+    }
+
+    async fn write_page(&mut self, page: u8, data: [u8; 4]) -> Result<(), Error> {
+        self.ntag_write(page, data).await
+    }
+}
+
+/// iso 14443-3 type a crc, transmitted least significant byte first
+fn crc_a(data: &[u8]) -> [u8; 2] {
+    let mut crc: u16 = 0x6363;
+    for &byte in data {
+        let mut b = byte ^ (crc as u8);
+        b ^= b << 4;
+        crc = (crc >> 8) ^ ((b as u16) << 8) ^ ((b as u16) << 3) ^ ((b as u16) >> 4);
+    }
+    crc.to_le_bytes()
+}
+
+/// checks the crc in the last two bytes of a received frame
+fn check_crc(frame: &[u8]) -> bool {
+    let (data, crc) = frame.split_at(frame.len() - 2);
+    crc_a(data) == crc
+}
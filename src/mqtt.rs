@@ -0,0 +1,637 @@
+pub mod broker;
+
+use crate::constants::MQTT_PACKET_SIZE;
+use defmt::Format;
+use heapless::Vec;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const PROTOCOL_NAME: &str = "MQTT";
+/// mqtt 3.1.1
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNECT_CLEAN_SESSION: u8 = 1 << 1;
+const CONNECT_WILL: u8 = 1 << 2;
+const CONNECT_WILL_RETAIN: u8 = 1 << 5;
+const CONNECT_PASSWORD: u8 = 1 << 6;
+const CONNECT_USERNAME: u8 = 1 << 7;
+
+pub const CONNACK_ACCEPTED: u8 = 0;
+pub const CONNACK_UNACCEPTABLE_PROTOCOL: u8 = 1;
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+pub type PacketBuffer = Vec<u8, MQTT_PACKET_SIZE>;
+
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum Error {
+    /// the packet does not fit `MQTT_PACKET_SIZE`
+    TooLarge,
+    Malformed,
+}
+
+/// the message a broker publishes for a client that goes away without disconnecting
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// a topic filter list as it appears in subscribe and unsubscribe packets
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub struct Filters<'a> {
+    raw: &'a [u8],
+    /// subscribe packets follow every filter with its requested qos
+    with_qos: bool,
+}
+
+impl<'a> Filters<'a> {
+    /// wraps a filter list built by `subscribe_filters`
+    pub fn subscribe(raw: &'a [u8]) -> Self {
+        Self {
+            raw,
+            with_qos: true,
+        }
+    }
+
+    /// the filters with their requested qos, 0 for unsubscribe packets
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, u8)> {
+        let mut rest = self.raw;
+        let with_qos = self.with_qos;
+        core::iter::from_fn(move || {
+            let mut reader = Reader { data: rest };
+            let filter = reader.string().ok()?;
+            let qos = if with_qos { reader.u8().ok()? } else { 0 };
+            rest = reader.data;
+            Some((filter, qos))
+        })
+    }
+}
+
+/// a decoded mqtt packet, borrowing its strings and payload from the receive buffer
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum Packet<'a> {
+    Connect {
+        protocol_level: u8,
+        client_id: &'a str,
+        keep_alive_secs: u16,
+        clean_session: bool,
+        will: Option<Will<'a>>,
+        username: Option<&'a str>,
+        password: Option<&'a [u8]>,
+    },
+    ConnAck {
+        session_present: bool,
+        return_code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+        qos: u8,
+        retain: bool,
+        dup: bool,
+        /// only qos 1 and 2 messages carry a packet id
+        packet_id: Option<u16>,
+    },
+    PubAck {
+        packet_id: u16,
+    },
+    Subscribe {
+        packet_id: u16,
+        filters: Filters<'a>,
+    },
+    SubAck {
+        packet_id: u16,
+        return_codes: &'a [u8],
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Filters<'a>,
+    },
+    UnsubAck {
+        packet_id: u16,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl<'a> Packet<'a> {
+    /// decodes the packet at the start of `data`, returning it with its length, or
+    /// `None` while the packet has not been received in full
+    pub fn decode(data: &'a [u8]) -> Result<Option<(Packet<'a>, usize)>, Error> {
+        let Some(&first) = data.first() else {
+            return Ok(None);
+        };
+        let Some((remaining_len, len_bytes)) = decode_remaining_len(&data[1..])? else {
+            return Ok(None);
+        };
+        let header_len = 1 + len_bytes;
+        let len = header_len + remaining_len;
+        if len > MQTT_PACKET_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let Some(body) = data.get(header_len..len) else {
+            return Ok(None);
+        };
+        let packet = Self::decode_body(first >> 4, first & 0x0f, body)?;
+        Ok(Some((packet, len)))
+    }
+
+    fn decode_body(packet_type: u8, flags: u8, body: &'a [u8]) -> Result<Packet<'a>, Error> {
+        let mut reader = Reader { data: body };
+        let packet = match packet_type {
+            CONNECT => {
+                if reader.string()? != PROTOCOL_NAME {
+                    return Err(Error::Malformed);
+                }
+                let protocol_level = reader.u8()?;
+                let connect_flags = reader.u8()?;
+                let keep_alive_secs = reader.u16()?;
+                let client_id = reader.string()?;
+                let will = if connect_flags & CONNECT_WILL != 0 {
+                    Some(Will {
+                        topic: reader.string()?,
+                        payload: reader.bytes()?,
+                        qos: (connect_flags >> 3) & 0x03,
+                        retain: connect_flags & CONNECT_WILL_RETAIN != 0,
+                    })
+                } else {
+                    None
+                };
+                let username = if connect_flags & CONNECT_USERNAME != 0 {
+                    Some(reader.string()?)
+                } else {
+                    None
+                };
+                let password = if connect_flags & CONNECT_PASSWORD != 0 {
+                    Some(reader.bytes()?)
+                } else {
+                    None
+                };
+                Packet::Connect {
+                    protocol_level,
+                    client_id,
+                    keep_alive_secs,
+                    clean_session: connect_flags & CONNECT_CLEAN_SESSION != 0,
+                    will,
+                    username,
+                    password,
+                }
+            }
+            CONNACK => Packet::ConnAck {
+                session_present: reader.u8()? & 0x01 != 0,
+                return_code: reader.u8()?,
+            },
+            PUBLISH => {
+                let qos = (flags >> 1) & 0x03;
+                let topic = reader.string()?;
+                let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+                Packet::Publish {
+                    topic,
+                    payload: reader.data,
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    dup: flags & 0x08 != 0,
+                    packet_id,
+                }
+            }
+            PUBACK => Packet::PubAck {
+                packet_id: reader.u16()?,
+            },
+            SUBSCRIBE => Packet::Subscribe {
+                packet_id: reader.u16()?,
+                filters: Filters {
+                    raw: reader.data,
+                    with_qos: true,
+                },
+            },
+            SUBACK => Packet::SubAck {
+                packet_id: reader.u16()?,
+                return_codes: reader.data,
+            },
+            UNSUBSCRIBE => Packet::Unsubscribe {
+                packet_id: reader.u16()?,
+                filters: Filters {
+                    raw: reader.data,
+                    with_qos: false,
+                },
+            },
+            UNSUBACK => Packet::UnsubAck {
+                packet_id: reader.u16()?,
+            },
+            PINGREQ => Packet::PingReq,
+            PINGRESP => Packet::PingResp,
+            DISCONNECT => Packet::Disconnect,
+            _ => return Err(Error::Malformed),
+        };
+        Ok(packet)
+    }
+
+    /// encodes the packet, ready to be written to the connection
+    pub fn encode(&self) -> Result<PacketBuffer, Error> {
+        let mut body = Writer::default();
+        let first = match *self {
+            Packet::Connect {
+                protocol_level,
+                client_id,
+                keep_alive_secs,
+                clean_session,
+                will,
+                username,
+                password,
+            } => {
+                let mut connect_flags = 0;
+                if clean_session {
+                    connect_flags |= CONNECT_CLEAN_SESSION;
+                }
+                if let Some(will) = will {
+                    connect_flags |= CONNECT_WILL | (will.qos & 0x03) << 3;
+                    if will.retain {
+                        connect_flags |= CONNECT_WILL_RETAIN;
+                    }
+                }
+                if username.is_some() {
+                    connect_flags |= CONNECT_USERNAME;
+                }
+                if password.is_some() {
+                    connect_flags |= CONNECT_PASSWORD;
+                }
+                body.string(PROTOCOL_NAME)?;
+                body.u8(protocol_level)?;
+                body.u8(connect_flags)?;
+                body.u16(keep_alive_secs)?;
+                body.string(client_id)?;
+                if let Some(will) = will {
+                    body.string(will.topic)?;
+                    body.bytes(will.payload)?;
+                }
+                if let Some(username) = username {
+                    body.string(username)?;
+                }
+                if let Some(password) = password {
+                    body.bytes(password)?;
+                }
+                CONNECT << 4
+            }
+            Packet::ConnAck {
+                session_present,
+                return_code,
+            } => {
+                body.u8(session_present as u8)?;
+                body.u8(return_code)?;
+                CONNACK << 4
+            }
+            Packet::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+                dup,
+                packet_id,
+            } => {
+                body.string(topic)?;
+                if qos > 0 {
+                    body.u16(packet_id.ok_or(Error::Malformed)?)?;
+                }
+                body.raw(payload)?;
+                PUBLISH << 4 | (dup as u8) << 3 | (qos & 0x03) << 1 | retain as u8
+            }
+            Packet::PubAck { packet_id } => {
+                body.u16(packet_id)?;
+                PUBACK << 4
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.u16(packet_id)?;
+                body.raw(filters.raw)?;
+                // subscribe and unsubscribe have to set the reserved flag bit
+                SUBSCRIBE << 4 | 0x02
+            }
+            Packet::SubAck {
+                packet_id,
+                return_codes,
+            } => {
+                body.u16(packet_id)?;
+                body.raw(return_codes)?;
+                SUBACK << 4
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.u16(packet_id)?;
+                body.raw(filters.raw)?;
+                UNSUBSCRIBE << 4 | 0x02
+            }
+            Packet::UnsubAck { packet_id } => {
+                body.u16(packet_id)?;
+                UNSUBACK << 4
+            }
+            Packet::PingReq => PINGREQ << 4,
+            Packet::PingResp => PINGRESP << 4,
+            Packet::Disconnect => DISCONNECT << 4,
+        };
+
+        let mut packet = PacketBuffer::new();
+        packet.push(first).map_err(|_| Error::TooLarge)?;
+        packet
+            .extend_from_slice(&encode_remaining_len(body.data.len())?)
+            .map_err(|_| Error::TooLarge)?;
+        packet
+            .extend_from_slice(&body.data)
+            .map_err(|_| Error::TooLarge)?;
+        Ok(packet)
+    }
+}
+
+/// the largest remaining length the four length bytes hold
+const MAX_REMAINING_LEN: usize = 268_435_455;
+
+/// decodes the remaining length at the start of `data`, returning it with the number
+/// of bytes it takes, or `None` while they have not all been received
+fn decode_remaining_len(data: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    // up to four bytes, seven bits each, the top bit telling that another follows
+    let mut remaining_len = 0usize;
+    for (i, &byte) in data.iter().take(4).enumerate() {
+        remaining_len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((remaining_len, i + 1)));
+        }
+    }
+    if data.len() >= 4 {
+        Err(Error::Malformed)
+    } else {
+        Ok(None)
+    }
+}
+
+fn encode_remaining_len(mut remaining_len: usize) -> Result<Vec<u8, 4>, Error> {
+    if remaining_len > MAX_REMAINING_LEN {
+        return Err(Error::TooLarge);
+    }
+    let mut encoded = Vec::new();
+    loop {
+        let mut byte = (remaining_len & 0x7f) as u8;
+        remaining_len >>= 7;
+        if remaining_len > 0 {
+            byte |= 0x80;
+        }
+        // four bytes hold anything up to `MAX_REMAINING_LEN`
+        let _ = encoded.push(byte);
+        if remaining_len == 0 {
+            return Ok(encoded);
+        }
+    }
+}
+
+/// whether `topic` matches `filter`, with `+` standing for one topic level and a
+/// trailing `#` for any number of them
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // wildcards never match the broker's own `$` topics
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// whether `filter` uses its wildcards only as whole levels, with `#` only last
+pub fn valid_filter(filter: &str) -> bool {
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let wildcard = level.contains(['+', '#']);
+        if (wildcard && level.len() > 1) || (level == "#" && levels.peek().is_some()) {
+            return false;
+        }
+    }
+    !filter.is_empty()
+}
+
+/// builds the topic filter list for a subscribe packet
+pub fn subscribe_filters(filters: &[(&str, u8)]) -> Result<PacketBuffer, Error> {
+    let mut writer = Writer::default();
+    for (filter, qos) in filters {
+        writer.string(filter)?;
+        writer.u8(*qos)?;
+    }
+    Ok(writer.data)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < len {
+            return Err(Error::Malformed);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// length prefixed binary data
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    /// length prefixed utf-8 string
+    fn string(&mut self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.bytes()?).map_err(|_| Error::Malformed)
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    data: PacketBuffer,
+}
+
+impl Writer {
+    fn raw(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.data
+            .extend_from_slice(data)
+            .map_err(|_| Error::TooLarge)
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.raw(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.raw(&value.to_be_bytes())
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let len = u16::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+        self.u16(len)?;
+        self.raw(data)
+    }
+
+    fn string(&mut self, string: &str) -> Result<(), Error> {
+        self.bytes(string.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_remaining_lengths_at_their_limits() {
+        for (len, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xff, 0xff, 0x7f]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+            (MAX_REMAINING_LEN, &[0xff, 0xff, 0xff, 0x7f]),
+        ] {
+            assert_eq!(encode_remaining_len(len).unwrap(), encoded);
+            assert_eq!(
+                decode_remaining_len(encoded),
+                Ok(Some((len, encoded.len())))
+            );
+        }
+        assert_eq!(
+            encode_remaining_len(MAX_REMAINING_LEN + 1),
+            Err(Error::TooLarge)
+        );
+    }
+
+    #[test]
+    fn decodes_remaining_lengths_as_they_arrive() {
+        assert_eq!(decode_remaining_len(&[]), Ok(None));
+        assert_eq!(decode_remaining_len(&[0x80]), Ok(None));
+        assert_eq!(decode_remaining_len(&[0xff, 0xff, 0xff]), Ok(None));
+        // the length bytes are followed by the packet body
+        assert_eq!(decode_remaining_len(&[0x05, 0xff]), Ok(Some((5, 1))));
+        // a fifth length byte
+        assert_eq!(
+            decode_remaining_len(&[0xff, 0xff, 0xff, 0xff, 0x01]),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn refuses_packets_larger_than_the_buffer() {
+        assert_eq!(
+            Packet::decode(&[PUBLISH << 4, 0xff, 0xff, 0xff, 0x7f]),
+            Err(Error::TooLarge)
+        );
+        let payload = [0u8; MQTT_PACKET_SIZE];
+        let publish = Packet::Publish {
+            topic: "a",
+            payload: &payload,
+            qos: 0,
+            retain: false,
+            dup: false,
+            packet_id: None,
+        };
+        assert_eq!(publish.encode(), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn round_trips_packets_across_the_length_boundary() {
+        // a one byte topic takes three bytes, leaving 124 and 125 for the payload
+        for payload_len in [124, 125] {
+            let payload = [0x42u8; 125];
+            let publish = Packet::Publish {
+                topic: "a",
+                payload: &payload[..payload_len],
+                qos: 0,
+                retain: true,
+                dup: false,
+                packet_id: None,
+            };
+            let encoded = publish.encode().unwrap();
+            assert_eq!(
+                encoded.len(),
+                payload_len + 5 + (payload_len == 125) as usize
+            );
+            assert_eq!(Packet::decode(&encoded), Ok(Some((publish, encoded.len()))));
+            // cut short, it waits for the rest
+            assert_eq!(Packet::decode(&encoded[..encoded.len() - 1]), Ok(None));
+        }
+    }
+
+    #[test]
+    fn matches_topics() {
+        assert!(topic_matches("stat/bulb/RESULT", "stat/bulb/RESULT"));
+        assert!(!topic_matches("stat/bulb/RESULT", "stat/bulb"));
+        assert!(!topic_matches("stat/bulb", "stat/bulb/RESULT"));
+
+        assert!(topic_matches("stat/+/RESULT", "stat/bulb/RESULT"));
+        assert!(topic_matches("stat/+/RESULT", "stat//RESULT"));
+        assert!(!topic_matches("stat/+/RESULT", "stat/bulb/STATE"));
+        assert!(!topic_matches("stat/+", "stat/bulb/RESULT"));
+        assert!(topic_matches("+/+", "/bulb"));
+
+        assert!(topic_matches("#", "stat/bulb/RESULT"));
+        assert!(topic_matches("stat/#", "stat/bulb/RESULT"));
+        // `#` includes the level above it
+        assert!(topic_matches("stat/#", "stat"));
+        assert!(!topic_matches("stat/#", "tele/bulb/STATE"));
+    }
+
+    #[test]
+    fn wildcards_leave_out_dollar_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn validates_filters() {
+        for filter in [
+            "stat/bulb/RESULT",
+            "#",
+            "+",
+            "stat/+/RESULT",
+            "stat/#",
+            "+/+/#",
+        ] {
+            assert!(valid_filter(filter), "{}", filter);
+        }
+        for filter in [
+            "",
+            "stat/bulb#",
+            "stat/#/RESULT",
+            "stat/bu+lb",
+            "stat+/bulb",
+            "##",
+        ] {
+            assert!(!valid_filter(filter), "{}", filter);
+        }
+    }
+}
@@ -0,0 +1,638 @@
+extern crate alloc;
+use super::{
+    topic_matches, valid_filter, Filters, Packet, CONNACK_ACCEPTED, CONNACK_UNACCEPTABLE_PROTOCOL,
+    SUBACK_FAILURE,
+};
+use crate::constants::{
+    MQTT_BROKER_CONNECT_TIMEOUT_SECS, MQTT_BROKER_MAX_CLIENTS, MQTT_BROKER_PORT, MQTT_MAX_RETAINED,
+    MQTT_MAX_SUBSCRIPTIONS, MQTT_OUTBOX_SIZE, MQTT_PACKET_SIZE, MQTT_PAYLOAD_SIZE, TCP_BUFFER_SIZE,
+};
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use defmt::{info, warn, Format};
+use embassy_futures::select::{select3, Either3};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_deadline, Duration, Instant};
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+
+/// the session slot used by the firmware itself, after the ones for network clients
+pub const LOCAL_SLOT: usize = MQTT_BROKER_MAX_CLIENTS;
+const SLOTS: usize = MQTT_BROKER_MAX_CLIENTS + 1;
+/// longest topic or topic filter the broker keeps
+const TOPIC_SIZE: usize = 64;
+/// most filters a subscribe packet holds, each taking at least four bytes of it
+const MAX_FILTERS: usize = MQTT_PACKET_SIZE / 4;
+
+pub type Topic = String<TOPIC_SIZE>;
+
+/// a published message the broker holds on to, for retaining or delivering
+#[derive(Debug, Format, Clone)]
+pub struct Message {
+    pub topic: Topic,
+    pub payload: Vec<u8, MQTT_PAYLOAD_SIZE>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+impl Message {
+    /// copies the message, returning `None` when it is too large to keep
+    pub fn new(topic: &str, payload: &[u8], qos: u8, retain: bool) -> Option<Self> {
+        Some(Self {
+            topic: String::try_from(topic).ok()?,
+            payload: Vec::from_slice(payload).ok()?,
+            qos,
+            retain,
+        })
+    }
+
+    pub fn as_publish(&self, packet_id: Option<u16>) -> Packet<'_> {
+        Packet::Publish {
+            topic: &self.topic,
+            payload: &self.payload,
+            qos: self.qos,
+            retain: self.retain,
+            dup: false,
+            packet_id,
+        }
+    }
+}
+
+struct Session {
+    client_id: String<TOPIC_SIZE>,
+    subscriptions: Vec<(Topic, u8), MQTT_MAX_SUBSCRIPTIONS>,
+    will: Option<Message>,
+}
+
+struct BrokerState {
+    sessions: [Option<Session>; SLOTS],
+    retained: Vec<Message, MQTT_MAX_RETAINED>,
+}
+
+/// a minimal mqtt 3.1.1 broker for the devices on the access point
+///
+/// every client gets a session slot with a queue of messages waiting to be written to
+/// it. qos 0 and 1 are supported, qos 1 messages are acknowledged but never resent,
+/// and sessions are always clean. a client connecting with the client id of another
+/// connection takes over its session, closing the old connection. the firmware itself
+/// subscribes and publishes through `LOCAL_SLOT` without a network connection.
+pub struct MqttBroker {
+    state: Mutex<NoopRawMutex, RefCell<BrokerState>>,
+    outboxes: [Channel<NoopRawMutex, Message, MQTT_OUTBOX_SIZE>; SLOTS],
+    /// set for a connection whose client connected again through another one
+    taken_over: [Signal<NoopRawMutex, ()>; SLOTS],
+}
+
+impl Default for MqttBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MqttBroker {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(BrokerState {
+                sessions: [const { None }; SLOTS],
+                retained: Vec::new(),
+            })),
+            outboxes: [const { Channel::new() }; SLOTS],
+            taken_over: [const { Signal::new() }; SLOTS],
+        }
+    }
+
+    /// starts a clean session in `slot`, dropping anything still queued for it. another
+    /// network client's session with the same client id is dropped without its will,
+    /// the client being back
+    pub fn connect(&self, slot: usize, client_id: &str, will: Option<Message>) {
+        self.clear(slot);
+        let client_id = String::try_from(client_id).unwrap_or_default();
+        self.state.lock(|state| {
+            let sessions = &mut state.borrow_mut().sessions;
+            if slot != LOCAL_SLOT {
+                for (other, session) in sessions[..LOCAL_SLOT].iter_mut().enumerate() {
+                    let same_client = session
+                        .as_ref()
+                        .is_some_and(|session| session.client_id == client_id);
+                    if other != slot && same_client {
+                        info!(
+                            "mqtt client {} took over slot {}",
+                            client_id.as_str(),
+                            other
+                        );
+                        *session = None;
+                        self.taken_over[other].signal(());
+                    }
+                }
+            }
+            sessions[slot] = Some(Session {
+                client_id,
+                subscriptions: Vec::new(),
+                will,
+            });
+        });
+    }
+
+    /// drops the messages still queued for `slot` and a takeover of its last session
+    fn clear(&self, slot: usize) {
+        while self.outboxes[slot].try_receive().is_ok() {}
+        self.taken_over[slot].reset();
+    }
+
+    /// ends the session in `slot`, publishing its will when it went away without
+    /// disconnecting
+    pub fn disconnect(&self, slot: usize, publish_will: bool) {
+        let session = self
+            .state
+            .lock(|state| state.borrow_mut().sessions[slot].take());
+        if let Some(Session {
+            client_id,
+            will: Some(will),
+            ..
+        }) = session.filter(|_| publish_will)
+        {
+            info!("publishing will of {}", client_id.as_str());
+            self.publish(will);
+        }
+    }
+
+    /// subscribes `slot` to `filter` and queues the retained messages it matches,
+    /// returning the granted qos or `SUBACK_FAILURE`
+    pub fn subscribe(&self, slot: usize, filter: &str, qos: u8) -> u8 {
+        // qos 2 is granted as qos 1
+        let qos = qos.min(1);
+        let Ok(filter) = Topic::try_from(filter) else {
+            return SUBACK_FAILURE;
+        };
+        if !valid_filter(&filter) {
+            return SUBACK_FAILURE;
+        }
+        self.state.lock(|state| {
+            let state = &mut *state.borrow_mut();
+            let Some(session) = state.sessions[slot].as_mut() else {
+                return SUBACK_FAILURE;
+            };
+            let subscriptions = &mut session.subscriptions;
+            match subscriptions.iter_mut().find(|(f, _)| *f == filter) {
+                Some(subscription) => subscription.1 = qos,
+                None => {
+                    if subscriptions.push((filter.clone(), qos)).is_err() {
+                        return SUBACK_FAILURE;
+                    }
+                }
+            }
+            for message in state.retained.iter() {
+                if topic_matches(&filter, &message.topic) {
+                    let mut message = message.clone();
+                    message.qos = message.qos.min(qos);
+                    self.deliver(slot, message);
+                }
+            }
+            qos
+        })
+    }
+
+    /// subscribes `slot` to every filter of a subscribe packet, returning the codes
+    /// for its suback
+    pub fn subscribe_all(&self, slot: usize, filters: Filters) -> Vec<u8, MAX_FILTERS> {
+        // the filters cannot outnumber `MAX_FILTERS`
+        filters
+            .iter()
+            .map(|(filter, qos)| self.subscribe(slot, filter, qos))
+            .collect()
+    }
+
+    pub fn unsubscribe(&self, slot: usize, filter: &str) {
+        self.state.lock(|state| {
+            if let Some(session) = state.borrow_mut().sessions[slot].as_mut() {
+                session.subscriptions.retain(|(f, _)| f != filter);
+            }
+        });
+    }
+
+    /// retains the message if asked to, and queues it for every matching subscription
+    pub fn publish(&self, message: Message) {
+        self.state.lock(|state| {
+            let state = &mut *state.borrow_mut();
+            if message.retain {
+                state.retained.retain(|m| m.topic != message.topic);
+                // an empty retained message only clears the topic
+                if !message.payload.is_empty() && state.retained.push(message.clone()).is_err() {
+                    warn!("no room to retain {}", message.topic.as_str());
+                }
+            }
+            for (slot, session) in state.sessions.iter().enumerate() {
+                let Some(session) = session else {
+                    continue;
+                };
+                let granted = session
+                    .subscriptions
+                    .iter()
+                    .filter(|(filter, _)| topic_matches(filter, &message.topic))
+                    .map(|(_, qos)| *qos)
+                    .max();
+                if let Some(granted) = granted {
+                    let mut delivery = message.clone();
+                    delivery.qos = delivery.qos.min(granted);
+                    // retain is only set on messages sent because of a new subscription
+                    delivery.retain = false;
+                    self.deliver(slot, delivery);
+                }
+            }
+        });
+    }
+
+    /// waits for the next message matching the firmware's own subscriptions
+    pub async fn receive_local(&self) -> Message {
+        self.outboxes[LOCAL_SLOT].receive().await
+    }
+
+    fn deliver(&self, slot: usize, message: Message) {
+        if self.outboxes[slot].try_send(message).is_err() {
+            warn!(
+                "mqtt client in slot {} is not keeping up, dropping a message",
+                slot
+            );
+        }
+    }
+}
+
+/// what to do with the connection after handling a packet
+enum Next {
+    Continue,
+    /// close the connection, publishing the client's will unless it disconnected
+    Close {
+        clean: bool,
+    },
+}
+
+/// accepts mqtt clients on `MQTT_BROKER_PORT`, one at a time, for session `slot`.
+/// one task runs for every slot below `MQTT_BROKER_MAX_CLIENTS`
+#[embassy_executor::task(pool_size = MQTT_BROKER_MAX_CLIENTS)]
+pub async fn mqtt_broker_task(stack: Stack<'static>, broker: &'static MqttBroker, slot: usize) {
+    // every task in the pool needs its own buffers, which a static cell per call site
+    // cannot give, so they come from the heap once
+    let rx_buffer = Box::leak(Box::new([0u8; TCP_BUFFER_SIZE]));
+    let tx_buffer = Box::leak(Box::new([0u8; TCP_BUFFER_SIZE]));
+    stack.wait_config_up().await;
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer[..], &mut tx_buffer[..]);
+        if let Err(e) = socket.accept(MQTT_BROKER_PORT).await {
+            warn!("mqtt accept error: {:?}", e);
+            continue;
+        }
+        info!("mqtt client connected to slot {}", slot);
+        let clean = serve(&mut socket, broker, slot).await;
+        broker.disconnect(slot, !clean);
+        info!("mqtt client in slot {} disconnected", slot);
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+    }
+}
+
+/// runs one client connection, returning whether the client disconnected cleanly
+async fn serve(socket: &mut TcpSocket<'_>, broker: &MqttBroker, slot: usize) -> bool {
+    let mut pending: Vec<u8, MQTT_PACKET_SIZE> = Vec::new();
+    let mut connected = false;
+    // until the client sends its keep alive, it has to connect in time
+    let mut keep_alive = Duration::from_secs(MQTT_BROKER_CONNECT_TIMEOUT_SECS);
+    let mut last_heard = Instant::now();
+    let mut packet_id = 0u16;
+
+    loop {
+        // handle every complete packet received so far
+        loop {
+            let (next, len) = match Packet::decode(&pending) {
+                Ok(Some((packet, len))) => {
+                    let next = handle(socket, broker, slot, &packet, &mut connected).await;
+                    if let Packet::Connect {
+                        keep_alive_secs, ..
+                    } = packet
+                    {
+                        // a keep alive of 0 turns the timeout off
+                        keep_alive = match keep_alive_secs {
+                            0 => Duration::from_secs(u32::MAX as u64),
+                            secs => Duration::from_secs(secs as u64),
+                        };
+                    }
+                    (next, len)
+                }
+                Ok(None) if !pending.is_full() => break,
+                Ok(None) | Err(_) => {
+                    warn!("mqtt client sent a malformed or oversized packet");
+                    return false;
+                }
+            };
+            pending.rotate_left(len);
+            pending.truncate(pending.len() - len);
+            if let Next::Close { clean } = next {
+                return clean;
+            }
+        }
+
+        // the client has one and a half keep alive periods to send something
+        let deadline = last_heard + keep_alive + keep_alive / 2;
+        let mut chunk = [0u8; 256];
+        let room = (pending.capacity() - pending.len()).min(chunk.len());
+        let read = socket.read(&mut chunk[..room]);
+        let outbox = broker.outboxes[slot].receive();
+        let taken_over = broker.taken_over[slot].wait();
+        match with_deadline(deadline, select3(read, outbox, taken_over)).await {
+            Err(_) => {
+                info!("mqtt client in slot {} timed out", slot);
+                return false;
+            }
+            Ok(Either3::First(Ok(0) | Err(_))) => return false,
+            Ok(Either3::First(Ok(len))) => {
+                let _ = pending.extend_from_slice(&chunk[..len]);
+                last_heard = Instant::now();
+            }
+            Ok(Either3::Second(message)) => {
+                let id = (message.qos > 0).then(|| {
+                    packet_id = packet_id.wrapping_add(1).max(1);
+                    packet_id
+                });
+                if connected && !write(socket, &message.as_publish(id)).await {
+                    return false;
+                }
+            }
+            // the session is gone already, and with it the will
+            Ok(Either3::Third(())) => return true,
+        }
+    }
+}
+
+async fn handle(
+    socket: &mut TcpSocket<'_>,
+    broker: &MqttBroker,
+    slot: usize,
+    packet: &Packet<'_>,
+    connected: &mut bool,
+) -> Next {
+    let reply = match *packet {
+        Packet::Connect {
+            protocol_level,
+            client_id,
+            will,
+            ..
+        } if !*connected => {
+            if protocol_level != 4 {
+                let refused = Packet::ConnAck {
+                    session_present: false,
+                    return_code: CONNACK_UNACCEPTABLE_PROTOCOL,
+                };
+                write(socket, &refused).await;
+                return Next::Close { clean: true };
+            }
+            let will =
+                will.and_then(|will| Message::new(will.topic, will.payload, will.qos, will.retain));
+            info!("mqtt client {} connected", client_id);
+            broker.connect(slot, client_id, will);
+            *connected = true;
+            Packet::ConnAck {
+                session_present: false,
+                return_code: CONNACK_ACCEPTED,
+            }
+        }
+        // anything but a single connect first is a protocol violation
+        Packet::Connect { .. } => return Next::Close { clean: false },
+        _ if !*connected => return Next::Close { clean: false },
+        Packet::Publish {
+            topic,
+            payload,
+            qos,
+            retain,
+            packet_id,
+            ..
+        } => {
+            if qos > 1 {
+                warn!("mqtt qos 2 is not supported");
+                return Next::Close { clean: false };
+            }
+            match Message::new(topic, payload, qos, retain) {
+                Some(message) => broker.publish(message),
+                None => warn!("dropping oversized message on {}", topic),
+            }
+            match packet_id {
+                Some(packet_id) => Packet::PubAck { packet_id },
+                None => return Next::Continue,
+            }
+        }
+        Packet::Subscribe { packet_id, filters } => {
+            let return_codes = broker.subscribe_all(slot, filters);
+            // a subscribe without filters is a protocol violation
+            if return_codes.is_empty() {
+                return Next::Close { clean: false };
+            }
+            let suback = Packet::SubAck {
+                packet_id,
+                return_codes: &return_codes,
+            };
+            // the suback borrows the local return codes, so it is written here
+            if !write(socket, &suback).await {
+                return Next::Close { clean: false };
+            }
+            return Next::Continue;
+        }
+        Packet::Unsubscribe { packet_id, filters } => {
+            for (filter, _) in filters.iter() {
+                broker.unsubscribe(slot, filter);
+            }
+            Packet::UnsubAck { packet_id }
+        }
+        Packet::PingReq => Packet::PingResp,
+        Packet::PubAck { .. } => return Next::Continue,
+        Packet::Disconnect => return Next::Close { clean: true },
+        _ => return Next::Close { clean: false },
+    };
+    if !write(socket, &reply).await {
+        return Next::Close { clean: false };
+    }
+    Next::Continue
+}
+
+async fn write(socket: &mut TcpSocket<'_>, packet: &Packet<'_>) -> bool {
+    let Ok(encoded) = packet.encode() else {
+        warn!("mqtt packet does not fit its buffer");
+        return false;
+    };
+    socket.write_all(&encoded).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::subscribe_filters;
+    use std::vec::Vec;
+
+    fn message(topic: &str, payload: &[u8], qos: u8, retain: bool) -> Message {
+        Message::new(topic, payload, qos, retain).unwrap()
+    }
+
+    /// the messages queued for `slot`, as topic, payload, qos and retain
+    fn queued(broker: &MqttBroker, slot: usize) -> Vec<(Topic, Vec<u8>, u8, bool)> {
+        core::iter::from_fn(|| broker.outboxes[slot].try_receive().ok())
+            .map(|m| (m.topic, m.payload.to_vec(), m.qos, m.retain))
+            .collect()
+    }
+
+    fn topic(topic: &str) -> Topic {
+        Topic::try_from(topic).unwrap()
+    }
+
+    #[test]
+    fn retained_messages_reach_later_subscribers() {
+        let broker = MqttBroker::new();
+        broker.publish(message("tele/bulb/LWT", b"Online", 1, true));
+        broker.publish(message("tele/other/LWT", b"Offline", 0, true));
+        broker.connect(0, "phone", None);
+        assert_eq!(broker.subscribe(0, "tele/bulb/+", 1), 1);
+        assert_eq!(
+            queued(&broker, 0),
+            [(topic("tele/bulb/LWT"), b"Online".to_vec(), 1, true)]
+        );
+        // once subscribed, messages arrive as published, not retained
+        broker.publish(message("tele/bulb/LWT", b"Offline", 0, true));
+        assert_eq!(
+            queued(&broker, 0),
+            [(topic("tele/bulb/LWT"), b"Offline".to_vec(), 0, false)]
+        );
+        // the newer retained message replaced the older one
+        broker.connect(1, "laptop", None);
+        broker.subscribe(1, "tele/#", 0);
+        assert_eq!(
+            queued(&broker, 1),
+            [
+                (topic("tele/other/LWT"), b"Offline".to_vec(), 0, true),
+                (topic("tele/bulb/LWT"), b"Offline".to_vec(), 0, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_empty_retained_message_clears_the_topic() {
+        let broker = MqttBroker::new();
+        broker.publish(message("tele/bulb/LWT", b"Online", 0, true));
+        broker.connect(0, "phone", None);
+        broker.subscribe(0, "tele/bulb/LWT", 0);
+        queued(&broker, 0);
+        broker.publish(message("tele/bulb/LWT", b"", 0, true));
+        // subscribers still get the empty message
+        assert_eq!(
+            queued(&broker, 0),
+            [(topic("tele/bulb/LWT"), Vec::new(), 0, false)]
+        );
+        broker.connect(1, "laptop", None);
+        broker.subscribe(1, "tele/bulb/LWT", 0);
+        assert!(queued(&broker, 1).is_empty());
+    }
+
+    #[test]
+    fn publishes_the_will_only_on_an_unclean_disconnect() {
+        let broker = MqttBroker::new();
+        broker.connect(LOCAL_SLOT, "magic-markers", None);
+        broker.subscribe(LOCAL_SLOT, "tele/+/LWT", 0);
+        let will = message("tele/bulb/LWT", b"Offline", 0, true);
+
+        broker.connect(0, "bulb", Some(will.clone()));
+        broker.disconnect(0, false);
+        assert!(queued(&broker, LOCAL_SLOT).is_empty());
+
+        broker.connect(0, "bulb", Some(will));
+        broker.disconnect(0, true);
+        assert_eq!(
+            queued(&broker, LOCAL_SLOT),
+            [(topic("tele/bulb/LWT"), b"Offline".to_vec(), 0, false)]
+        );
+        // a retained will stays for later subscribers
+        broker.connect(1, "phone", None);
+        broker.subscribe(1, "tele/bulb/LWT", 0);
+        assert_eq!(queued(&broker, 1).len(), 1);
+    }
+
+    #[test]
+    fn delivers_at_the_lower_of_the_published_and_granted_qos() {
+        let broker = MqttBroker::new();
+        broker.connect(0, "qos0", None);
+        broker.connect(1, "qos1", None);
+        assert_eq!(broker.subscribe(0, "cmnd/#", 0), 0);
+        // qos 2 is granted as qos 1
+        assert_eq!(broker.subscribe(1, "cmnd/#", 2), 1);
+
+        broker.publish(message("cmnd/bulb/Power", b"ON", 1, false));
+        assert_eq!(queued(&broker, 0)[0].2, 0);
+        assert_eq!(queued(&broker, 1)[0].2, 1);
+        broker.publish(message("cmnd/bulb/Power", b"OFF", 0, false));
+        assert_eq!(queued(&broker, 0)[0].2, 0);
+        assert_eq!(queued(&broker, 1)[0].2, 0);
+
+        // overlapping subscriptions deliver once, at the highest granted qos
+        broker.subscribe(0, "cmnd/bulb/+", 1);
+        broker.publish(message("cmnd/bulb/Power", b"ON", 1, false));
+        assert_eq!(
+            queued(&broker, 0),
+            [(topic("cmnd/bulb/Power"), b"ON".to_vec(), 1, false)]
+        );
+    }
+
+    #[test]
+    fn answers_every_filter_of_a_subscribe() {
+        let broker = MqttBroker::new();
+        broker.connect(0, "phone", None);
+        let filters: Vec<_> = (0..MQTT_MAX_SUBSCRIPTIONS + 2)
+            .map(|i| std::format!("stat/bulb{}/RESULT", i))
+            .collect();
+        let mut filters: Vec<_> = filters.iter().map(|filter| (filter.as_str(), 1)).collect();
+        filters.push(("stat/#/RESULT", 0));
+        let raw = subscribe_filters(&filters).unwrap();
+        let return_codes = broker.subscribe_all(0, Filters::subscribe(&raw));
+        assert_eq!(return_codes.len(), filters.len());
+        assert!(return_codes[..MQTT_MAX_SUBSCRIPTIONS]
+            .iter()
+            .all(|&code| code == 1));
+        assert!(return_codes[MQTT_MAX_SUBSCRIPTIONS..]
+            .iter()
+            .all(|&code| code == SUBACK_FAILURE));
+    }
+
+    #[test]
+    fn a_reconnecting_client_takes_over_its_session() {
+        let broker = MqttBroker::new();
+        broker.connect(LOCAL_SLOT, "magic-markers", None);
+        broker.subscribe(LOCAL_SLOT, "tele/+/LWT", 0);
+        let will = message("tele/bulb/LWT", b"Offline", 0, false);
+        broker.connect(0, "bulb", Some(will.clone()));
+        broker.subscribe(0, "cmnd/bulb/#", 0);
+        broker.connect(1, "other", None);
+
+        broker.connect(2, "bulb", Some(will));
+        assert!(broker.taken_over[0].signaled());
+        assert!(!broker.taken_over[1].signaled());
+        // the old connection closing publishes no will, and gets no more messages
+        broker.disconnect(0, true);
+        assert!(queued(&broker, LOCAL_SLOT).is_empty());
+        broker.publish(message("cmnd/bulb/Power", b"ON", 0, false));
+        assert!(queued(&broker, 0).is_empty());
+
+        // the next client in the old slot starts without the takeover
+        broker.connect(0, "phone", None);
+        assert!(!broker.taken_over[0].signaled());
+    }
+
+    #[test]
+    fn network_clients_never_take_over_the_firmware() {
+        let broker = MqttBroker::new();
+        broker.connect(LOCAL_SLOT, "magic-markers", None);
+        broker.subscribe(LOCAL_SLOT, "tele/+/LWT", 0);
+        broker.connect(0, "magic-markers", None);
+        assert!(!broker.taken_over[LOCAL_SLOT].signaled());
+        broker.publish(message("tele/bulb/LWT", b"Online", 0, false));
+        assert_eq!(queued(&broker, LOCAL_SLOT).len(), 1);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::marker_color::MarkerColor;
+use core::fmt::Write;
+use heapless::String;
+
+/// first page of ntag21x user memory, where the tlv blocks start
+pub const NTAG_USER_START_PAGE: u8 = 4;
+/// bytes returned by a single ntag READ command (four pages)
+pub const NTAG_READ_SIZE: usize = 16;
+pub const NTAG_PAGE_SIZE: usize = 4;
+/// size of the buffer filled by `encode_color_message`
+pub const COLOR_MESSAGE_SIZE: usize = 32;
+
+const TLV_NULL: u8 = 0x00;
+const TLV_NDEF_MESSAGE: u8 = 0x03;
+const TLV_TERMINATOR: u8 = 0xfe;
+
+const TNF_WELL_KNOWN: u8 = 0x01;
+const TNF_MIME: u8 = 0x02;
+const RECORD_TYPE_TEXT: &[u8] = b"T";
+
+const FLAG_MESSAGE_BEGIN: u8 = 0x80;
+const FLAG_SHORT_RECORD: u8 = 0x10;
+const FLAG_ID_LENGTH: u8 = 0x08;
+const FLAG_MESSAGE_END: u8 = 0x40;
+
+/// what the tlv blocks read so far say about the ndef message
+#[derive(Debug, PartialEq)]
+pub enum TlvSearch {
+    /// offset and length of the message within the memory searched. the message
+    /// itself may extend past its end
+    Message(usize, usize),
+    /// a terminator came before any ndef message tlv
+    NoMessage,
+    /// the tlvs continue past the end of the memory read so far
+    NeedMore,
+}
+
+/// finds the ndef message tlv in ntag user memory, skipping the lock control,
+/// memory control and other tlvs before it
+pub fn find_message(memory: &[u8]) -> TlvSearch {
+    let byte = |index: usize| memory.get(index).copied();
+    let mut offset = 0;
+    loop {
+        let Some(tlv_type) = byte(offset) else {
+            return TlvSearch::NeedMore;
+        };
+        match tlv_type {
+            TLV_NULL => {
+                offset += 1;
+                continue;
+            }
+            TLV_TERMINATOR => return TlvSearch::NoMessage,
+            _ => {}
+        }
+        let (length, header_len) = match byte(offset + 1) {
+            None => return TlvSearch::NeedMore,
+            Some(0xff) => match (byte(offset + 2), byte(offset + 3)) {
+                (Some(high), Some(low)) => (u16::from_be_bytes([high, low]) as usize, 4),
+                _ => return TlvSearch::NeedMore,
+            },
+            Some(length) => (length as usize, 2),
+        };
+        if tlv_type == TLV_NDEF_MESSAGE {
+            return TlvSearch::Message(offset + header_len, length);
+        }
+        offset += header_len + length;
+    }
+}
+
+/// parses the records of an ndef message and returns the first marker color found
+/// in a text or mime record
+pub fn parse_message_color(message: &[u8]) -> Option<MarkerColor> {
+    let mut offset = 0;
+    while offset < message.len() {
+        let header = message[offset];
+        let type_len = *message.get(offset + 1)? as usize;
+        let (payload_len, mut cursor) = if header & FLAG_SHORT_RECORD != 0 {
+            (*message.get(offset + 2)? as usize, offset + 3)
+        } else {
+            let bytes = message.get(offset + 2..offset + 6)?;
+            let length = u32::from_be_bytes(bytes.try_into().ok()?);
+            (length as usize, offset + 6)
+        };
+        let id_len = if header & FLAG_ID_LENGTH != 0 {
+            cursor += 1;
+            *message.get(cursor - 1)? as usize
+        } else {
+            0
+        };
+        // lengths come from the tag, so a record may claim to run past any buffer
+        let record_type = message.get(cursor..cursor.checked_add(type_len)?)?;
+        cursor = cursor.checked_add(type_len + id_len)?;
+        let payload_end = cursor.checked_add(payload_len)?;
+        let payload = message.get(cursor..payload_end)?;
+
+        let color = match header & 0x07 {
+            TNF_WELL_KNOWN if record_type == RECORD_TYPE_TEXT => text_record_content(payload)
+                .and_then(|text| core::str::from_utf8(text).ok())
+                .and_then(parse_color),
+            TNF_MIME => core::str::from_utf8(payload).ok().and_then(parse_color),
+            _ => None,
+        };
+        if color.is_some() || header & FLAG_MESSAGE_END != 0 {
+            return color;
+        }
+        offset = payload_end;
+    }
+    None
+}
+
+/// encodes an ndef message tlv holding a single text record with the color as
+/// `hsb:h,s,b`, followed by a terminator tlv
+///
+/// returns the buffer and the number of bytes to write, rounded up to whole pages.
+pub fn encode_color_message(color: &MarkerColor) -> ([u8; COLOR_MESSAGE_SIZE], usize) {
+    let (h, s, b) = color.hsb();
+    let mut text: String<16> = String::new();
+    // at most "hsb:360,100,100", which always fits
+    let _ = write!(text, "hsb:{},{},{}", h, s, b);
+    let language = b"en";
+    let payload_len = 1 + language.len() + text.len();
+    let record_len = 4 + payload_len;
+
+    let mut message = [0u8; COLOR_MESSAGE_SIZE];
+    let header = [
+        TLV_NDEF_MESSAGE,
+        record_len as u8,
+        FLAG_MESSAGE_BEGIN | FLAG_MESSAGE_END | FLAG_SHORT_RECORD | TNF_WELL_KNOWN,
+        RECORD_TYPE_TEXT.len() as u8,
+        payload_len as u8,
+        RECORD_TYPE_TEXT[0],
+        language.len() as u8,
+    ];
+    let mut len = 0;
+    for part in [&header[..], language, text.as_bytes(), &[TLV_TERMINATOR]] {
+        message[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    }
+    (message, len.div_ceil(NTAG_PAGE_SIZE) * NTAG_PAGE_SIZE)
+}
+
+/// strips the status byte and language code from a text record payload
+fn text_record_content(payload: &[u8]) -> Option<&[u8]> {
+    let language_len = (*payload.first()? & 0x3f) as usize;
+    payload.get(1 + language_len..)
+}
+
+/// parses a color payload written to a tag
+///
+/// accepts `color:#rrggbb`, `#rrggbb` and `hsb:h,s,b` (hue 0-360, saturation and
+/// brightness 0-100), ignoring surrounding whitespace and case.
+pub fn parse_color(text: &str) -> Option<MarkerColor> {
+    let text = text.trim();
+    if let Some(hsb) = strip_prefix_ignore_case(text, "hsb:") {
+        let mut parts = hsb.split(',').map(|part| part.trim());
+        let h: u16 = parts.next()?.parse().ok()?;
+        let s: u8 = parts.next()?.parse().ok()?;
+        let b: u8 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || h > 360 || s > 100 || b > 100 {
+            return None;
+        }
+        return Some(MarkerColor::Custom(h % 360, s, b));
+    }
+
+    let hex = strip_prefix_ignore_case(text, "color:")
+        .unwrap_or(text)
+        .trim();
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some(MarkerColor::from_rgb(
+        (rgb >> 16) as u8,
+        (rgb >> 8) as u8,
+        rgb as u8,
+    ))
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &text[prefix.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_a_written_color() {
+        let color = MarkerColor::Custom(120, 80, 60);
+        let (memory, _) = encode_color_message(&color);
+        let TlvSearch::Message(offset, len) = find_message(&memory) else {
+            panic!("no message found");
+        };
+        let parsed = parse_message_color(&memory[offset..offset + len]).unwrap();
+        assert_eq!(parsed.hsb(), color.hsb());
+    }
+
+    #[test]
+    fn skips_control_tlvs() {
+        let mut memory = [0u8; 32];
+        // lock control and memory control tlvs, then a proprietary one filling the
+        // first read, so the message tlv starts in the second
+        memory[..16].copy_from_slice(&[
+            0x01, 0x03, 0xa0, 0x10, 0x44, 0x02, 0x03, 0x00, 0x00, 0x00, 0xfd, 0x04, 0, 0, 0, 0,
+        ]);
+        memory[16..18].copy_from_slice(&[TLV_NDEF_MESSAGE, 9]);
+        assert_eq!(find_message(&memory[..16]), TlvSearch::NeedMore);
+        assert_eq!(find_message(&memory), TlvSearch::Message(18, 9));
+    }
+
+    #[test]
+    fn stops_at_the_terminator() {
+        assert_eq!(
+            find_message(&[0x00, 0x00, TLV_TERMINATOR, 0x03]),
+            TlvSearch::NoMessage
+        );
+        // a three byte length cut off by the end of the read
+        assert_eq!(
+            find_message(&[TLV_NDEF_MESSAGE, 0xff, 0x01]),
+            TlvSearch::NeedMore
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_record_lengths() {
+        // a long record claiming a payload of 4 GiB
+        let message = [
+            FLAG_MESSAGE_BEGIN | FLAG_MESSAGE_END | TNF_WELL_KNOWN,
+            1,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+            b'T',
+            0x02,
+            b'e',
+            b'n',
+        ];
+        assert_eq!(parse_message_color(&message), None);
+    }
+
+    #[test]
+    fn parses_color_formats() {
+        assert_eq!(
+            parse_color(" HSB:10, 20, 30 "),
+            Some(MarkerColor::Custom(10, 20, 30))
+        );
+        assert_eq!(parse_color("hsb:361,0,0"), None);
+        assert!(parse_color("color:#FF0000").is_some());
+        assert!(parse_color("#00ff00").is_some());
+        assert_eq!(parse_color("#00ff0"), None);
+    }
+}
@@ -1,23 +1,39 @@
-use crate::constants::{GATEWAY_IP_ADDRESS, I2C_FREQUENCY_KHZ, RFID_I2C_ADDRESS};
+use crate::constants::GATEWAY_IP_ADDRESS;
+#[cfg(any(feature = "reader-mfrc522-i2c", feature = "reader-pn532"))]
+use crate::constants::I2C_FREQUENCY_KHZ;
+use crate::constants::NETWORK_SOCKETS;
+#[cfg(feature = "reader-pn532")]
+use crate::constants::PN532_I2C_ADDRESS;
+#[cfg(feature = "reader-mfrc522-i2c")]
+use crate::constants::RFID_I2C_ADDRESS;
+#[cfg(feature = "reader-mfrc522-spi")]
+use crate::constants::SPI_FREQUENCY_MHZ;
 use crate::mk_static;
+use crate::tag_reader::RfidReader;
 use core::net::Ipv4Addr;
 use core::str::FromStr;
 use defmt::{error, info};
 use embassy_net::{Ipv4Cidr, Runner, Stack, StaticConfigV4};
+#[cfg(any(feature = "reader-mfrc522-i2c", feature = "reader-pn532"))]
+use esp_hal::i2c::master::{Config as I2cConfig, I2c};
+#[cfg(feature = "reader-mfrc522-spi")]
+use esp_hal::spi::{
+    master::{Config as SpiConfig, Spi},
+    Mode,
+};
 use esp_hal::{
     gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull},
-    i2c::master::{Config, I2c},
     time::Rate,
     timer::{systimer::SystemTimer, timg::TimerGroup},
-    Blocking,
 };
+use esp_storage::FlashStorage;
 use esp_wifi::wifi::{WifiController, WifiDevice};
-use mfrc522::{comm::blocking::i2c::I2cInterface, Initialized, Mfrc522};
 
 pub struct Peripherals {
     pub led: Output<'static>,
     pub button: Input<'static>,
-    pub mfrc522: Mfrc522<I2cInterface<I2c<'static, Blocking>>, Initialized>,
+    pub reader: RfidReader,
+    pub flash: FlashStorage,
     pub wifi_controller: WifiController<'static>,
     pub network_runner: Runner<'static, WifiDevice<'static>>,
     pub network_stack: Stack<'static>,
@@ -53,7 +69,7 @@ impl Peripherals {
             device,
             config,
             mk_static!(
-                embassy_net::StackResources<3>,
+                embassy_net::StackResources<NETWORK_SOCKETS>,
                 embassy_net::StackResources::new()
             ),
             seed,
@@ -71,52 +87,77 @@ impl Peripherals {
         );
 
         // rfid reader
-        let sda = esp_peripherals.GPIO2;
-        let scl = esp_peripherals.GPIO1;
-        let mut i2c = match I2c::new(
-            esp_peripherals.I2C0,
-            Config::default().with_frequency(Rate::from_khz(I2C_FREQUENCY_KHZ)),
-        ) {
-            Ok(i2c) => {
-                info!("i2c initialized");
-                i2c
-            }
-            Err(e) => {
-                error!("i2c init error: {:?}", e);
-                panic!();
-            }
+        #[cfg(feature = "rfid-irq")]
+        let irq = Some(Input::new(
+            esp_peripherals.GPIO3,
+            InputConfig::default().with_pull(Pull::Up),
+        ));
+        #[cfg(not(feature = "rfid-irq"))]
+        let irq = None;
+        #[cfg(any(feature = "reader-mfrc522-i2c", feature = "reader-pn532"))]
+        let i2c = {
+            let sda = esp_peripherals.GPIO2;
+            let scl = esp_peripherals.GPIO1;
+            let i2c = match I2c::new(
+                esp_peripherals.I2C0,
+                I2cConfig::default().with_frequency(Rate::from_khz(I2C_FREQUENCY_KHZ)),
+            ) {
+                Ok(i2c) => {
+                    info!("i2c initialized");
+                    i2c
+                }
+                Err(e) => {
+                    error!("i2c init error: {:?}", e);
+                    panic!();
+                }
+            };
+            i2c.with_sda(sda).with_scl(scl).into_async()
         };
-        i2c = i2c.with_sda(sda).with_scl(scl);
-        let itf = I2cInterface::new(i2c, RFID_I2C_ADDRESS);
-        let mut mfrc522 = Mfrc522::new(itf).init().unwrap_or_else(|e| match e {
-            mfrc522::Error::Comm(c) => {
-                error!("mfrc522 comm error: {:?}", c);
-                panic!();
-            }
-            _ => {
-                error!("other mfrc522 init error");
-                panic!();
-            }
-        });
-        if let Ok(version) = mfrc522.version() {
-            info!("mfrc522 version: {:?}", version);
-        } else {
-            error!("mfrc522 version error");
-            panic!();
-        }
-        match mfrc522.set_antenna_gain(mfrc522::RxGain::DB48) {
-            Ok(()) => info!("antenna gain set"),
-            Err(_) => {
-                error!("failed to set antenna gain");
-                panic!();
-            }
-        }
-        info!("rfid reader initialized");
+        // each reader is brought up by the rfid task, which keeps retrying while it is
+        // unresponsive
+        #[cfg(feature = "reader-mfrc522-i2c")]
+        let reader = crate::mfrc522::Mfrc522::new(
+            crate::mfrc522::I2cInterface::new(i2c, RFID_I2C_ADDRESS),
+            irq,
+        );
+        #[cfg(feature = "reader-pn532")]
+        let reader = crate::pn532::Pn532::new(i2c, PN532_I2C_ADDRESS, irq);
+        #[cfg(feature = "reader-mfrc522-spi")]
+        let reader = {
+            let spi = match Spi::new(
+                esp_peripherals.SPI2,
+                SpiConfig::default()
+                    .with_frequency(Rate::from_mhz(SPI_FREQUENCY_MHZ))
+                    .with_mode(Mode::_0),
+            ) {
+                Ok(spi) => {
+                    info!("spi initialized");
+                    spi
+                }
+                Err(e) => {
+                    error!("spi init error: {:?}", e);
+                    panic!();
+                }
+            };
+            let spi = spi
+                .with_sck(esp_peripherals.GPIO21)
+                .with_mosi(esp_peripherals.GPIO22)
+                .with_miso(esp_peripherals.GPIO23)
+                .into_async();
+            let cs = Output::new(esp_peripherals.GPIO18, Level::High, OutputConfig::default());
+            let device =
+                embedded_hal_bus::spi::ExclusiveDevice::new(spi, cs, embassy_time::Delay).unwrap();
+            crate::mfrc522::Mfrc522::new(crate::mfrc522::SpiInterface::new(device), irq)
+        };
+
+        // flash storage for the marker registry
+        let flash = FlashStorage::new();
 
         Self {
             led,
             button,
-            mfrc522,
+            reader,
+            flash,
             wifi_controller: ctrl,
             network_runner: runner,
             network_stack: stack,
@@ -0,0 +1,384 @@
+use crate::constants::{PN532_RESPONSE_TIMEOUT_MS, RFID_FIELD_RESET_MS};
+use crate::tag_reader::{Error, TagReader};
+use crate::tag_uid::TagUid;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+
+const HOST_TO_PN532: u8 = 0xd4;
+const PN532_TO_HOST: u8 = 0xd5;
+const FRAME_START: [u8; 3] = [0x00, 0x00, 0xff];
+const ACK_FRAME: [u8; 6] = [0x00, 0x00, 0xff, 0x00, 0xff, 0x00];
+const STATUS_READY: u8 = 0x01;
+/// large enough for every command and answer used here
+const FRAME_SIZE: usize = 32;
+
+const CMD_GET_FIRMWARE_VERSION: u8 = 0x02;
+const CMD_SAM_CONFIGURATION: u8 = 0x14;
+const CMD_RF_CONFIGURATION: u8 = 0x32;
+const CMD_IN_DATA_EXCHANGE: u8 = 0x40;
+const CMD_IN_COMMUNICATE_THRU: u8 = 0x42;
+const CMD_IN_LIST_PASSIVE_TARGET: u8 = 0x4a;
+const CMD_IN_RELEASE: u8 = 0x52;
+
+const IC_PN532: u8 = 0x32;
+const BAUD_RATE_106_TYPE_A: u8 = 0x00;
+const RF_CONFIG_FIELD: u8 = 0x01;
+const RF_CONFIG_MAX_RETRIES: u8 = 0x05;
+
+const PICC_READ: u8 = 0x30;
+const PICC_HLTA: u8 = 0x50;
+const PICC_WRITE: u8 = 0xa2;
+
+/// async driver for the nxp pn532 over i2c
+///
+/// the pn532 handles anticollision and select itself. halting sends a real HLTA
+/// through it, so listing targets, which only idle tags answer, turns up the next
+/// tag on the reader.
+pub struct Pn532<I2C, IRQ> {
+    i2c: I2C,
+    address: u8,
+    irq: Option<IRQ>,
+}
+
+impl<I2C: I2c, IRQ: Wait> Pn532<I2C, IRQ> {
+    /// creates the driver, `irq` being the input wired to the reader's irq pin if any
+    pub fn new(i2c: I2C, address: u8, irq: Option<IRQ>) -> Self {
+        Self { i2c, address, irq }
+    }
+
+    /// lists a single type a target, returning `Err(Error::Timeout)` when there is none
+    async fn list_target(&mut self) -> Result<TagUid, Error> {
+        let mut response = [0u8; 6 + TagUid::MAX_LEN];
+        let len = self
+            .command(
+                CMD_IN_LIST_PASSIVE_TARGET,
+                &[1, BAUD_RATE_106_TYPE_A],
+                &mut response,
+            )
+            .await?;
+        // number of targets, target number, sens_res, sel_res, uid length, uid
+        if len < 1 || response[0] == 0 {
+            return Err(Error::Timeout);
+        }
+        let uid_len = response[5] as usize;
+        let uid = response.get(6..6 + uid_len).ok_or(Error::Protocol)?;
+        TagUid::new(uid).ok_or(Error::Protocol)
+    }
+
+    /// sends `frame` to the listed tag and receives its answer into `buffer`
+    async fn data_exchange(&mut self, frame: &[u8], buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut params = [0u8; 1 + 6];
+        params[0] = 1;
+        params[1..=frame.len()].copy_from_slice(frame);
+        let mut response = [0u8; 1 + 16];
+        let len = self
+            .command(CMD_IN_DATA_EXCHANGE, &params[..=frame.len()], &mut response)
+            .await?;
+        if len < 1 {
+            return Err(Error::IncompleteFrame);
+        }
+        match response[0] & 0x3f {
+            0x00 => {}
+            0x01 => return Err(Error::Timeout),
+            0x02 => return Err(Error::Crc),
+            0x03 => return Err(Error::Parity),
+            0x04 => return Err(Error::Collision),
+            _ => return Err(Error::Protocol),
+        }
+        let received = len - 1;
+        if received > buffer.len() {
+            return Err(Error::BufferOverflow);
+        }
+        buffer[..received].copy_from_slice(&response[1..len]);
+        Ok(received)
+    }
+
+    /// sends a command frame, waits for its ack and answer, and copies the answer's
+    /// data into `response`, returning its length
+    async fn command(
+        &mut self,
+        command: u8,
+        params: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut frame = [0u8; FRAME_SIZE];
+        let len = encode_frame(command, params, &mut frame);
+        self.i2c
+            .write(self.address, &frame[..len])
+            .await
+            .map_err(|_| Error::Comm)?;
+
+        // every read starts with the status byte
+        let mut ack = [0u8; 1 + ACK_FRAME.len()];
+        self.wait_ready().await?;
+        self.i2c
+            .read(self.address, &mut ack)
+            .await
+            .map_err(|_| Error::Comm)?;
+        if ack[1..] != ACK_FRAME {
+            return Err(Error::Protocol);
+        }
+
+        let mut answer = [0u8; 1 + FRAME_SIZE];
+        let answer_len = (1 + 9 + response.len()).min(answer.len());
+        self.wait_ready().await?;
+        self.i2c
+            .read(self.address, &mut answer[..answer_len])
+            .await
+            .map_err(|_| Error::Comm)?;
+        decode_frame(command, &answer[1..answer_len], response)
+    }
+
+    /// waits for the pn532 to have a frame ready, on its irq line if one is wired up
+    /// or by polling its status byte otherwise
+    async fn wait_ready(&mut self) -> Result<(), Error> {
+        let wait = async {
+            loop {
+                match self.irq.as_mut() {
+                    Some(irq) => irq.wait_for_low().await.map_err(|_| Error::Comm)?,
+                    None => Timer::after(Duration::from_millis(1)).await,
+                }
+                let mut status = [0u8; 1];
+                self.i2c
+                    .read(self.address, &mut status)
+                    .await
+                    .map_err(|_| Error::Comm)?;
+                if status[0] & STATUS_READY != 0 {
+                    return Ok(());
+                }
+            }
+        };
+        with_timeout(Duration::from_millis(PN532_RESPONSE_TIMEOUT_MS), wait)
+            .await
+            .map_err(|_| Error::Unresponsive)?
+    }
+}
+
+impl<I2C: I2c, IRQ: Wait> TagReader for Pn532<I2C, IRQ> {
+    async fn init(&mut self) -> Result<u8, Error> {
+        // the first frame after power up only wakes the pn532 from its low power mode
+        let version = match self.version().await {
+            Ok(version) => version,
+            Err(_) => {
+                Timer::after(Duration::from_millis(10)).await;
+                self.version().await?
+            }
+        };
+        // normal mode, no secure access module
+        self.command(CMD_SAM_CONFIGURATION, &[0x01, 0x14, 0x01], &mut [])
+            .await?;
+        // a single activation attempt, so listing targets returns at once on an empty field
+        self.command(
+            CMD_RF_CONFIGURATION,
+            &[RF_CONFIG_MAX_RETRIES, 0xff, 0x01, 0x00],
+            &mut [],
+        )
+        .await?;
+        Ok(version)
+    }
+
+    async fn version(&mut self) -> Result<u8, Error> {
+        // ic, version, revision, supported protocols
+        let mut firmware = [0u8; 4];
+        let len = self
+            .command(CMD_GET_FIRMWARE_VERSION, &[], &mut firmware)
+            .await?;
+        if len != firmware.len() || firmware[0] != IC_PN532 {
+            return Err(Error::Protocol);
+        }
+        Ok(firmware[1])
+    }
+
+    async fn poll(&mut self) -> Result<TagUid, Error> {
+        self.list_target().await
+    }
+
+    async fn halt(&mut self) -> Result<(), Error> {
+        // a halted tag does not answer, so the pn532 reports a timeout on success
+        let mut status = [0u8; 1];
+        self.command(CMD_IN_COMMUNICATE_THRU, &[PICC_HLTA, 0x00], &mut status)
+            .await?;
+        if status[0] & 0x3f != 0x01 {
+            return Err(Error::Protocol);
+        }
+        // drop the halted tag from the pn532's own target list
+        self.command(CMD_IN_RELEASE, &[0], &mut [0u8; 1]).await?;
+        Ok(())
+    }
+
+    async fn reset_field(&mut self) -> Result<(), Error> {
+        self.command(CMD_RF_CONFIGURATION, &[RF_CONFIG_FIELD, 0x00], &mut [])
+            .await?;
+        Timer::after(Duration::from_millis(RFID_FIELD_RESET_MS)).await;
+        self.command(CMD_RF_CONFIGURATION, &[RF_CONFIG_FIELD, 0x01], &mut [])
+            .await?;
+        Timer::after(Duration::from_millis(RFID_FIELD_RESET_MS)).await;
+        Ok(())
+    }
+
+    async fn read_pages(&mut self, page: u8) -> Result<[u8; 16], Error> {
+        let mut data = [0u8; 16];
+        let received = self.data_exchange(&[PICC_READ, page], &mut data).await?;
+        if received != data.len() {
+            return Err(Error::IncompleteFrame);
+        }
+        Ok(data)
+    }
+
+    async fn write_page(&mut self, page: u8, data: [u8; 4]) -> Result<(), Error> {
+        let frame = [PICC_WRITE, page, data[0], data[1], data[2], data[3]];
+        self.data_exchange(&frame, &mut []).await?;
+        Ok(())
+    }
+}
+
+/// two's complement of the byte sum, so data and checksum add up to zero
+fn checksum(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// writes the information frame carrying `command` to the pn532 into `frame`,
+/// returning its length
+fn encode_frame(command: u8, params: &[u8], frame: &mut [u8; FRAME_SIZE]) -> usize {
+    let len = params.len() + 2;
+    frame[..3].copy_from_slice(&FRAME_START);
+    frame[3] = len as u8;
+    frame[4] = (len as u8).wrapping_neg();
+    frame[5] = HOST_TO_PN532;
+    frame[6] = command;
+    frame[7..7 + params.len()].copy_from_slice(params);
+    frame[5 + len] = checksum(&frame[5..5 + len]);
+    // postamble
+    frame[6 + len] = 0x00;
+    7 + len
+}
+
+/// checks the pn532's answer to `command`, read without its status byte, and copies
+/// its data into `response`, returning its length
+fn decode_frame(command: u8, answer: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+    if answer.len() < 5 || answer[..3] != FRAME_START || answer[3].wrapping_add(answer[4]) != 0 {
+        return Err(Error::Protocol);
+    }
+    let len = answer[3] as usize;
+    let body = answer.get(5..5 + len + 1).ok_or(Error::BufferOverflow)?;
+    if len < 2 || body[0] != PN532_TO_HOST || body[1] != command + 1 {
+        return Err(Error::Protocol);
+    }
+    if checksum(&body[..len]) != body[len] {
+        return Err(Error::Crc);
+    }
+    let data = &body[2..len];
+    if data.len() > response.len() {
+        return Err(Error::BufferOverflow);
+    }
+    response[..data.len()].copy_from_slice(data);
+    Ok(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_firmware_version_frame() {
+        let mut frame = [0u8; FRAME_SIZE];
+        let len = encode_frame(CMD_GET_FIRMWARE_VERSION, &[], &mut frame);
+        assert_eq!(
+            frame[..len],
+            [0x00, 0x00, 0xff, 0x02, 0xfe, 0xd4, 0x02, 0x2a, 0x00]
+        );
+    }
+
+    #[test]
+    fn encodes_hlta_through_frame() {
+        let mut frame = [0u8; FRAME_SIZE];
+        let len = encode_frame(CMD_IN_COMMUNICATE_THRU, &[PICC_HLTA, 0x00], &mut frame);
+        assert_eq!(
+            frame[..len],
+            [0x00, 0x00, 0xff, 0x04, 0xfc, 0xd4, 0x42, 0x50, 0x00, 0x9a, 0x00]
+        );
+        // length and its checksum, then data and its checksum, each sum to zero
+        assert_eq!(frame[3].wrapping_add(frame[4]), 0);
+        assert_eq!(checksum(&frame[5..len - 1]), 0);
+    }
+
+    #[test]
+    fn decodes_firmware_version_answer() {
+        let answer = [
+            0x00, 0x00, 0xff, 0x06, 0xfa, 0xd5, 0x03, 0x32, 0x01, 0x06, 0x07, 0xe8, 0x00,
+        ];
+        let mut firmware = [0u8; 4];
+        assert_eq!(
+            decode_frame(CMD_GET_FIRMWARE_VERSION, &answer, &mut firmware),
+            Ok(4)
+        );
+        assert_eq!(firmware, [IC_PN532, 0x01, 0x06, 0x07]);
+    }
+
+    #[test]
+    fn rejects_bad_length_checksum() {
+        let answer = [
+            0x00, 0x00, 0xff, 0x06, 0xfb, 0xd5, 0x03, 0x32, 0x01, 0x06, 0x07, 0xe8, 0x00,
+        ];
+        let mut firmware = [0u8; 4];
+        assert_eq!(
+            decode_frame(CMD_GET_FIRMWARE_VERSION, &answer, &mut firmware),
+            Err(Error::Protocol)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_data_checksum() {
+        let answer = [
+            0x00, 0x00, 0xff, 0x06, 0xfa, 0xd5, 0x03, 0x32, 0x01, 0x06, 0x07, 0xe9, 0x00,
+        ];
+        let mut firmware = [0u8; 4];
+        assert_eq!(
+            decode_frame(CMD_GET_FIRMWARE_VERSION, &answer, &mut firmware),
+            Err(Error::Crc)
+        );
+    }
+
+    #[test]
+    fn rejects_answer_to_another_command_or_direction() {
+        let answer = [
+            0x00, 0x00, 0xff, 0x06, 0xfa, 0xd5, 0x03, 0x32, 0x01, 0x06, 0x07, 0xe8, 0x00,
+        ];
+        let mut firmware = [0u8; 4];
+        assert_eq!(
+            decode_frame(CMD_SAM_CONFIGURATION, &answer, &mut firmware),
+            Err(Error::Protocol)
+        );
+        // the host's own frame echoed back has the wrong frame identifier
+        let mut frame = [0u8; FRAME_SIZE];
+        let len = encode_frame(CMD_GET_FIRMWARE_VERSION, &[], &mut frame);
+        assert_eq!(
+            decode_frame(CMD_GET_FIRMWARE_VERSION, &frame[..len], &mut firmware),
+            Err(Error::Protocol)
+        );
+    }
+
+    #[test]
+    fn decodes_hlta_timeout_status() {
+        // InCommunicateThru answers with only a status byte, 0x01 for a timeout
+        let answer = [0x00, 0x00, 0xff, 0x03, 0xfd, 0xd5, 0x43, 0x01, 0xe7, 0x00];
+        let mut status = [0u8; 1];
+        assert_eq!(
+            decode_frame(CMD_IN_COMMUNICATE_THRU, &answer, &mut status),
+            Ok(1)
+        );
+        assert_eq!(status, [0x01]);
+    }
+
+    #[test]
+    fn rejects_ack_read_as_answer() {
+        let mut firmware = [0u8; 4];
+        assert_eq!(
+            decode_frame(CMD_GET_FIRMWARE_VERSION, &ACK_FRAME, &mut firmware),
+            Err(Error::Protocol)
+        );
+    }
+}
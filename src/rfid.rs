@@ -1,45 +1,363 @@
+use crate::constants::{
+    MARKER_PRESENCE_CHECK_INTERVAL_MS, MARKER_REMOVAL_GRACE_PERIOD_MS, MAX_PRESENT_MARKERS,
+    NDEF_READ_LIMIT, RFID_HEALTH_CHECK_INTERVAL_MS, RFID_INIT_BACKOFF_MAX_MS,
+    RFID_INIT_BACKOFF_MIN_MS, RFID_MAX_CONSECUTIVE_ERRORS, RFID_POLL_INTERVAL_MS,
+};
 use crate::marker_color::MarkerColor;
-use crate::state::{StateCommand, StateSignal};
-use defmt::{debug, info};
-use embassy_time::{Duration, Timer};
-use esp_hal::{i2c::master::I2c, Blocking};
-use mfrc522::{comm::blocking::i2c::I2cInterface, Initialized, Mfrc522, Uid};
+use crate::marker_registry::{Marker, MarkerOptions, MarkerRegistryMutex};
+use crate::ndef::{
+    encode_color_message, find_message, parse_message_color, TlvSearch, NTAG_PAGE_SIZE,
+    NTAG_READ_SIZE, NTAG_USER_START_PAGE,
+};
+use crate::state::{StateChannel, StateCommand};
+#[cfg(target_os = "none")]
+use crate::tag_reader::RfidReader;
+use crate::tag_reader::{Error, TagReader};
+use crate::tag_uid::TagUid;
+use defmt::{debug, info, warn, Format};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
 
+#[derive(Debug, Format, PartialEq, Clone)]
+pub enum ReaderMode {
+    /// show the color of tapped tags, from their ndef record or the registry
+    Read,
+    /// bind the next unknown tag to the given color and store it in the registry
+    Enroll(MarkerColor),
+    /// write the color as an ndef record to every blank tag placed on the reader
+    WriteTag(MarkerColor),
+}
+
+pub type ReaderModeSignal = Signal<NoopRawMutex, ReaderMode>;
+
+/// a tag resting on the reader, halted so polling only finds newly placed ones
+struct PresentTag {
+    uid: TagUid,
+    /// `None` for unknown and disabled tags, which are tracked but not shown
+    color: Option<MarkerColor>,
+    last_seen: Instant,
+}
+
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 pub async fn rfid_task(
-    mut mfrc522: Mfrc522<I2cInterface<I2c<'static, Blocking>>, Initialized>,
-    state_signal: &'static StateSignal,
+    reader: RfidReader,
+    marker_registry: &'static MarkerRegistryMutex,
+    reader_mode_signal: &'static ReaderModeSignal,
+    state_channel: &'static StateChannel,
+) {
+    run_reader(reader, marker_registry, reader_mode_signal, state_channel).await
+}
+
+/// polls the reader for tags and acts on them in the current reader mode, resetting
+/// the reader whenever it stops responding
+pub async fn run_reader<R: TagReader>(
+    mut reader: R,
+    marker_registry: &MarkerRegistryMutex,
+    reader_mode_signal: &ReaderModeSignal,
+    state_channel: &StateChannel,
 ) {
+    let mut mode = ReaderMode::Read;
+    // tag last programmed in write mode, so a failed write is not retried until the
+    // tag is swapped for another one
+    let mut last_written_uid: Option<TagUid> = None;
+    let mut present_tags: Vec<PresentTag, MAX_PRESENT_MARKERS> = Vec::new();
+    let mut last_inventory = Instant::now();
+    let mut consecutive_errors: u8 = 0;
+    let mut last_health_check = Instant::now();
+    start_reader(&mut reader, state_channel, false).await;
     loop {
-        if let Ok(atqa) = mfrc522.new_card_present() {
-            match mfrc522.select(&atqa) {
-                Ok(Uid::Double(inner)) => {
-                    if let Some(marker_color) = MarkerColor::from_uid(&inner) {
-                        info!("detected color: {}", marker_color);
-                        state_signal.signal(StateCommand::SetMarkerColor(marker_color));
+        if last_health_check.elapsed() >= Duration::from_millis(RFID_HEALTH_CHECK_INTERVAL_MS) {
+            last_health_check = Instant::now();
+            if let Err(e) = reader.version().await.and_then(check_version) {
+                warn!("rfid reader health check failed: {:?}", e);
+                consecutive_errors = RFID_MAX_CONSECUTIVE_ERRORS;
+            }
+        }
+        if consecutive_errors >= RFID_MAX_CONSECUTIVE_ERRORS {
+            warn!("rfid reader not responding, resetting it");
+            state_channel.send(StateCommand::ReaderFault(true)).await;
+            start_reader(&mut reader, state_channel, true).await;
+            consecutive_errors = 0;
+            last_health_check = Instant::now();
+            // tags may have been swapped while the reader was down
+            last_written_uid = None;
+            continue;
+        }
+
+        if let Some(new_mode) = reader_mode_signal.try_take() {
+            info!("reader mode: {}", new_mode);
+            mode = new_mode;
+            last_written_uid = None;
+            present_tags.clear();
+        }
+
+        let result = match mode.clone() {
+            ReaderMode::Read => {
+                let colors = present_colors(&present_tags);
+                let inventory = last_inventory.elapsed()
+                    >= Duration::from_millis(MARKER_PRESENCE_CHECK_INTERVAL_MS);
+                if inventory {
+                    last_inventory = Instant::now();
+                }
+                let result =
+                    scan_tags(&mut reader, marker_registry, &mut present_tags, inventory).await;
+                let new_colors = present_colors(&present_tags);
+                if new_colors != colors {
+                    state_channel
+                        .send(StateCommand::SetMarkerColors(new_colors))
+                        .await;
+                }
+                result
+            }
+            ReaderMode::Enroll(color) => match reader.poll().await {
+                Ok(uid) => {
+                    let marker = marker_registry.lock().await.lookup(&uid).cloned();
+                    if let Some(marker) = marker {
+                        debug!("marker already enrolled: {}", marker.name);
+                    } else {
+                        let marker = Marker::new(uid, color.name(), color.clone());
+                        let result = marker_registry.lock().await.upsert(marker);
+                        match &result {
+                            Ok(()) => info!("enrolled {} as {}", uid, color),
+                            Err(e) => warn!("failed to enroll marker: {:?}", e),
+                        }
+                        state_channel
+                            .send(StateCommand::EnrollmentFinished(result.is_ok()))
+                            .await;
+                        mode = ReaderMode::Read;
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            ReaderMode::WriteTag(color) => match reader.poll().await {
+                Ok(uid) if last_written_uid == Some(uid) => Ok(()),
+                Ok(uid) => {
+                    let marker = marker_registry.lock().await.lookup(&uid).cloned();
+                    if let Some(marker) = marker {
+                        debug!("not writing to enrolled marker: {}", marker.name);
+                    } else if let Some(existing) = read_ndef_color(&mut reader).await {
+                        debug!("not writing to tag with color: {}", existing);
                     } else {
-                        info!("unknown marker uid: {}", inner.as_bytes());
+                        let success = write_ndef_color(&mut reader, &color).await;
+                        if success {
+                            info!("wrote {} to tag {}", color, uid);
+                        } else {
+                            warn!("failed to write {} to tag {}", color, uid);
+                        }
+                        last_written_uid = Some(uid);
+                        state_channel.send(StateCommand::TagWritten(success)).await;
                     }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+        };
+        match result {
+            Err(e) if e.is_reader_fault() => consecutive_errors += 1,
+            _ => consecutive_errors = 0,
+        }
+        match result {
+            // nothing new on the reader
+            Ok(()) | Err(Error::Timeout) => {}
+            Err(e) => debug!("rfid poll error: {:?}", e),
+        }
+        Timer::after(Duration::from_millis(RFID_POLL_INTERVAL_MS)).await;
+    }
+}
+
+/// polls until no idle tag is left, reading the color of each newly placed one and
+/// halting every tag found so the next poll turns up another
+///
+/// with `inventory` the field is reset first, so every tag on the reader is found
+/// again, and tags that have been missing for longer than the grace period are
+/// dropped.
+async fn scan_tags<R: TagReader>(
+    reader: &mut R,
+    marker_registry: &MarkerRegistryMutex,
+    present_tags: &mut Vec<PresentTag, MAX_PRESENT_MARKERS>,
+    inventory: bool,
+) -> Result<(), Error> {
+    if inventory {
+        reader.reset_field().await?;
+    }
+    let mut result = Ok(());
+    // bounded in case a reader fails to halt tags, which would then be found forever
+    for _ in 0..=MAX_PRESENT_MARKERS {
+        let uid = match reader.poll().await {
+            Ok(uid) => uid,
+            Err(Error::Timeout) => break,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        if let Some(tag) = present_tags.iter_mut().find(|tag| tag.uid == uid) {
+            tag.last_seen = Instant::now();
+        } else {
+            let color = read_marker_color(reader, marker_registry, &uid).await;
+            let tag = PresentTag {
+                uid,
+                color,
+                last_seen: Instant::now(),
+            };
+            if present_tags.push(tag).is_err() {
+                warn!("too many tags on the reader, ignoring {}", uid);
+            }
+        }
+        if let Err(e) = reader.halt().await {
+            result = Err(e);
+            break;
+        }
+    }
+    if inventory {
+        present_tags.retain(|tag| {
+            let present =
+                tag.last_seen.elapsed() < Duration::from_millis(MARKER_REMOVAL_GRACE_PERIOD_MS);
+            if !present {
+                info!("marker removed: {}", tag.uid);
+            }
+            present
+        });
+    }
+    result
+}
+
+/// the color of a newly placed tag, from its ndef message or else the registry
+async fn read_marker_color<R: TagReader>(
+    reader: &mut R,
+    marker_registry: &MarkerRegistryMutex,
+    uid: &TagUid,
+) -> Option<MarkerColor> {
+    if let Some(color) = read_ndef_color(reader).await {
+        info!("detected ndef color: {}", color);
+        return Some(color);
+    }
+    match marker_registry.lock().await.lookup(uid) {
+        Some(marker) if marker.options.contains(MarkerOptions::DISABLED) => {
+            info!("ignoring disabled marker: {}", marker.name);
+            None
+        }
+        Some(marker) => {
+            info!("detected marker: {} ({})", marker.name, marker.color);
+            Some(marker.color.clone())
+        }
+        None => {
+            info!("unknown marker uid: {}", uid);
+            None
+        }
+    }
+}
+
+fn present_colors(present_tags: &[PresentTag]) -> Vec<MarkerColor, MAX_PRESENT_MARKERS> {
+    present_tags
+        .iter()
+        .filter_map(|tag| tag.color.clone())
+        .collect()
+}
+
+/// brings the reader up, retrying with exponential backoff for as long as it fails
+///
+/// a reader fault is reported on the first failure, and cleared once the reader is
+/// back up if it was reported, here or by the caller (`faulted`).
+async fn start_reader<R: TagReader>(
+    reader: &mut R,
+    state_channel: &StateChannel,
+    mut faulted: bool,
+) {
+    let mut backoff = RFID_INIT_BACKOFF_MIN_MS;
+    loop {
+        match init_reader(reader).await {
+            Ok(()) => {
+                info!("rfid reader initialized");
+                if faulted {
+                    state_channel.send(StateCommand::ReaderFault(false)).await;
+                }
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    "rfid reader init failed: {:?}, retrying in {}ms",
+                    e, backoff
+                );
+                if !faulted {
+                    state_channel.send(StateCommand::ReaderFault(true)).await;
+                    faulted = true;
                 }
-                Ok(_) => info!("wrong uid size"),
-                Err(e) => match e {
-                    mfrc522::Error::Collision => debug!("collision"),
-                    mfrc522::Error::Timeout => debug!("timeout"),
-                    mfrc522::Error::Comm(c) => debug!("invalid response {:?}", c),
-                    mfrc522::Error::IncompleteFrame => debug!("incomplete frame"),
-                    mfrc522::Error::BufferOverflow => debug!("buffer overflow"),
-                    mfrc522::Error::Crc => debug!("crc error"),
-                    mfrc522::Error::Protocol => debug!("protocol error"),
-                    mfrc522::Error::Bcc => debug!("bcc error"),
-                    mfrc522::Error::Nak => debug!("nak"),
-                    mfrc522::Error::NoRoom => debug!("no room"),
-                    mfrc522::Error::Overheating => debug!("overheating"),
-                    mfrc522::Error::Proprietary => debug!("proprietary error"),
-                    mfrc522::Error::Parity => debug!("parity error"),
-                    mfrc522::Error::Wr => debug!("write error"),
-                },
             }
         }
-        Timer::after(Duration::from_millis(10)).await;
+        Timer::after(Duration::from_millis(backoff)).await;
+        backoff = (backoff * 2).min(RFID_INIT_BACKOFF_MAX_MS);
+    }
+}
+
+/// resets and configures the reader, checking it answers with a sane version
+async fn init_reader<R: TagReader>(reader: &mut R) -> Result<(), Error> {
+    let version = check_version(reader.init().await?)?;
+    info!("rfid reader version: {=u8:#x}", version);
+    Ok(())
+}
+
+/// rejects the version an unpowered or disconnected reader reads back, all zeros or
+/// all ones
+fn check_version(version: u8) -> Result<u8, Error> {
+    match version {
+        0x00 | 0xff => Err(Error::Comm),
+        version => Ok(version),
+    }
+}
+
+/// reads ntag user memory from the selected tag and parses a color from its ndef
+/// message, returning `None` for tags without one or that do not support READ
+async fn read_ndef_color<R: TagReader>(reader: &mut R) -> Option<MarkerColor> {
+    let mut memory = [0u8; NDEF_READ_LIMIT];
+    let mut read = 0;
+    // lock and memory control tlvs may come first, so keep reading until the
+    // message tlv shows up
+    let (offset, len) = loop {
+        if read + NTAG_READ_SIZE > NDEF_READ_LIMIT {
+            debug!("no ndef message tlv in the first {} bytes", read);
+            return None;
+        }
+        let page = NTAG_USER_START_PAGE + (read / NTAG_PAGE_SIZE) as u8;
+        memory[read..read + NTAG_READ_SIZE].copy_from_slice(&reader.read_pages(page).await.ok()?);
+        read += NTAG_READ_SIZE;
+        match find_message(&memory[..read]) {
+            TlvSearch::Message(offset, len) => break (offset, len),
+            TlvSearch::NoMessage => return None,
+            TlvSearch::NeedMore => {}
+        }
+    };
+    let end = offset + len;
+    if end > NDEF_READ_LIMIT {
+        debug!("ndef message too large: {} bytes", len);
+        return None;
+    }
+    while read < end {
+        let page = NTAG_USER_START_PAGE + (read / NTAG_PAGE_SIZE) as u8;
+        memory[read..read + NTAG_READ_SIZE].copy_from_slice(&reader.read_pages(page).await.ok()?);
+        read += NTAG_READ_SIZE;
+    }
+    parse_message_color(&memory[offset..end])
+}
+
+/// writes the color as an ndef message to the selected ntag, one page at a time, and
+/// reads it back to verify
+async fn write_ndef_color<R: TagReader>(reader: &mut R, color: &MarkerColor) -> bool {
+    let (message, len) = encode_color_message(color);
+    for (index, page) in message[..len].chunks(NTAG_PAGE_SIZE).enumerate() {
+        let mut data = [0u8; NTAG_PAGE_SIZE];
+        data.copy_from_slice(page);
+        if reader
+            .write_page(NTAG_USER_START_PAGE + index as u8, data)
+            .await
+            .is_err()
+        {
+            return false;
+        }
     }
+    read_ndef_color(reader).await.map(|written| written.hsb()) == Some(color.hsb())
 }
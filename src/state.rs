@@ -1,20 +1,66 @@
-use crate::bulb::{BulbChannelSender, TasmotaCommand};
-use crate::constants::PERIODIC_SYNC_INTERVAL_SECS;
+use crate::bulb::{BulbChannels, BulbReport, BulbSelection, LightCommands};
+use crate::button::ButtonGesture;
+use crate::constants::{
+    BULBS, ENROLLMENT_TIMEOUT_SECS, MARKER_BULBS, MARKER_REMOVAL_POLICY, MAX_BULBS,
+    MAX_PRESENT_MARKERS, PERIODIC_SYNC_INTERVAL_SECS,
+};
 use crate::led::LedStateSignal;
+use crate::light_state::LightState;
 use crate::marker_color::MarkerColor;
+use crate::rfid::{ReaderMode, ReaderModeSignal};
 use defmt::{info, Format};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Instant, Timer};
+use embassy_sync::channel::Channel;
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use heapless::Vec;
+
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum RemovalPolicy {
+    /// keep showing the last marker's color after it is lifted off the reader
+    Latch,
+    /// show the marker's color only while it rests on the reader, then revert to white
+    HoldToShow,
+}
+
+#[derive(Debug, Clone)]
+pub struct Enrollment {
+    /// index into `MarkerColor::PALETTE` the next unknown tag will be bound to
+    pub slot: usize,
+    pub deadline: Instant,
+    /// light to restore if enrollment is cancelled
+    pub previous_light: Option<LightState>,
+}
+
+impl Enrollment {
+    pub fn color(&self) -> MarkerColor {
+        MarkerColor::PALETTE[self.slot].clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TagWriter {
+    /// color written to blank tags, `None` until chosen by api or by tapping a marker
+    pub color: Option<MarkerColor>,
+}
 
 #[derive(Debug, Clone)]
 pub struct State {
     pub last_marker_color_updated_at: u32,
     pub last_marker_color: Option<MarkerColor>,
-    pub is_connected: bool,
-    pub intended_bulb_state: Option<TasmotaCommand>,
-    pub current_dimmer_level: u8,
+    /// whether each bulb in `BULBS` is reachable
+    pub bulbs_connected: [bool; MAX_BULBS],
+    /// the light the marker bulbs should show, `None` until something chose one
+    pub desired_light: Option<LightState>,
+    /// the state each bulb last reported, for backends that report it
+    pub reported_bulb_states: [Option<BulbReport>; MAX_BULBS],
     pub last_button_press_at: u32,
+    pub enrollment: Option<Enrollment>,
+    pub tag_writer: Option<TagWriter>,
+    /// outcome of the last enrollment or tag write, for led feedback
+    pub last_tag_result: Option<bool>,
+    pub last_tag_result_at: u32,
+    /// the rfid reader is not responding and is being brought back up
+    pub reader_fault: bool,
 }
 
 impl Default for State {
@@ -28,17 +74,21 @@ impl State {
         Self {
             last_marker_color_updated_at: Instant::MIN.as_millis() as u32,
             last_marker_color: None,
-            is_connected: false,
-            intended_bulb_state: None,
-            current_dimmer_level: 0,
+            bulbs_connected: [false; MAX_BULBS],
+            desired_light: None,
+            reported_bulb_states: core::array::from_fn(|_| None),
             last_button_press_at: Instant::MIN.as_millis() as u32,
+            enrollment: None,
+            tag_writer: None,
+            last_tag_result: None,
+            last_tag_result_at: Instant::MIN.as_millis() as u32,
+            reader_fault: false,
         }
     }
 
     pub fn update_marker_color(&mut self, color: MarkerColor) {
         self.last_marker_color = Some(color);
         self.last_marker_color_updated_at = Instant::now().as_millis() as u32;
-        self.current_dimmer_level = 100;
     }
 
     pub fn clear_marker_color(&mut self) {
@@ -46,106 +96,365 @@ impl State {
         self.last_marker_color_updated_at = Instant::now().as_millis() as u32;
     }
 
-    pub fn set_connected(&mut self, connected: bool) {
-        self.is_connected = connected;
+    pub fn set_connected(&mut self, bulb: usize, connected: bool) {
+        self.bulbs_connected[bulb] = connected;
     }
 
-    pub fn toggle_dimmer(&mut self) -> u8 {
-        // Toggle between 0 and 100
-        self.current_dimmer_level = if self.current_dimmer_level == 0 {
-            100
-        } else {
-            0
+    /// whether any bulb is reachable
+    pub fn is_connected(&self) -> bool {
+        self.bulbs_connected[..BULBS.len()].contains(&true)
+    }
+
+    /// makes `light` the desired one, returning the commands that take the bulbs
+    /// there from the previously desired light
+    pub fn set_desired_light(&mut self, light: LightState) -> LightCommands {
+        let commands = match &self.desired_light {
+            Some(previous) => light.changes_from(previous),
+            None => light.commands(),
         };
-        self.current_dimmer_level
+        self.desired_light = Some(light);
+        commands
+    }
+
+    /// the desired light switched off, or on when it is off or nothing was chosen yet
+    pub fn toggled_light(&self) -> LightState {
+        match &self.desired_light {
+            Some(light) if light.power => LightState {
+                power: false,
+                ..light.clone()
+            },
+            Some(light) => LightState {
+                power: true,
+                ..light.clone()
+            },
+            None => LightState::default(),
+        }
+    }
+
+    pub fn start_enrollment(&mut self) {
+        self.enrollment = Some(Enrollment {
+            slot: 0,
+            deadline: Instant::now() + Duration::from_secs(ENROLLMENT_TIMEOUT_SECS),
+            previous_light: self.desired_light.clone(),
+        });
+    }
+
+    /// moves enrollment to the next palette slot, returning the newly selected color
+    pub fn next_enrollment_slot(&mut self) -> Option<MarkerColor> {
+        let enrollment = self.enrollment.as_mut()?;
+        enrollment.slot = (enrollment.slot + 1) % MarkerColor::PALETTE.len();
+        enrollment.deadline = Instant::now() + Duration::from_secs(ENROLLMENT_TIMEOUT_SECS);
+        Some(enrollment.color())
+    }
+
+    /// leaves enrollment mode, recording the result for led feedback when there is one
+    pub fn finish_enrollment(&mut self, result: Option<bool>) -> Option<Enrollment> {
+        if let Some(success) = result {
+            self.record_tag_result(success);
+        }
+        self.enrollment.take()
+    }
+
+    pub fn record_tag_result(&mut self, success: bool) {
+        self.last_tag_result = Some(success);
+        self.last_tag_result_at = Instant::now().as_millis() as u32;
     }
 }
 
 #[derive(Format, Clone)]
 pub enum StateCommand {
-    SetMarkerColor(MarkerColor),
-    ClearMarkerColor,
-    SetConnected(bool),
+    /// the colors of every marker resting on the reader, shown blended together. an
+    /// empty list means the last marker was lifted off the reader
+    SetMarkerColors(Vec<MarkerColor, MAX_PRESENT_MARKERS>),
+    /// whether the bulb at the given index of `BULBS` is reachable
+    SetConnected(usize, bool),
+    /// the bulb reported its state, which also shows it is reachable
+    BulbReported(usize, BulbReport),
+    /// the bulb's answer to a query, `None` when its backend cannot be queried
+    BulbQueried(usize, Option<BulbReport>),
     SyncState,
-    ToggleDimmer,
+    ButtonPress(ButtonGesture),
+    EnrollmentFinished(bool),
+    /// program blank tags with the given color, or with the next tapped marker's
+    StartTagWriter(Option<MarkerColor>),
+    StopTagWriter,
+    TagWritten(bool),
+    /// the rfid reader stopped responding (`true`) or came back up (`false`)
+    ReaderFault(bool),
 }
 
-pub type StateSignal = Signal<NoopRawMutex, StateCommand>;
+/// commands for the state manager, queued so none replaces another before it is
+/// handled, like a button press arriving while a marker is read
+pub type StateChannel = Channel<NoopRawMutex, StateCommand, 8>;
 
 #[embassy_executor::task]
 pub async fn state_manager_task(
-    state_signal: &'static StateSignal,
-    bulb_channel_sender: BulbChannelSender,
+    state_channel: &'static StateChannel,
+    bulb_channels: &'static BulbChannels,
     led_state_signal: &'static LedStateSignal,
+    reader_mode_signal: &'static ReaderModeSignal,
 ) {
     let mut state = State::new();
 
     loop {
-        let command = state_signal.wait().await;
-        match command {
-            StateCommand::SetMarkerColor(color) => {
-                let color_changed = state.last_marker_color.as_ref() != Some(&color);
-                state.update_marker_color(color.clone());
-                if color_changed {
-                    let (h, s, b) = color.hsb();
-                    let command = TasmotaCommand::HSBColor(h, s, b);
-                    state.intended_bulb_state = Some(command.clone());
-                    bulb_channel_sender.send(command).await;
+        let next_command = state_channel.receive();
+        let command = match state.enrollment.as_ref().map(|e| e.deadline) {
+            Some(deadline) => match with_deadline(deadline, next_command).await {
+                Ok(command) => command,
+                Err(_) => {
+                    info!("enrollment timed out");
+                    reader_mode_signal.signal(ReaderMode::Read);
+                    if let Some(enrollment) = state.finish_enrollment(Some(false)) {
+                        restore_bulb_state(&mut state, enrollment, bulb_channels);
+                    }
                     led_state_signal.signal(state.clone());
+                    continue;
                 }
-            }
-            StateCommand::ClearMarkerColor => {
-                let had_color = state.last_marker_color.is_some();
-                state.clear_marker_color();
-                if had_color {
-                    let command = TasmotaCommand::White(100);
-                    state.intended_bulb_state = Some(command.clone());
-                    bulb_channel_sender.send(command).await;
-                    led_state_signal.signal(state.clone());
+            },
+            None => next_command.await,
+        };
+        match command {
+            StateCommand::SetMarkerColors(colors) => match MarkerColor::blend(&colors) {
+                Some(color) => {
+                    info!("blended {} marker colors: {}", colors.len(), color);
+                    set_marker_color(
+                        &mut state,
+                        color,
+                        bulb_channels,
+                        led_state_signal,
+                        reader_mode_signal,
+                    );
+                }
+                // the last marker was lifted off the reader
+                None if MARKER_REMOVAL_POLICY == RemovalPolicy::Latch => {
+                    info!("markers removed, keeping color");
                 }
+                None => clear_marker_color(&mut state, bulb_channels, led_state_signal),
+            },
+            StateCommand::SetConnected(bulb, connected) => {
+                update_connection(&mut state, bulb, connected, bulb_channels, led_state_signal);
             }
-            StateCommand::SetConnected(connected) => {
-                let connection_changed = state.is_connected != connected;
-                let was_disconnected = !state.is_connected;
-                state.set_connected(connected);
-                if connection_changed {
-                    led_state_signal.signal(state.clone());
-                    // If we just reconnected and have an intended state, resync it
-                    if connected && was_disconnected {
-                        if let Some(intended_command) = &state.intended_bulb_state {
-                            bulb_channel_sender.send(intended_command.clone()).await;
+            StateCommand::BulbReported(bulb, report) => {
+                info!("bulb {} reported: {:?}", BULBS[bulb].name, report);
+                state.reported_bulb_states[bulb] = Some(report);
+                update_connection(&mut state, bulb, true, bulb_channels, led_state_signal);
+            }
+            StateCommand::SyncState => {
+                // ask the reachable bulbs for their state, correcting the ones that
+                // drifted from the desired light once they answer
+                if !state.is_connected() {
+                    info!("skipping sync - not connected to any bulb");
+                } else if state.desired_light.is_some() {
+                    for bulb in 0..BULBS.len() {
+                        if state.bulbs_connected[bulb] && MARKER_BULBS.includes(bulb) {
+                            bulb_channels.query(BulbSelection::Bulb(bulb));
                         }
                     }
+                } else {
+                    info!("no desired light to sync");
                 }
             }
-            StateCommand::SyncState => {
-                // Manually triggered state sync - resend intended state if we have one
-                if state.is_connected {
-                    if let Some(intended_command) = &state.intended_bulb_state {
-                        info!("syncing bulb state: {:?}", intended_command);
-                        bulb_channel_sender.send(intended_command.clone()).await;
-                    } else {
-                        info!("no intended state to sync");
-                    }
+            StateCommand::BulbQueried(bulb, report) => {
+                update_connection(&mut state, bulb, true, bulb_channels, led_state_signal);
+                let Some(light) = &state.desired_light else {
+                    continue;
+                };
+                // bulbs that cannot be queried get the whole desired light resent
+                let corrections = match &report {
+                    Some(report) => light.drift(report),
+                    None => light.commands(),
+                };
+                if corrections.is_empty() {
+                    info!("bulb {} is in sync", BULBS[bulb].name);
                 } else {
-                    info!("skipping sync - not connected to bulb");
+                    info!(
+                        "bulb {} drifted to {:?}, sending: {:?}",
+                        BULBS[bulb].name, report, corrections
+                    );
+                    bulb_channels.send(BulbSelection::Bulb(bulb), corrections);
+                }
+                if report.is_some() {
+                    state.reported_bulb_states[bulb] = report;
                 }
             }
-            StateCommand::ToggleDimmer => {
-                let dimmer_level = state.toggle_dimmer();
+            StateCommand::ButtonPress(ButtonGesture::Short) => {
                 state.last_button_press_at = Instant::now().as_millis() as u32;
-                let command = TasmotaCommand::Dimmer(dimmer_level);
-                state.intended_bulb_state = Some(command.clone());
-                bulb_channel_sender.send(command).await;
-                info!("toggled dimmer to: {}%", dimmer_level);
+                if let Some(color) = state.next_enrollment_slot() {
+                    info!("enrollment slot: {}", color);
+                    reader_mode_signal.signal(ReaderMode::Enroll(color.clone()));
+                    preview_enrollment_color(&mut state, color, bulb_channels);
+                } else {
+                    let light = state.toggled_light();
+                    info!("toggled light {}", if light.power { "on" } else { "off" });
+                    set_light(&mut state, light, bulb_channels);
+                }
+                led_state_signal.signal(state.clone());
+            }
+            StateCommand::ButtonPress(ButtonGesture::Long) => {
+                // long presses cycle through enrollment, tag writing, and back to normal
+                if let Some(enrollment) = state.finish_enrollment(None) {
+                    info!("enrollment cancelled, tap a marker to choose the tag writer color");
+                    restore_bulb_state(&mut state, enrollment, bulb_channels);
+                    state.tag_writer = Some(TagWriter { color: None });
+                    reader_mode_signal.signal(ReaderMode::Read);
+                } else if state.tag_writer.take().is_some() {
+                    info!("tag writer stopped");
+                    reader_mode_signal.signal(ReaderMode::Read);
+                } else {
+                    state.start_enrollment();
+                    let color = MarkerColor::PALETTE[0].clone();
+                    info!("enrollment started, slot: {}", color);
+                    reader_mode_signal.signal(ReaderMode::Enroll(color.clone()));
+                    preview_enrollment_color(&mut state, color, bulb_channels);
+                }
                 led_state_signal.signal(state.clone());
             }
+            StateCommand::EnrollmentFinished(success) => {
+                if let Some(enrollment) = state.finish_enrollment(Some(success)) {
+                    if success {
+                        // the enrolled tag is still on the reader and the bulb already
+                        // shows its color, so adopt it as the current marker
+                        state.update_marker_color(enrollment.color());
+                    } else {
+                        restore_bulb_state(&mut state, enrollment, bulb_channels);
+                    }
+                }
+                led_state_signal.signal(state.clone());
+            }
+            StateCommand::StartTagWriter(color) => {
+                if let Some(enrollment) = state.finish_enrollment(None) {
+                    restore_bulb_state(&mut state, enrollment, bulb_channels);
+                }
+                match &color {
+                    Some(color) => {
+                        info!("tag writer started with color: {}", color);
+                        reader_mode_signal.signal(ReaderMode::WriteTag(color.clone()));
+                    }
+                    None => {
+                        info!("tag writer started, tap a marker to choose the color");
+                        reader_mode_signal.signal(ReaderMode::Read);
+                    }
+                }
+                state.tag_writer = Some(TagWriter { color });
+                led_state_signal.signal(state.clone());
+            }
+            StateCommand::StopTagWriter => {
+                if state.tag_writer.take().is_some() {
+                    info!("tag writer stopped");
+                    reader_mode_signal.signal(ReaderMode::Read);
+                    led_state_signal.signal(state.clone());
+                }
+            }
+            StateCommand::TagWritten(success) => {
+                state.record_tag_result(success);
+                led_state_signal.signal(state.clone());
+            }
+            StateCommand::ReaderFault(fault) => {
+                if state.reader_fault != fault {
+                    if fault {
+                        info!("rfid reader fault");
+                    } else {
+                        info!("rfid reader recovered");
+                    }
+                    state.reader_fault = fault;
+                    led_state_signal.signal(state.clone());
+                }
+            }
         }
     }
 }
 
+/// records whether a bulb is reachable, resending the whole desired light to it when
+/// it comes back
+fn update_connection(
+    state: &mut State,
+    bulb: usize,
+    connected: bool,
+    bulb_channels: &BulbChannels,
+    led_state_signal: &LedStateSignal,
+) {
+    if state.bulbs_connected[bulb] == connected {
+        return;
+    }
+    info!(
+        "bulb {} {}",
+        BULBS[bulb].name,
+        if connected {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    );
+    state.set_connected(bulb, connected);
+    led_state_signal.signal(state.clone());
+    // the bulb may have lost any part of the light while it was unreachable
+    if connected && MARKER_BULBS.includes(bulb) {
+        if let Some(light) = &state.desired_light {
+            bulb_channels.send(BulbSelection::Bulb(bulb), light.commands());
+        }
+    }
+}
+
+/// shows a marker color on the bulb, and picks it as the tag writer color when the
+/// writer is waiting for one
+fn set_marker_color(
+    state: &mut State,
+    color: MarkerColor,
+    bulb_channels: &BulbChannels,
+    led_state_signal: &LedStateSignal,
+    reader_mode_signal: &ReaderModeSignal,
+) {
+    if let Some(writer) = state.tag_writer.as_mut().filter(|w| w.color.is_none()) {
+        info!("tag writer color: {}", color);
+        writer.color = Some(color.clone());
+        reader_mode_signal.signal(ReaderMode::WriteTag(color.clone()));
+    }
+    let color_changed = state.last_marker_color.as_ref() != Some(&color);
+    state.update_marker_color(color.clone());
+    if color_changed {
+        set_light(state, LightState::color(&color), bulb_channels);
+        led_state_signal.signal(state.clone());
+    }
+}
+
+/// reverts the bulb to white when a marker color is showing
+fn clear_marker_color(
+    state: &mut State,
+    bulb_channels: &BulbChannels,
+    led_state_signal: &LedStateSignal,
+) {
+    let had_color = state.last_marker_color.is_some();
+    state.clear_marker_color();
+    if had_color {
+        set_light(state, LightState::white(100), bulb_channels);
+        led_state_signal.signal(state.clone());
+    }
+}
+
+/// shows the color of the selected enrollment slot on the bulb
+fn preview_enrollment_color(state: &mut State, color: MarkerColor, bulb_channels: &BulbChannels) {
+    set_light(state, LightState::color(&color), bulb_channels);
+}
+
+/// puts the bulb back the way it was before enrollment started
+fn restore_bulb_state(state: &mut State, enrollment: Enrollment, bulb_channels: &BulbChannels) {
+    match enrollment.previous_light {
+        Some(light) => set_light(state, light, bulb_channels),
+        // nothing was shown before, so go back to the default white
+        None => set_light(state, LightState::default(), bulb_channels),
+    }
+}
+
+/// makes `light` the desired light, sending the marker bulbs only what changed
+fn set_light(state: &mut State, light: LightState, bulb_channels: &BulbChannels) {
+    let commands = state.set_desired_light(light);
+    if !commands.is_empty() {
+        bulb_channels.send(MARKER_BULBS, commands);
+    }
+}
+
 #[embassy_executor::task]
-pub async fn periodic_sync_task(state_signal: &'static StateSignal) {
+pub async fn periodic_sync_task(state_channel: &'static StateChannel) {
     info!(
         "starting periodic sync task with {}s interval",
         PERIODIC_SYNC_INTERVAL_SECS
@@ -154,6 +463,6 @@ pub async fn periodic_sync_task(state_signal: &'static StateSignal) {
     loop {
         Timer::after(Duration::from_secs(PERIODIC_SYNC_INTERVAL_SECS)).await;
         info!("triggering periodic state sync");
-        state_signal.signal(StateCommand::SyncState);
+        state_channel.send(StateCommand::SyncState).await;
     }
 }
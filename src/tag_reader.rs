@@ -0,0 +1,96 @@
+use crate::tag_uid::TagUid;
+use defmt::Format;
+
+#[cfg(not(any(
+    feature = "reader-mfrc522-i2c",
+    feature = "reader-mfrc522-spi",
+    feature = "reader-pn532"
+)))]
+compile_error!("select the rfid reader with one of the `reader-*` features");
+
+#[cfg(any(
+    all(feature = "reader-mfrc522-i2c", feature = "reader-mfrc522-spi"),
+    all(feature = "reader-mfrc522-i2c", feature = "reader-pn532"),
+    all(feature = "reader-mfrc522-spi", feature = "reader-pn532")
+))]
+compile_error!("only one `reader-*` feature can be enabled, use `--no-default-features`");
+
+/// the reader fitted to this board, picked by the `reader-*` cargo feature
+#[cfg(all(target_os = "none", feature = "reader-mfrc522-i2c"))]
+pub type RfidReader = crate::mfrc522::Mfrc522<
+    crate::mfrc522::I2cInterface<esp_hal::i2c::master::I2c<'static, esp_hal::Async>>,
+    esp_hal::gpio::Input<'static>,
+>;
+/// the reader fitted to this board, picked by the `reader-*` cargo feature
+#[cfg(all(target_os = "none", feature = "reader-mfrc522-spi"))]
+pub type RfidReader = crate::mfrc522::Mfrc522<
+    crate::mfrc522::SpiInterface<
+        embedded_hal_bus::spi::ExclusiveDevice<
+            esp_hal::spi::master::Spi<'static, esp_hal::Async>,
+            esp_hal::gpio::Output<'static>,
+            embassy_time::Delay,
+        >,
+    >,
+    esp_hal::gpio::Input<'static>,
+>;
+/// the reader fitted to this board, picked by the `reader-*` cargo feature
+#[cfg(all(target_os = "none", feature = "reader-pn532"))]
+pub type RfidReader = crate::pn532::Pn532<
+    esp_hal::i2c::master::I2c<'static, esp_hal::Async>,
+    esp_hal::gpio::Input<'static>,
+>;
+
+#[derive(Debug, Format, PartialEq, Clone, Copy)]
+pub enum Error {
+    /// the bus transfer to the reader failed
+    Comm,
+    /// no tag answered before the reader gave up
+    Timeout,
+    /// the reader never finished the command, not even on its own timer
+    Unresponsive,
+    Collision,
+    Crc,
+    Bcc,
+    Protocol,
+    Parity,
+    BufferOverflow,
+    IncompleteFrame,
+    Nak,
+}
+
+impl Error {
+    /// whether the error points at the reader itself rather than the tags in its field
+    pub fn is_reader_fault(&self) -> bool {
+        matches!(self, Error::Comm | Error::Unresponsive)
+    }
+}
+
+/// an iso 14443 type a reader that can talk to ntag21x tags
+///
+/// every method that addresses a tag acts on the one selected by the last successful
+/// `poll`. several tags in the field are told apart by anticollision, so polling and
+/// halting until `poll` finds nothing enumerates all of them.
+#[allow(async_fn_in_trait)]
+pub trait TagReader {
+    /// resets and configures the reader, returning its firmware version
+    async fn init(&mut self) -> Result<u8, Error>;
+
+    /// reads the firmware version back, to check the reader is still answering
+    async fn version(&mut self) -> Result<u8, Error>;
+
+    /// selects a tag newly placed on the reader, never one that has been halted.
+    /// `Err(Error::Timeout)` means no such tag is in the field
+    async fn poll(&mut self) -> Result<TagUid, Error>;
+
+    /// halts the selected tag, so `poll` stops reporting it until it leaves the field
+    async fn halt(&mut self) -> Result<(), Error>;
+
+    /// switches the field off and back on, returning every tag in it to idle
+    async fn reset_field(&mut self) -> Result<(), Error>;
+
+    /// reads four ntag pages starting at `page`
+    async fn read_pages(&mut self, page: u8) -> Result<[u8; 16], Error>;
+
+    /// writes a single four byte ntag page
+    async fn write_page(&mut self, page: u8, data: [u8; 4]) -> Result<(), Error>;
+}
@@ -0,0 +1,98 @@
+use defmt::{Format, Formatter};
+
+const MAX_UID_LENGTH: usize = 10;
+
+/// an iso 14443 tag uid of any cascade level: 4 (single), 7 (double) or 10 (triple)
+/// bytes long
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TagUid {
+    len: u8,
+    bytes: [u8; MAX_UID_LENGTH],
+}
+
+impl TagUid {
+    pub const MAX_LEN: usize = MAX_UID_LENGTH;
+
+    pub const fn single(uid: [u8; 4]) -> Self {
+        Self::from_array(uid)
+    }
+
+    pub const fn double(uid: [u8; 7]) -> Self {
+        Self::from_array(uid)
+    }
+
+    pub const fn triple(uid: [u8; 10]) -> Self {
+        Self::from_array(uid)
+    }
+
+    /// builds a uid from raw bytes, returning `None` unless it is 4, 7 or 10 bytes long
+    pub fn new(uid: &[u8]) -> Option<Self> {
+        matches!(uid.len(), 4 | 7 | 10).then(|| Self::from_slice(uid))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    fn from_slice(uid: &[u8]) -> Self {
+        let mut bytes = [0u8; Self::MAX_LEN];
+        bytes[..uid.len()].copy_from_slice(uid);
+        Self {
+            len: uid.len() as u8,
+            bytes,
+        }
+    }
+
+    const fn from_array<const N: usize>(uid: [u8; N]) -> Self {
+        let mut bytes = [0u8; Self::MAX_LEN];
+        let mut i = 0;
+        while i < N {
+            bytes[i] = uid[i];
+            i += 1;
+        }
+        Self {
+            len: N as u8,
+            bytes,
+        }
+    }
+}
+
+impl Format for TagUid {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{=[u8]:02x}", self.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_every_cascade_level() {
+        let single = [0x12, 0x34, 0x56, 0x78];
+        let double = [4, 61, 60, 18, 54, 30, 145];
+        let triple = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(TagUid::new(&single), Some(TagUid::single(single)));
+        assert_eq!(TagUid::new(&double), Some(TagUid::double(double)));
+        assert_eq!(TagUid::new(&triple), Some(TagUid::triple(triple)));
+        assert_eq!(TagUid::new(&single).unwrap().as_bytes(), &single);
+        assert_eq!(TagUid::new(&double).unwrap().as_bytes(), &double);
+        assert_eq!(TagUid::new(&triple).unwrap().as_bytes(), &triple);
+    }
+
+    #[test]
+    fn rejects_other_lengths() {
+        for len in [0, 1, 3, 5, 6, 8, 9, 11, 16] {
+            assert_eq!(TagUid::new(&[0xaa; 16][..len]), None, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn sizes_are_distinct() {
+        // a shorter uid padded with zeros is not the longer uid
+        assert_ne!(
+            TagUid::single([1, 2, 3, 4]),
+            TagUid::new(&[1, 2, 3, 4, 0, 0, 0]).unwrap()
+        );
+    }
+}